@@ -1,4 +1,5 @@
 use reqwest::Client;
+use secrecy::{ExposeSecret, SecretString};
 use serde_json::Value;
 use std::collections::HashMap;
 use thiserror::Error;
@@ -10,14 +11,21 @@ pub enum Error {
     #[error("JSON serialization/deserialization failed: {0}")]
     Json(#[from] serde_json::Error),
     #[error("API error: {status} - {message}")]
-    Api { status: u16, message: String },
+    Api {
+        status: u16,
+        message: String,
+        /// The response's `Retry-After` header, if any.
+        retry_after: Option<String>,
+    },
 }
 
 #[derive(Clone, Debug)]
 pub struct AmazingMarvinCredentials {
     pub uri: String,
     pub username: String,
-    pub password: String,
+    /// Only exposed to build the request's authorization header, so it never
+    /// ends up in logs or panics.
+    pub password: SecretString,
     pub database_name: String,
 }
 
@@ -53,17 +61,30 @@ impl AmazingMarvinClient {
         let response = self
             .client
             .post(&url)
-            .basic_auth(&self.credentials.username, Some(&self.credentials.password))
+            .basic_auth(
+                &self.credentials.username,
+                Some(self.credentials.password.expose_secret()),
+            )
             .header("Content-Type", "application/json")
             .header("Accept", "application/json")
             .json(&query_body)
             .send()
             .await?;
+        tracing::debug!(%url, %selector, status = %response.status(), "POST _find");
 
         if !response.status().is_success() {
             let status = response.status().as_u16();
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned);
             let message = response.text().await.unwrap_or_default();
-            return Err(Error::Api { status, message });
+            return Err(Error::Api {
+                status,
+                message,
+                retry_after,
+            });
         }
 
         let response_body: Value = response.json().await?;
@@ -105,6 +126,7 @@ impl AmazingMarvinClient {
             0 => Err(Error::Api {
                 status: 404,
                 message: format!("No category found with title '{title}'"),
+                retry_after: None,
             }),
             1 => {
                 let category = docs.into_iter().next().unwrap();
@@ -115,6 +137,7 @@ impl AmazingMarvinClient {
                     .ok_or_else(|| Error::Api {
                         status: 400,
                         message: format!("Category '{title}' does not have a valid _id"),
+                        retry_after: None,
                     })
             }
             count => Err(Error::Api {
@@ -122,6 +145,7 @@ impl AmazingMarvinClient {
                 message: format!(
                     "Found {count} categories with title '{title}', expected exactly one"
                 ),
+                retry_after: None,
             }),
         }
     }
@@ -208,6 +232,7 @@ impl AmazingMarvinClient {
             .ok_or_else(|| Error::Api {
                 status: 404,
                 message: format!("Habit with name '{habit_name}' not found"),
+                retry_after: None,
             })?;
 
         let history = habit
@@ -251,7 +276,7 @@ mod tests {
         AmazingMarvinCredentials {
             uri: get_keyring("am-uri"),
             username: get_keyring("am-username"),
-            password: get_keyring("am-password"),
+            password: get_keyring("am-password").into(),
             database_name: get_keyring("am-database"),
         }
     }
@@ -62,6 +62,7 @@ impl AwClient {
         end: &OffsetDateTime,
     ) -> Result<Vec<Event>, Error> {
         let url = format!("{}/api/0/buckets/{}/events", self.url, bucket);
+        tracing::debug!(%url, %start, %end, "GET events");
 
         let start = start.format(&Rfc3339).map_err(Error::DateFormatError)?;
         let end = end.format(&Rfc3339).map_err(Error::DateFormatError)?;
@@ -75,12 +76,30 @@ impl AwClient {
             ])
             .send()
             .await?
-            .json()
+            .error_for_status()?
+            .json::<Vec<Event>>()
             .await?;
 
+        tracing::debug!(events = response.len(), "received events");
         Ok(response)
     }
 
+    /// Checks whether a bucket exists.
+    ///
+    /// # Errors
+    /// Returns error if the HTTP request fails or the server answers with an error other than
+    /// "not found"
+    pub async fn bucket_exists(&self, bucket: &str) -> Result<bool, Error> {
+        let url = format!("{}/api/0/buckets/{}", self.url, bucket);
+        let response = self.client.get(&url).send().await?;
+        tracing::debug!(%url, status = %response.status(), "GET bucket");
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
     /// Sends an event heartbeat to a bucket. Events with identical data within the pulsetime
     /// window will be merged to save storage space.
     ///
@@ -1,4 +1,5 @@
 use reqwest::Client;
+use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use time::format_description::well_known::Rfc3339;
@@ -12,8 +13,16 @@ pub enum Error {
     #[error("Date formatting failed: {0}")]
     DateFormat(#[from] time::error::Format),
 
+    #[error("Date parsing failed: {0}")]
+    DateParse(#[from] time::error::Parse),
+
     #[error("GitHub API error ({status}): {message}")]
-    Api { status: u16, message: String },
+    Api {
+        status: u16,
+        message: String,
+        /// The response's `Retry-After` header, if any.
+        retry_after: Option<String>,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -52,12 +61,13 @@ struct RepoCommitter {
 /// with optional authentication using a GitHub personal access token.
 pub struct GitHubClient {
     client: Client,
-    token: Option<String>,
+    /// Only exposed to build the authorization header.
+    token: Option<SecretString>,
 }
 
 impl GitHubClient {
     #[must_use]
-    pub fn new(token: Option<String>) -> Self {
+    pub fn new(token: Option<SecretString>) -> Self {
         Self {
             client: Client::new(),
             token,
@@ -77,18 +87,14 @@ impl GitHubClient {
             .header("User-Agent", "github-light/0.1.0");
 
         if let Some(token) = &self.token {
-            request = request.header("Authorization", format!("token {token}"));
+            request = request.header("Authorization", format!("token {}", token.expose_secret()));
         }
 
         let response = request.send().await?;
+        tracing::debug!(username, status = %response.status(), "GET user repositories");
 
         if !response.status().is_success() {
-            let status = response.status().as_u16();
-            let message = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_owned());
-            return Err(Error::Api { status, message });
+            return Err(api_error(response).await);
         }
 
         let repositories: Vec<UserRepository> = response.json().await?;
@@ -121,7 +127,12 @@ impl GitHubClient {
     }
 
     /// Fetches commits for a specific repository since the specified date.
-    async fn get_repository_commits(
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the HTTP request fails, the date format is invalid,
+    /// or the GitHub API returns an error response.
+    pub async fn get_repository_commits(
         &self,
         repo: &str,
         username: &str,
@@ -140,18 +151,14 @@ impl GitHubClient {
             .header("User-Agent", "github-light/0.1.0");
 
         if let Some(token) = &self.token {
-            request = request.header("Authorization", format!("token {token}"));
+            request = request.header("Authorization", format!("token {}", token.expose_secret()));
         }
 
         let response = request.send().await?;
+        tracing::debug!(repo, since = %since_str, status = %response.status(), "GET commits");
 
         if !response.status().is_success() {
-            let status = response.status().as_u16();
-            let message = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_owned());
-            return Err(Error::Api { status, message });
+            return Err(api_error(response).await);
         }
 
         let repo_commits: Vec<RepoCommit> = response.json().await?;
@@ -159,11 +166,7 @@ impl GitHubClient {
         let commits = repo_commits
             .into_iter()
             .map(|item| -> Result<Commit, Error> {
-                let committer_date = OffsetDateTime::parse(&item.commit.committer.date, &Rfc3339)
-                    .map_err(|_| Error::Api {
-                    status: 500,
-                    message: "Failed to parse commit date".to_string(),
-                })?;
+                let committer_date = OffsetDateTime::parse(&item.commit.committer.date, &Rfc3339)?;
 
                 Ok(Commit {
                     sha: item.sha,
@@ -178,6 +181,24 @@ impl GitHubClient {
     }
 }
 
+async fn api_error(response: reqwest::Response) -> Error {
+    let status = response.status().as_u16();
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let message = response
+        .text()
+        .await
+        .unwrap_or_else(|_| "Unknown error".to_owned());
+    Error::Api {
+        status,
+        message,
+        retry_after,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,5 +1,6 @@
 use anyhow::{bail, Context, Result};
 use reqwest::{header::RETRY_AFTER, Client, StatusCode};
+use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::time::Duration;
@@ -40,14 +41,32 @@ struct ApiError {
     metadata: Value,
 }
 
+/// The HTTP status of a failed OpenRouter request, attached to its error so
+/// callers can tell rate limits and server errors from permanent failures.
+#[derive(Debug)]
+pub struct HttpStatus {
+    pub status: u16,
+    /// The response's `Retry-After` header, if any.
+    pub retry_after: Option<String>,
+}
+
+impl std::fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP {}", self.status)
+    }
+}
+
+impl std::error::Error for HttpStatus {}
+
 pub struct LlmClient {
     client: Client,
-    api_key: String,
+    /// Only exposed to build the authorization header.
+    api_key: SecretString,
     model: String,
 }
 
 impl LlmClient {
-    pub fn new(api_key: String, model: String) -> Result<Self> {
+    pub fn new(api_key: SecretString, model: String) -> Result<Self> {
         let client = Client::builder()
             .timeout(Duration::from_secs(60))
             .build()
@@ -69,10 +88,11 @@ impl LlmClient {
             }],
         };
 
+        tracing::debug!(model = %self.model, prompt, "chat request");
         let response = self
             .client
             .post(CHAT_COMPLETIONS_URL)
-            .bearer_auth(&self.api_key)
+            .bearer_auth(self.api_key.expose_secret())
             .header("HTTP-Referer", APP_URL)
             .header("X-Title", APP_NAME)
             .json(&request)
@@ -98,6 +118,9 @@ impl LlmClient {
             )
         })?;
 
+        tracing::debug!(%status, "chat response");
+        tracing::trace!(body, "chat response body");
+
         parse_chat_response(status, retry_after.as_deref(), &body)
     }
 }
@@ -107,28 +130,36 @@ fn parse_chat_response(
     retry_after: Option<&str>,
     body: &str,
 ) -> Result<String> {
+    let http_error = |message: String| {
+        anyhow::Error::new(HttpStatus {
+            status: status.as_u16(),
+            retry_after: retry_after.map(str::to_owned),
+        })
+        .context(message)
+    };
+
     if body.trim().is_empty() {
-        bail!(
+        return Err(http_error(format!(
             "OpenRouter returned an empty response body (HTTP {status}); check the API key, model, and provider availability"
-        );
+        )));
     }
 
     let response: ChatResponse = serde_json::from_str(body).map_err(|source| {
         let summary = truncate(body, 500);
-        anyhow::anyhow!(
+        http_error(format!(
             "OpenRouter returned invalid JSON (HTTP {status}): {source}; response body: {summary:?}"
-        )
+        ))
     })?;
 
     if let Some(error) = response.error {
-        return Err(format_api_error(status, retry_after, error));
+        return Err(http_error(format_api_error(status, retry_after, error)));
     }
 
     if !status.is_success() {
         let summary = truncate(body, 500);
-        bail!(
+        return Err(http_error(format!(
             "OpenRouter request failed with HTTP {status} and no structured API error; response body: {summary:?}"
-        );
+        )));
     }
 
     let choice = response.choices.into_iter().next().context(format!(
@@ -147,11 +178,7 @@ fn parse_chat_response(
     Ok(content)
 }
 
-fn format_api_error(
-    status: StatusCode,
-    retry_after: Option<&str>,
-    error: ApiError,
-) -> anyhow::Error {
+fn format_api_error(status: StatusCode, retry_after: Option<&str>, error: ApiError) -> String {
     let code = error
         .code
         .map(|code| match code {
@@ -172,7 +199,7 @@ fn format_api_error(
         .map(|value| format!("; Retry-After: {value}"))
         .unwrap_or_default();
 
-    anyhow::anyhow!(
+    format!(
         "OpenRouter request failed (HTTP {status}, API code {code}{error_type}): {message}{retry}"
     )
 }
@@ -287,4 +314,16 @@ mod tests {
             "OpenRouter request failed with HTTP 503 Service Unavailable and no structured API error; response body: \"{}\""
         );
     }
+
+    #[test]
+    fn attaches_the_http_status_for_retries() {
+        let body = r#"{"choices":[],"error":{"code":429,"message":"Rate limit exceeded"}}"#;
+
+        let error =
+            parse_chat_response(StatusCode::TOO_MANY_REQUESTS, Some("60"), body).unwrap_err();
+        let status = error.downcast_ref::<HttpStatus>().unwrap();
+
+        assert_eq!(status.status, 429);
+        assert_eq!(status.retry_after.as_deref(), Some("60"));
+    }
 }
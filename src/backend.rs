@@ -0,0 +1,217 @@
+use crate::goals::{GoalConfig, GoalSettings};
+use crate::key::Key;
+use crate::local_backend::LocalBeeminder;
+use crate::state::StateStore;
+use anyhow::{bail, Result};
+use beeminder::{
+    types::{CreateDatapoint, CreateGoal, Datapoint, DatapointFull, UpdateDatapoint},
+    BeeminderClient,
+};
+use serde::Deserialize;
+use std::{fmt, path::PathBuf};
+use time::macros::datetime;
+
+/// Where datapoints are read from and written to, from the top-level
+/// `beeminder_backend` key: `"api"` (the default) or `"local:/path/to/dir"`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum BackendConfig {
+    /// The Beeminder API, with `beeminder_key` and `beeminder_username`.
+    #[default]
+    Api,
+    /// A directory of JSON goal files standing in for Beeminder; see
+    /// [`LocalBeeminder`].
+    Local(PathBuf),
+}
+
+impl TryFrom<String> for BackendConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        if value == "api" {
+            return Ok(Self::Api);
+        }
+        match value.strip_prefix("local:") {
+            Some(dir) if !dir.is_empty() => Ok(Self::Local(crate::paths::expand_tilde(dir)?)),
+            _ => {
+                bail!("invalid Beeminder backend '{value}'; use \"api\" or \"local:/path/to/dir\"")
+            }
+        }
+    }
+}
+
+impl fmt::Display for BackendConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Api => f.write_str("the Beeminder API"),
+            Self::Local(dir) => write!(f, "local goals in {}", dir.display()),
+        }
+    }
+}
+
+impl BackendConfig {
+    /// Connects to the backend. Only the API needs the key, so local runs
+    /// work without one.
+    pub async fn connect(&self, key: &Key, username: &str) -> Result<Beeminder> {
+        match self {
+            Self::Api => {
+                let key = key.resolve().await?.expose().to_owned();
+                Ok(Beeminder::Api(
+                    BeeminderClient::new(key).with_username(username.to_string()),
+                ))
+            }
+            Self::Local(dir) => Ok(Beeminder::Local(LocalBeeminder::open(dir)?)),
+        }
+    }
+
+    /// The sync state for this backend. A local backend keeps its own next
+    /// to its goals, so trying one out does not mark events as synced to
+    /// Beeminder.
+    pub fn state_path(&self) -> Result<PathBuf> {
+        match self {
+            Self::Api => StateStore::default_path(),
+            Self::Local(dir) => Ok(dir.join("state.sqlite3")),
+        }
+    }
+}
+
+/// The Beeminder operations beesync uses, against the API or a local
+/// stand-in. Errors the API would return, such as a 422 for a duplicate
+/// requestid, are `beeminder::Error`s from either.
+pub enum Beeminder {
+    Api(BeeminderClient),
+    Local(LocalBeeminder),
+}
+
+impl Beeminder {
+    /// Seconds by which `goal`'s day ends after midnight; negative when it
+    /// ends before.
+    pub async fn goal_deadline(&self, goal: &str) -> Result<i32> {
+        match self {
+            Self::Api(client) => Ok(client.get_goal(goal).await?.deadline),
+            Self::Local(local) => local.goal_deadline(goal),
+        }
+    }
+
+    /// The settings of `goal`, or `None` when there is no such goal.
+    pub async fn goal(&self, goal: &str) -> Result<Option<GoalSettings>> {
+        match self {
+            Self::Api(client) => match client.get_goal(goal).await {
+                Ok(goal) => Ok(Some(GoalSettings {
+                    title: goal.title,
+                    goal_type: goal.goal_type,
+                    gunits: goal.gunits,
+                    aggday: Some(goal.aggday),
+                    rate: goal.rate,
+                    runits: goal.runits,
+                })),
+                Err(beeminder::Error::HttpStatus { status: 404, .. }) => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            Self::Local(local) => local.goal(goal),
+        }
+    }
+
+    pub async fn create_goal(&self, goal: &GoalConfig) -> Result<()> {
+        match self {
+            Self::Api(client) => {
+                // Beeminder wants two of the goal date, value and rate; a
+                // goal that just keeps its rate ends in the distant future.
+                let create = CreateGoal {
+                    slug: goal.slug.clone(),
+                    title: goal.title().to_string(),
+                    goal_type: goal.goal_type.api_name().to_string(),
+                    gunits: goal.units().to_string(),
+                    goaldate: Some(datetime!(2099-12-31 0:00 UTC)),
+                    goalval: None,
+                    rate: Some(goal.rate.value),
+                    runits: Some(goal.rate.unit.to_string()),
+                    aggday: goal
+                        .aggday
+                        .as_ref()
+                        .map(|aggday| aggday.as_str().to_string()),
+                };
+                client.create_goal(&create).await?;
+                Ok(())
+            }
+            Self::Local(local) => local.create_goal(&goal.slug, goal.settings()),
+        }
+    }
+
+    pub async fn get_datapoints(
+        &self,
+        goal: &str,
+        sort: Option<&str>,
+        count: Option<u64>,
+        page: Option<u64>,
+        per: Option<u64>,
+    ) -> Result<Vec<Datapoint>> {
+        match self {
+            Self::Api(client) => Ok(client.get_datapoints(goal, sort, count, page, per).await?),
+            Self::Local(local) => local.get_datapoints(goal, sort, count, page, per),
+        }
+    }
+
+    pub async fn get_datapoints_full(
+        &self,
+        goal: &str,
+        sort: Option<&str>,
+        count: Option<u64>,
+        page: Option<u64>,
+        per: Option<u64>,
+    ) -> Result<Vec<DatapointFull>> {
+        match self {
+            Self::Api(client) => Ok(client
+                .get_datapoints_full(goal, sort, count, page, per)
+                .await?),
+            Self::Local(local) => local.get_datapoints_full(goal, sort, count, page, per),
+        }
+    }
+
+    pub async fn create_datapoint(
+        &self,
+        goal: &str,
+        datapoint: &CreateDatapoint,
+    ) -> Result<Datapoint> {
+        match self {
+            Self::Api(client) => Ok(client.create_datapoint(goal, datapoint).await?),
+            Self::Local(local) => local.create_datapoint(goal, datapoint),
+        }
+    }
+
+    pub async fn update_datapoint(
+        &self,
+        goal: &str,
+        update: &UpdateDatapoint,
+    ) -> Result<Datapoint> {
+        match self {
+            Self::Api(client) => Ok(client.update_datapoint(goal, update).await?),
+            Self::Local(local) => local.update_datapoint(goal, update),
+        }
+    }
+
+    pub async fn delete_datapoint(&self, goal: &str, id: &str) -> Result<Datapoint> {
+        match self {
+            Self::Api(client) => Ok(client.delete_datapoint(goal, id).await?),
+            Self::Local(local) => local.delete_datapoint(goal, id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backends_are_parsed() {
+        let parse = |value: &str| BackendConfig::try_from(value.to_string());
+
+        assert_eq!(parse("api").unwrap(), BackendConfig::Api);
+        assert_eq!(
+            parse("local:/tmp/goals").unwrap(),
+            BackendConfig::Local(PathBuf::from("/tmp/goals"))
+        );
+        assert!(parse("local:").is_err());
+        assert!(parse("https://www.beeminder.com").is_err());
+    }
+}
@@ -1,13 +1,19 @@
+use crate::check::Check;
 use crate::key::Key;
+use crate::module::{report, BoxFuture, ClientCache, SyncContext, SyncModule};
+use crate::report::Change;
+use crate::timezone::GoalDays;
 use amazing_marvin_light::{AmazingMarvinClient, AmazingMarvinCredentials};
 use anyhow::{anyhow, Result};
-use beeminder::{types::CreateDatapoint, BeeminderClient};
+use beeminder::types::CreateDatapoint;
 use serde::Deserialize;
 use serde_json::Value;
-use std::collections::{HashMap, HashSet};
+use std::{collections::HashMap, sync::Arc};
 use time::OffsetDateTime;
+use tracing::debug;
 
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CategorySyncConfig {
     pub uri: Key,
     pub username: Key,
@@ -15,9 +21,14 @@ pub struct CategorySyncConfig {
     pub database_name: Key,
     pub category: String,
     pub goal_name: String,
+    #[serde(skip)]
+    client: ClientCache<AmazingMarvinClient>,
 }
 
-fn task_to_datapoint(task: &HashMap<String, Value>) -> Result<CreateDatapoint> {
+fn task_to_datapoint(
+    task: &HashMap<String, Value>,
+    days: &GoalDays<'_>,
+) -> Result<CreateDatapoint> {
     let id = task
         .get("_id")
         .and_then(|v| v.as_str())
@@ -39,69 +50,115 @@ fn task_to_datapoint(task: &HashMap<String, Value>) -> Result<CreateDatapoint> {
                 .map_err(|_| anyhow!("Invalid doneAt timestamp: {}", millis))
         })?;
 
-    let daystamp = format!(
-        "{:04}{:02}{:02}",
-        done_at.year(),
-        done_at.month() as u8,
-        done_at.day()
-    );
-
     Ok(CreateDatapoint {
         value: 1.0,
         timestamp: Some(done_at),
-        daystamp: Some(daystamp),
+        daystamp: Some(days.daystamp(done_at)),
         comment: Some(title.to_string()),
         requestid: Some(id.to_string()),
     })
 }
 
-pub async fn category_sync(config: &CategorySyncConfig, beeminder: &BeeminderClient) -> Result<()> {
-    println!("📋 category-sync");
-
-    let uri = config.uri.get_value()?;
-    let username = config.username.get_value()?;
-    let password = config.password.get_value()?;
-    let database_name = config.database_name.get_value()?;
-
-    let credentials = AmazingMarvinCredentials {
-        uri,
-        username,
-        password,
-        database_name,
-    };
+impl CategorySyncConfig {
+    async fn client(&self) -> Result<Arc<AmazingMarvinClient>> {
+        let inputs = (
+            self.uri.value().await?,
+            self.username.value().await?,
+            self.password.resolve().await?,
+            self.database_name.value().await?,
+        );
+        self.client
+            .get_or_try_init(inputs, |(uri, username, password, database_name)| {
+                let credentials = AmazingMarvinCredentials {
+                    uri,
+                    username,
+                    password: password.into(),
+                    database_name,
+                };
+                Ok(AmazingMarvinClient::new(credentials))
+            })
+    }
+}
 
-    let marvin_client = AmazingMarvinClient::new(credentials);
+async fn category_sync(config: &CategorySyncConfig, ctx: &SyncContext<'_>) -> Result<()> {
+    let marvin_client = config.client().await?;
     let goal = &config.goal_name;
 
-    let done_tasks = marvin_client
-        .find_recently_completed_tasks_in_category(&config.category)
-        .await?;
-
-    let existing_dps = beeminder
-        .get_datapoints(goal, Some("timestamp"), None, None, None)
+    let done_tasks = ctx
+        .retry("fetching Marvin tasks", || {
+            marvin_client.find_recently_completed_tasks_in_category(&config.category)
+        })
         .await?;
 
-    let existing_ids: HashSet<_> = existing_dps
-        .iter()
-        .filter_map(|dp| dp.requestid.as_deref())
-        .collect();
+    let existing_ids = ctx.synced_requestids(goal).await?;
 
+    let done_count = done_tasks.len();
+    ctx.seen(done_count);
     let new_tasks: Vec<_> = done_tasks
         .into_iter()
         .filter(|task| {
-            task.get("_id")
-                .and_then(|v| v.as_str())
-                .is_some_and(|id| !existing_ids.contains(id))
+            let id = task.get("_id").and_then(|v| v.as_str());
+            let synced = id.is_some_and(|id| existing_ids.contains(id));
+            if synced {
+                debug!(id, "task already synced");
+            }
+            id.is_some() && !synced
         })
         .collect();
+    debug!(done = done_count, new = new_tasks.len(), category = %config.category, "fetched completed tasks");
+    ctx.count(Change::Skipped, done_count - new_tasks.len());
 
+    let days = ctx.goal_days(goal).await?;
     for task in new_tasks.into_iter().rev() {
-        let dp = task_to_datapoint(&task)?;
-        beeminder.create_datapoint(goal, &dp).await?;
+        let dp = task_to_datapoint(&task, &days)?;
+        ctx.create_datapoint(goal, &dp).await?;
         if let Some(comment) = dp.comment.as_ref() {
-            println!("  🆕 Created Amazing Marvin datapoint: {comment}");
+            report!(
+                ctx,
+                "  🆕 {} Amazing Marvin datapoint: {comment}",
+                ctx.created()
+            );
         }
     }
 
     Ok(())
 }
+
+impl SyncModule for CategorySyncConfig {
+    const NAME: &'static str = "category";
+    const EMOJI: &'static str = "📋";
+
+    fn goals(&self) -> Vec<String> {
+        vec![self.goal_name.clone()]
+    }
+
+    fn sync<'a>(&'a self, ctx: &'a SyncContext<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(category_sync(self, ctx))
+    }
+
+    fn check(&self) -> BoxFuture<'_, Vec<Check>> {
+        Box::pin(async move {
+            let mut checks = vec![
+                Check::setting("uri", &self.uri).await,
+                Check::setting("username", &self.username).await,
+                Check::key("password", &self.password).await,
+                Check::setting("database_name", &self.database_name).await,
+            ];
+            if checks.iter().all(|check| check.result.is_ok()) {
+                let result = match self.client().await {
+                    Ok(marvin) => marvin
+                        .get_category_id_by_title(&self.category)
+                        .await
+                        .map(drop)
+                        .map_err(anyhow::Error::from),
+                    Err(e) => Err(e),
+                };
+                checks.push(Check::new(
+                    format!("category '{}' exists", self.category),
+                    result,
+                ));
+            }
+            checks
+        })
+    }
+}
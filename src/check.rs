@@ -0,0 +1,154 @@
+use crate::backend::BackendConfig;
+use crate::key::Key;
+use crate::module::Module;
+use crate::secret::{redact, Secret};
+use anyhow::{bail, Result};
+use aw_client_light::AwClient;
+use std::sync::Arc;
+
+/// One thing `beesync check` verified, and whether it holds.
+pub struct Check {
+    pub what: String,
+    pub result: Result<()>,
+}
+
+impl Check {
+    pub fn new(what: impl Into<String>, result: Result<()>) -> Self {
+        Self {
+            what: what.into(),
+            result,
+        }
+    }
+
+    /// Checks that `key` resolves to a non-empty value.
+    pub async fn key(field: &str, key: &Key) -> Self {
+        Self::new(format!("{field} resolves"), resolve(key).await.map(drop))
+    }
+
+    /// Checks that `key`, a setting rather than a credential, resolves to a
+    /// non-empty value.
+    pub async fn setting(field: &str, key: &Key) -> Self {
+        let result = key.value().await.and_then(|value| {
+            if value.trim().is_empty() {
+                bail!("resolved to an empty value");
+            }
+            Ok(())
+        });
+        Self::new(format!("{field} resolves"), result)
+    }
+}
+
+/// Checks that ActivityWatch at `url` is reachable and has `bucket`.
+pub async fn aw_bucket(aw: Result<Arc<AwClient>>, url: &str, bucket: &str) -> Check {
+    let exists = match aw {
+        Ok(aw) => aw.bucket_exists(bucket).await.map_err(anyhow::Error::from),
+        Err(e) => Err(e),
+    };
+    let result = match exists {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(anyhow::anyhow!("no such bucket on {url}")),
+        Err(e) => Err(e),
+    };
+    Check::new(format!("bucket '{bucket}' exists"), result)
+}
+
+async fn resolve(key: &Key) -> Result<Secret> {
+    let value = key.resolve().await?;
+    if value.expose().trim().is_empty() {
+        bail!("resolved to an empty value");
+    }
+    Ok(value)
+}
+
+/// Runs every module's live checks, plus access to each goal it writes to,
+/// and prints them as a table. Nothing is written anywhere.
+///
+/// Returns the number of failed checks.
+pub async fn live(
+    backend: &BackendConfig,
+    beeminder_key: &Key,
+    username: &str,
+    modules: &[Arc<dyn Module>],
+) -> usize {
+    let mut rows = Vec::new();
+    let (what, connected) = match backend {
+        BackendConfig::Api => (
+            "beeminder_key resolves".to_string(),
+            match resolve(beeminder_key).await {
+                Ok(_) => backend.connect(beeminder_key, username).await,
+                Err(e) => Err(e),
+            },
+        ),
+        BackendConfig::Local(_) => (
+            format!("{backend} are usable"),
+            backend.connect(beeminder_key, username).await,
+        ),
+    };
+    let beeminder = match connected {
+        Ok(beeminder) => {
+            rows.push(("beesync".to_string(), Check::new(what, Ok(()))));
+            Some(beeminder)
+        }
+        Err(e) => {
+            rows.push(("beesync".to_string(), Check::new(what, Err(e))));
+            None
+        }
+    };
+
+    for module in modules {
+        let label = module.label();
+        for check in module.check().await {
+            rows.push((label.clone(), check));
+        }
+        let Some(beeminder) = &beeminder else {
+            continue;
+        };
+        for goal in module.goals() {
+            // Reading datapoints needs the same credentials as writing them,
+            // so this confirms both that the goal exists and that the key
+            // belongs to its owner.
+            let result = beeminder
+                .get_datapoints(&goal, None, Some(1), None, None)
+                .await
+                .map(drop);
+            rows.push((
+                label.clone(),
+                Check::new(format!("goal '{goal}' is writable"), result),
+            ));
+        }
+    }
+
+    print_table(&rows);
+    rows.iter()
+        .filter(|(_, check)| check.result.is_err())
+        .count()
+}
+
+fn print_table(rows: &[(String, Check)]) {
+    let module_width = rows
+        .iter()
+        .map(|(label, _)| label.len())
+        .max()
+        .unwrap_or(0)
+        .max(6);
+    let check_width = rows
+        .iter()
+        .map(|(_, check)| check.what.len())
+        .max()
+        .unwrap_or(0)
+        .max(5);
+    println!(
+        "{:<module_width$}  {:<check_width$}  RESULT",
+        "MODULE", "CHECK"
+    );
+    for (label, check) in rows {
+        let result = match &check.result {
+            Ok(()) => "✅ ok".to_string(),
+            Err(e) => format!("❌ {}", redact(&format!("{e:#}"))),
+        };
+        println!(
+            "{label:<module_width$}  {:<check_width$}  {result}",
+            check.what
+        );
+    }
+}
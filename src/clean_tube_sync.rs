@@ -1,11 +1,20 @@
+use crate::check::{self, Check};
+use crate::module::{report, BoxFuture, ClientCache, SyncContext, SyncModule};
+use crate::report::Change;
+use crate::state::GoalState;
 use anyhow::Result;
 use aw_client_light::AwClient;
-use beeminder::{types::CreateDatapoint, BeeminderClient};
+use beeminder::types::CreateDatapoint;
 use serde::Deserialize;
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 use time::{Duration, OffsetDateTime};
+use tracing::debug;
 
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CleanTubeConfig {
     pub activity_watch_base_url: String,
     pub window_bucket: String,
@@ -13,12 +22,22 @@ pub struct CleanTubeConfig {
     pub lookback_days: i64,
     pub min_video_duration_seconds: f64,
     pub max_datapoints: u64,
+    #[serde(skip)]
+    client: ClientCache<AwClient>,
 }
 
-async fn get_seen_titles(aw: &AwClient, config: &CleanTubeConfig) -> Result<Vec<String>> {
+async fn get_seen_titles(
+    aw: &AwClient,
+    config: &CleanTubeConfig,
+    ctx: &SyncContext<'_>,
+) -> Result<Vec<String>> {
     let end = OffsetDateTime::now_utc();
     let start = end - Duration::days(config.lookback_days);
-    let events = aw.get_events(&config.window_bucket, &start, &end).await?;
+    let events = ctx
+        .retry("fetching ActivityWatch events", || {
+            aw.get_events(&config.window_bucket, &start, &end)
+        })
+        .await?;
 
     let mut video_to_time: HashMap<String, f64> = HashMap::new();
     for event in events {
@@ -29,49 +48,100 @@ async fn get_seen_titles(aw: &AwClient, config: &CleanTubeConfig) -> Result<Vec<
         }
     }
 
+    let videos = video_to_time.len();
     let mut titles: Vec<_> = video_to_time
         .into_iter()
         .filter(|(_, duration)| *duration > config.min_video_duration_seconds)
         .map(|(video, _)| video)
         .collect();
     titles.sort();
+    debug!(videos, watched = titles.len(), "found YouTube videos");
     Ok(titles)
 }
 
+/// Titles already logged, from the state store. The first time, the state is
+/// rebuilt from the comments of the goal's last `max_datapoints` datapoints.
 async fn get_logged_titles(
-    beeminder: &BeeminderClient,
+    ctx: &SyncContext<'_>,
+    state: &GoalState<'_>,
     config: &CleanTubeConfig,
 ) -> Result<HashSet<String>> {
-    let datapoints = beeminder
-        .get_datapoints(&config.goal_name, None, Some(config.max_datapoints), None, None)
+    if let Some(titles) = state.items()? {
+        debug!(logged = titles.len(), "using recorded sync state");
+        return Ok(titles);
+    }
+    let datapoints = ctx
+        .retry("fetching Beeminder datapoints", || {
+            ctx.beeminder.get_datapoints(
+                &config.goal_name,
+                None,
+                Some(config.max_datapoints),
+                None,
+                None,
+            )
+        })
         .await?;
-
-    Ok(datapoints
+    let items: Vec<_> = datapoints
         .into_iter()
-        .map(|dp| dp.comment.unwrap_or_default())
-        .collect())
+        .map(|dp| (dp.comment.unwrap_or_default(), Some(dp.id)))
+        .collect();
+    let titles = items.iter().map(|(title, _)| title.clone()).collect();
+    debug!(logged = items.len(), "rebuilt sync state from Beeminder");
+    state.rebuild(items, None)?;
+    Ok(titles)
+}
+
+impl CleanTubeConfig {
+    fn client(&self) -> Result<Arc<AwClient>> {
+        self.client.get_or_try_init((), |()| {
+            Ok(AwClient::new(Some(self.activity_watch_base_url.clone())))
+        })
+    }
 }
 
-pub async fn clean_tube_sync(config: &CleanTubeConfig, beeminder: &BeeminderClient) -> Result<()> {
-    println!("🚇 clean-tube-sync");
-    let aw = AwClient::new(Some(config.activity_watch_base_url.clone()));
-    let logged_titles = get_logged_titles(beeminder, config).await?;
-    let seen_titles = get_seen_titles(&aw, config).await?;
+async fn clean_tube_sync(config: &CleanTubeConfig, ctx: &SyncContext<'_>) -> Result<()> {
+    let aw = config.client()?;
+    let state = ctx.state(&config.goal_name);
+    let logged_titles = get_logged_titles(ctx, &state, config).await?;
+    let seen_titles = get_seen_titles(&aw, config, ctx).await?;
+    ctx.seen(seen_titles.len());
 
     for seen in seen_titles {
         if logged_titles.contains(&seen) {
-            println!("  ✅ '{seen}' already logged!");
+            ctx.count(Change::Skipped, 1);
+            report!(ctx, "  ✅ '{seen}' already logged!");
         } else {
-            println!("  🆕 '{seen}' was logged!");
+            report!(ctx, "  🆕 {} YouTube datapoint: {seen}", ctx.created());
             let dp = CreateDatapoint {
                 value: 1.0,
-                comment: Some(seen),
+                comment: Some(seen.clone()),
                 timestamp: Some(OffsetDateTime::now_utc()),
                 daystamp: None,
                 requestid: None,
             };
-            beeminder.create_datapoint(&config.goal_name, &dp).await?;
+            let created = ctx.create_datapoint(&config.goal_name, &dp).await?;
+            state.record(&seen, created.as_ref().map(|created| created.id.as_str()))?;
         }
     }
     Ok(())
 }
+
+impl SyncModule for CleanTubeConfig {
+    const NAME: &'static str = "clean_tube";
+    const EMOJI: &'static str = "🚇";
+
+    fn goals(&self) -> Vec<String> {
+        vec![self.goal_name.clone()]
+    }
+
+    fn sync<'a>(&'a self, ctx: &'a SyncContext<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(clean_tube_sync(self, ctx))
+    }
+
+    fn check(&self) -> BoxFuture<'_, Vec<Check>> {
+        Box::pin(async move {
+            let aw = self.client();
+            vec![check::aw_bucket(aw, &self.activity_watch_base_url, &self.window_bucket).await]
+        })
+    }
+}
@@ -1,14 +1,18 @@
+use crate::check::{self, Check};
 use crate::key::Key;
-use anyhow::Result;
+use crate::module::{BoxFuture, ClientCache, SyncContext, SyncModule};
+use crate::reconcile::{self, Desired};
+use crate::timezone::daystamp;
+use anyhow::{Context, Result};
 use aw_client_light::AwClient;
-use beeminder::types::CreateDatapoint;
-use beeminder::BeeminderClient;
 use llm::LlmClient;
 use serde::Deserialize;
-use std::collections::HashSet;
-use time::{Duration, OffsetDateTime, Time, UtcOffset};
+use std::{collections::HashSet, sync::Arc};
+use time::{Date, Duration, OffsetDateTime};
+use tracing::debug;
 
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CleanViewConfig {
     pub activity_watch_base_url: String,
     pub window_bucket: String,
@@ -18,6 +22,10 @@ pub struct CleanViewConfig {
     pub openrouter_model: String,
     pub min_window_duration_seconds: f64,
     pub prompt_template: String,
+    #[serde(skip)]
+    aw: ClientCache<AwClient>,
+    #[serde(skip)]
+    llm: ClientCache<LlmClient>,
 }
 
 fn get_prompt(template: &str, titles: &[String]) -> String {
@@ -25,23 +33,37 @@ fn get_prompt(template: &str, titles: &[String]) -> String {
     template.replace("{{titles}}", &titles_str)
 }
 
-pub async fn clean_view_sync(config: &CleanViewConfig, beeminder: &BeeminderClient) -> Result<()> {
-    println!("🧹 clean-view-sync");
-    let aw = AwClient::new(Some(config.activity_watch_base_url.clone()));
-    let llm = LlmClient::new(
-        config.openrouter_key.get_value()?,
-        config.openrouter_model.clone(),
-    )?;
-    let mut data_by_day: Vec<(String, Vec<String>)> = Vec::new();
+impl CleanViewConfig {
+    fn aw(&self) -> Result<Arc<AwClient>> {
+        self.aw.get_or_try_init((), |()| {
+            Ok(AwClient::new(Some(self.activity_watch_base_url.clone())))
+        })
+    }
 
-    let offset = UtcOffset::current_local_offset()?;
-    let now = OffsetDateTime::now_utc().to_offset(offset);
-    let end_of_day_today = now.replace_time(Time::MIDNIGHT) + Duration::days(1);
+    async fn llm(&self) -> Result<Arc<LlmClient>> {
+        let key = self.openrouter_key.resolve().await?;
+        self.llm.get_or_try_init(key, |key| {
+            LlmClient::new(key.into(), self.openrouter_model.clone())
+        })
+    }
+}
 
-    for day_offset in (0..config.lookback_days).rev() {
-        let end = end_of_day_today - Duration::days(day_offset);
-        let start = end - Duration::days(1);
-        let events = aw.get_events(&config.window_bucket, &start, &end).await?;
+async fn clean_view_sync(config: &CleanViewConfig, ctx: &SyncContext<'_>) -> Result<()> {
+    let (aw, llm) = (config.aw()?, config.llm().await?);
+    let mut data_by_day: Vec<(Date, Vec<String>)> = Vec::new();
+
+    // Each window is one of the goal's days, which end at its deadline.
+    let days = ctx.goal_days(&config.goal_name).await?;
+    let today = days.date(OffsetDateTime::now_utc());
+    for days_ago in (0..config.lookback_days).rev() {
+        let date = today - Duration::days(days_ago);
+        let start = days.start(date);
+        let end = days.start(date.next_day().context("date out of range")?);
+        let events = ctx
+            .retry("fetching ActivityWatch events", || {
+                aw.get_events(&config.window_bucket, &start, &end)
+            })
+            .await?;
 
         let entries: HashSet<_> = events
             .into_iter()
@@ -52,27 +74,21 @@ pub async fn clean_view_sync(config: &CleanViewConfig, beeminder: &BeeminderClie
             })
             .map(|event| event.data.title)
             .collect();
+        debug!(%start, %end, titles = entries.len(), "collected browser window titles");
+        ctx.seen(entries.len());
 
-        let daystamp = format!(
-            "{:04}{:02}{:02}",
-            start.year(),
-            start.month() as u8,
-            start.day()
-        );
-        data_by_day.push((daystamp, entries.into_iter().collect()));
+        data_by_day.push((date, entries.into_iter().collect()));
     }
 
-    let existing_datapoints = beeminder
-        .get_datapoints(&config.goal_name, None, Some(50), None, None)
-        .await?;
-
-    for (daystamp, titles) in &data_by_day {
+    let mut desired = Vec::with_capacity(data_by_day.len());
+    for (date, titles) in &data_by_day {
         let (comment, value) = {
             if titles.is_empty() {
                 ("🫙 No titles.".to_string(), 1.0)
             } else {
                 let prompt = get_prompt(&config.prompt_template, titles);
-                let result = llm.chat(&prompt).await?;
+                let result = ctx.retry("asking the LLM", || llm.chat(&prompt)).await?;
+                debug!(%date, answer = %result.trim(), "LLM verdict");
 
                 if result.trim() == "no" {
                     ("✨ LLM approved.".to_string(), 1.0)
@@ -81,38 +97,39 @@ pub async fn clean_view_sync(config: &CleanViewConfig, beeminder: &BeeminderClie
                 }
             }
         };
+        desired.push(Desired {
+            goal: config.goal_name.clone(),
+            date: *date,
+            value,
+            comment,
+            requestid: format!("beesync-clean_view-v1:{}", daystamp(*date)),
+            // Datapoints from before reconciliation carry no requestid.
+            adopt_unkeyed: true,
+        });
+    }
 
-        let mut add_new_datapoint = true;
-        for dp in &existing_datapoints {
-            if *daystamp == dp.daystamp && (value - dp.value).abs() > 0.01 {
-                println!("  ❌ Deleting existing wrong datapoint {daystamp}.");
-                beeminder
-                    .delete_datapoint(&config.goal_name, &dp.id)
-                    .await?;
-            } else if *daystamp == dp.daystamp {
-                add_new_datapoint = false;
-            }
-        }
+    reconcile::reconcile(ctx, desired).await
+}
 
-        if add_new_datapoint {
-            if (value - 1.0).abs() > 0.01 {
-                println!("  💦 Dirty datapoint for daystamp: {daystamp}.");
-            } else {
-                println!("  ✨ Clean datapoint for daystamp: {daystamp}.");
-            }
+impl SyncModule for CleanViewConfig {
+    const NAME: &'static str = "clean_view";
+    const EMOJI: &'static str = "🧹";
 
-            let dp = CreateDatapoint {
-                value,
-                comment: Some(comment),
-                timestamp: None,
-                daystamp: Some(daystamp.clone()),
-                requestid: None,
-            };
+    fn goals(&self) -> Vec<String> {
+        vec![self.goal_name.clone()]
+    }
+
+    fn sync<'a>(&'a self, ctx: &'a SyncContext<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(clean_view_sync(self, ctx))
+    }
 
-            beeminder.create_datapoint(&config.goal_name, &dp).await?;
-        } else {
-            println!("  ✅ Existing datapoint for {daystamp} is correct.");
-        }
+    fn check(&self) -> BoxFuture<'_, Vec<Check>> {
+        Box::pin(async move {
+            let key = Check::key("openrouter_key", &self.openrouter_key).await;
+            let aw = self.aw();
+            let bucket =
+                check::aw_bucket(aw, &self.activity_watch_base_url, &self.window_bucket).await;
+            vec![key, bucket]
+        })
     }
-    Ok(())
 }
@@ -0,0 +1,292 @@
+use crate::lock::OnConflict;
+use crate::module::{Module, REGISTRY};
+use anyhow::{bail, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::{path::PathBuf, sync::Arc};
+
+/// Synchronizes data from various services to Beeminder goals.
+#[derive(Parser)]
+#[command(name = "beesync", version)]
+pub struct Cli {
+    /// Path to the configuration file [default: $BEESYNC_CONFIG, else
+    /// $XDG_CONFIG_HOME/beesync/config.toml, else ./config.toml].
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Print debug detail such as HTTP requests and dedup decisions; repeat
+    /// for everything.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Only print failures.
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    /// Also log to this file, rotated daily. It always receives the module
+    /// reports, even with `--quiet`.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Sync every selected module (the default).
+    Run(RunArgs),
+    /// List known modules and whether they are configured.
+    List,
+    /// Validate the configuration, then verify credentials, goals and
+    /// sources against the live services without writing anything.
+    Check(CheckArgs),
+    /// Show what the selected modules would change without writing
+    /// (same as `run --dry-run`).
+    Preview(PreviewArgs),
+    /// Keep running and sync each selected module on its `schedule`.
+    Daemon(DaemonArgs),
+    /// Manage the entries of an encrypted vault, unlocked as `[vault]`
+    /// configures.
+    #[command(subcommand)]
+    Secrets(SecretsCommand),
+    /// Provision the goals defined in `[[goals]]` on Beeminder.
+    #[command(subcommand)]
+    Goals(GoalsCommand),
+}
+
+#[derive(Args, Default)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub selection: Selection,
+
+    /// Compute and print every change without writing to Beeminder.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// What to do when another beesync run is syncing the same config or
+    /// module; overrides `on_conflict` from the config.
+    #[arg(long, value_name = "POLICY")]
+    pub on_conflict: Option<OnConflict>,
+
+    /// Print a machine-readable run report to stdout; module output moves
+    /// to stderr.
+    #[arg(long, value_name = "FORMAT")]
+    pub report: Option<ReportFormat>,
+}
+
+#[derive(Args)]
+pub struct PreviewArgs {
+    #[command(flatten)]
+    pub selection: Selection,
+
+    /// Print a machine-readable run report to stdout; module output moves
+    /// to stderr.
+    #[arg(long, value_name = "FORMAT")]
+    pub report: Option<ReportFormat>,
+}
+
+#[derive(Args)]
+pub struct CheckArgs {
+    #[command(flatten)]
+    pub selection: Selection,
+
+    /// Only validate the configuration file; contact no service.
+    #[arg(long)]
+    pub offline: bool,
+}
+
+#[derive(Args)]
+pub struct DaemonArgs {
+    #[command(flatten)]
+    pub selection: Selection,
+
+    /// Compute and print every change without writing to Beeminder.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// What to do when another beesync run is syncing a scheduled module;
+    /// overrides `on_conflict` from the config.
+    #[arg(long, value_name = "POLICY")]
+    pub on_conflict: Option<OnConflict>,
+}
+
+#[derive(Subcommand)]
+pub enum SecretsCommand {
+    /// Store a secret read from stdin, creating the vault if needed.
+    Set {
+        /// The vault file.
+        vault: PathBuf,
+        /// The entry to store it under.
+        entry: String,
+    },
+    /// Print a stored secret.
+    Get {
+        /// The vault file.
+        vault: PathBuf,
+        entry: String,
+    },
+    /// Print the names of the stored secrets.
+    List {
+        /// The vault file.
+        vault: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum GoalsCommand {
+    /// Create the defined goals that are missing and report how existing
+    /// ones differ from their definition, which is left for you to settle.
+    Sync {
+        /// Show what would be created without creating it.
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ReportFormat {
+    Json,
+}
+
+#[derive(Args, Default)]
+pub struct Selection {
+    /// Only run these modules (comma-separated). `github` selects every
+    /// GitHub instance, `github:work` only the one named `work`.
+    #[arg(long, value_delimiter = ',', value_name = "MODULES")]
+    pub only: Vec<String>,
+
+    /// Skip these modules (comma-separated, like `--only`).
+    #[arg(long, value_delimiter = ',', value_name = "MODULES")]
+    pub skip: Vec<String>,
+}
+
+impl Selection {
+    /// Rejects module names that are not in the registry.
+    pub fn validate(&self) -> Result<()> {
+        for entry in self.only.iter().chain(&self.skip) {
+            let name = module_name(entry);
+            if !REGISTRY.iter().any(|spec| spec.name == name) {
+                let known: Vec<_> = REGISTRY.iter().map(|spec| spec.name).collect();
+                bail!(
+                    "unknown module '{name}'; known modules: {}",
+                    known.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the instance with this [`Module::label`] is selected.
+    pub fn includes(&self, label: &str) -> bool {
+        let selects = |entry: &String| entry == label || entry == module_name(label);
+        (self.only.is_empty() || self.only.iter().any(selects)) && !self.skip.iter().any(selects)
+    }
+
+    /// Whether any instance of module `name` can be selected.
+    pub fn includes_module(&self, name: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|only| module_name(only) == name))
+            && !self.skip.iter().any(|skip| skip == name)
+    }
+
+    pub fn apply(&self, modules: Vec<Arc<dyn Module>>) -> Vec<Arc<dyn Module>> {
+        modules
+            .into_iter()
+            .filter(|module| self.includes(&module.label()))
+            .collect()
+    }
+}
+
+/// The module part of a label such as `github:work`.
+fn module_name(label: &str) -> &str {
+    label.split_once(':').map_or(label, |(name, _)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selection(args: &[&str]) -> Selection {
+        let cli = Cli::try_parse_from([&["beesync", "run"], args].concat()).unwrap();
+        match cli.command {
+            Some(Command::Run(args)) => args.selection,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn only_and_skip_accept_comma_separated_lists() {
+        let selection = selection(&["--only", "github,daylio", "--skip", "daylio"]);
+
+        assert!(selection.includes("github"));
+        assert!(!selection.includes("daylio"));
+        assert!(!selection.includes("focusmate"));
+    }
+
+    #[test]
+    fn instances_are_selected_by_module_or_label() {
+        let selection = selection(&["--only", "github,category:work", "--skip", "github:old"]);
+
+        assert!(selection.includes("github"));
+        assert!(selection.includes("github:personal"));
+        assert!(!selection.includes("github:old"));
+        assert!(selection.includes("category:work"));
+        assert!(!selection.includes("category"));
+        assert!(selection.includes_module("category"));
+        assert!(!selection.includes_module("daylio"));
+        assert!(selection.validate().is_ok());
+    }
+
+    #[test]
+    fn empty_selection_includes_everything() {
+        assert!(Selection::default().includes("clean_view"));
+    }
+
+    #[test]
+    fn unknown_module_names_are_rejected() {
+        let error = selection(&["--skip", "gitub"])
+            .validate()
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.starts_with("unknown module 'gitub'"), "{error}");
+    }
+
+    #[test]
+    fn dry_run_flag_is_parsed() {
+        let cli = Cli::try_parse_from(["beesync", "run", "--dry-run"]).unwrap();
+
+        assert!(matches!(cli.command, Some(Command::Run(args)) if args.dry_run));
+    }
+
+    #[test]
+    fn report_format_is_parsed() {
+        let cli = Cli::try_parse_from(["beesync", "preview", "--report", "json"]).unwrap();
+
+        assert!(matches!(
+            cli.command,
+            Some(Command::Preview(PreviewArgs {
+                report: Some(ReportFormat::Json),
+                ..
+            }))
+        ));
+        assert!(Cli::try_parse_from(["beesync", "run", "--report", "yaml"]).is_err());
+    }
+
+    #[test]
+    fn secrets_subcommands_are_parsed() {
+        let cli = Cli::try_parse_from(["beesync", "secrets", "set", "s.vault", "github"]).unwrap();
+
+        assert!(matches!(
+            cli.command,
+            Some(Command::Secrets(SecretsCommand::Set { entry, .. })) if entry == "github"
+        ));
+        assert!(Cli::try_parse_from(["beesync", "secrets", "get", "s.vault"]).is_err());
+    }
+
+    #[test]
+    fn config_is_accepted_after_the_subcommand() {
+        let cli = Cli::try_parse_from(["beesync", "list", "--config", "other.toml"]).unwrap();
+
+        assert_eq!(cli.config, Some(PathBuf::from("other.toml")));
+    }
+}
@@ -1,34 +1,633 @@
-use crate::category_sync::CategorySyncConfig;
-use crate::clean_tube_sync::CleanTubeConfig;
-use crate::clean_view_sync::CleanViewConfig;
-use crate::daylio_sync::DaylioConfig;
-use crate::fatebook_sync::FatebookConfig;
-use crate::focusmate_sync::FocusmateConfig;
-use crate::github_sync::GitHubConfig;
+use crate::backend::BackendConfig;
+use crate::goals::GoalConfig;
 use crate::key::Key;
-use anyhow::Result;
-use serde::Deserialize;
+use crate::lock::OnConflict;
+use crate::module::{Module, ModuleOptions, REGISTRY};
+use crate::notify::NotifyConfig;
+use crate::retry::RetryConfig;
+use crate::timezone::TimeZone;
+use crate::vault::VaultConfig;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{de::IntoDeserializer, Deserialize, Deserializer};
+use std::{
+    ffi::OsString,
+    num::NonZeroUsize,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Where the config file is looked for when `--config` is not given.
+const CONFIG_ENV: &str = "BEESYNC_CONFIG";
+
+fn default_concurrency() -> NonZeroUsize {
+    NonZeroUsize::new(4).unwrap()
+}
 
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub beeminder_key: Key,
     pub beeminder_username: String,
-    pub category: Option<CategorySyncConfig>,
-    pub clean_tube: Option<CleanTubeConfig>,
-    pub clean_view: Option<CleanViewConfig>,
-    pub daylio: Option<DaylioConfig>,
-    pub focusmate: Option<FocusmateConfig>,
-    pub fatebook: Option<FatebookConfig>,
-    pub github: Option<GitHubConfig>,
+    /// Where datapoints go: the Beeminder API, or a local stand-in.
+    #[serde(default)]
+    pub beeminder_backend: BackendConfig,
+    /// Maximum number of modules syncing at the same time.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: NonZeroUsize,
+    /// What a run does when another beesync process is syncing the same
+    /// config or module.
+    #[serde(default)]
+    pub on_conflict: OnConflict,
+    /// How calls to Beeminder and the sources are retried; modules can
+    /// override it with their own `retry` key.
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Where module failures (and optionally successes) are reported.
+    #[serde(default)]
+    pub notify: NotifyConfig,
+    /// Longest a module may sync before it is cancelled; modules can
+    /// override it with their own `timeout` key.
+    #[serde(default, deserialize_with = "duration")]
+    pub timeout: Option<Duration>,
+    /// Prometheus textfile-collector file rewritten after each run.
+    pub metrics_file: Option<PathBuf>,
+    /// IANA time zone deciding which day datapoints count for; the
+    /// machine's by default.
+    pub timezone: Option<TimeZone>,
+    /// Goals `beesync goals sync` creates on Beeminder.
+    #[serde(default)]
+    pub goals: Vec<GoalConfig>,
+    /// How vault keys (`{ vault = "...", entry = "..." }`) are unlocked.
+    #[serde(default)]
+    pub vault: VaultConfig,
+    /// Module sections, keyed by [`crate::module::ModuleSpec::name`].
+    #[serde(skip)]
+    pub sections: toml::Table,
+}
+
+/// Deserializes a humantime duration such as `"90s"` or `"5m"`.
+pub(crate) fn duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(&value)
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("invalid duration '{value}': {e}")))
+}
+
+/// The config file to use: `--config`, else `$BEESYNC_CONFIG`, else
+/// `$XDG_CONFIG_HOME/beesync/config.toml` (`~/.config/beesync/config.toml`),
+/// else `config.toml` in the working directory.
+pub fn locate(explicit: Option<&Path>) -> Result<PathBuf> {
+    locate_in(
+        explicit,
+        std::env::var_os(CONFIG_ENV),
+        crate::paths::config_dir().map(|dir| dir.join("config.toml")),
+        PathBuf::from("config.toml"),
+    )
+}
+
+/// `local` is the fallback in the working directory.
+fn locate_in(
+    explicit: Option<&Path>,
+    from_env: Option<OsString>,
+    xdg: Result<PathBuf>,
+    local: PathBuf,
+) -> Result<PathBuf> {
+    if let Some(path) = explicit {
+        return Ok(path.to_path_buf());
+    }
+    if let Some(path) = from_env.filter(|path| !path.is_empty()) {
+        return Ok(path.into());
+    }
+    match xdg {
+        Ok(xdg) if xdg.exists() => Ok(xdg),
+        _ if local.exists() => Ok(local),
+        Ok(xdg) => bail!(
+            "no config file found: pass --config, set ${CONFIG_ENV}, or create {} \
+             (or config.toml in the working directory)",
+            xdg.display()
+        ),
+        Err(e) => Err(e.context(format!(
+            "no config file found: pass --config, set ${CONFIG_ENV}, or create \
+             config.toml in the working directory"
+        ))),
+    }
 }
 
 impl Config {
-    pub fn load() -> Result<Self> {
-        let config_path = std::env::args()
-            .nth(1)
-            .unwrap_or_else(|| "config.toml".to_string());
-        let config_str = std::fs::read_to_string(config_path)?;
-        let config: Self = toml::from_str(&config_str)?;
+    /// Reads the config at `path` along with the files it includes, and
+    /// replaces `${VAR}` in its strings with environment variables.
+    ///
+    /// Every module section is parsed up front, so unknown keys and invalid
+    /// values are reported with their file, line and column.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut sources = vec![Source::read(path)?];
+        if let Some(include) = sources[0].root.remove("include") {
+            let base = path.parent().unwrap_or_else(|| Path::new(""));
+            for file in included_files(base, include)
+                .with_context(|| format!("in config file {}", path.display()))?
+            {
+                sources.push(Source::read(&file)?);
+            }
+        }
+        Self::from_sources(sources, &|name| std::env::var(name).ok())
+    }
+
+    fn from_sources(
+        mut sources: Vec<Source>,
+        var: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Self> {
+        let module_names: Vec<_> = REGISTRY.iter().map(|spec| spec.name).collect();
+        let mut errors = Vec::new();
+        let mut globals = toml_edit::InlineTable::new();
+        let mut sections = toml::Table::new();
+        for (index, source) in sources.iter_mut().enumerate() {
+            let mut root = std::mem::take(&mut source.root);
+            let mut failed = Vec::new();
+            interpolate_table(&mut root, var, &mut failed);
+            for (error, span) in failed {
+                errors.push(source.describe(&error, span, &[]));
+            }
+            let keys: Vec<_> = root.iter().map(|(key, _)| key.to_string()).collect();
+            for key in keys {
+                let (key, item) = root.remove_entry(&key).expect("the key was listed");
+                let span = item.span().or_else(|| key.span());
+                let Ok(value) = item.into_value() else {
+                    continue;
+                };
+                if let Some(spec) = REGISTRY.iter().find(|spec| spec.name == key.get()) {
+                    if let Err(e) = spec.parse_spanned(value.clone()) {
+                        errors.push(source.describe(&e, span.clone(), ModuleOptions::KEYS));
+                    }
+                    let merged = toml::Value::deserialize(value.into_deserializer())
+                        .map_err(anyhow::Error::from)
+                        .and_then(|section| merge(&mut sections, key.get(), section));
+                    if let Err(e) = merged {
+                        errors.push(source.describe(&e, span, &[]));
+                    }
+                } else if index == 0 {
+                    globals.insert_formatted(&key, value);
+                } else {
+                    let error = match did_you_mean(key.get(), module_names.iter().copied()) {
+                        Some(name) => anyhow!("unknown key `{key}`; did you mean `{name}`?"),
+                        None => anyhow!(
+                            "`{key}` cannot be set in an included file, only module sections"
+                        ),
+                    };
+                    errors.push(source.describe(&error, key.span(), &[]));
+                }
+            }
+        }
+
+        let config = Self::deserialize(toml_edit::Value::from(globals).into_deserializer());
+        let mut config = match config {
+            Ok(config) if errors.is_empty() => config,
+            Ok(_) => return Err(collect(errors)),
+            Err(e) => {
+                errors.insert(0, sources[0].describe(&e.into(), None, &module_names));
+                return Err(collect(errors));
+            }
+        };
+        config.sections = sections;
+
+        let disabled: Vec<_> = config
+            .instances()?
+            .iter()
+            .filter(|module| !module.options().enabled)
+            .map(|module| module.label())
+            .collect();
+        if !disabled.is_empty() {
+            tracing::warn!(
+                "configured but disabled (enabled = false): {}",
+                disabled.join(", ")
+            );
+        }
         Ok(config)
     }
+
+    /// Parses every enabled module instance, in registry order.
+    pub fn modules(&self) -> Result<Vec<Arc<dyn Module>>> {
+        let mut modules = self.instances()?;
+        modules.retain(|module| module.options().enabled);
+        Ok(modules)
+    }
+
+    /// Parses every configured module instance, including disabled ones.
+    fn instances(&self) -> Result<Vec<Arc<dyn Module>>> {
+        let mut modules = Vec::new();
+        for spec in REGISTRY {
+            if let Some(section) = self.sections.get(spec.name) {
+                modules.extend(spec.parse(section.clone())?);
+            }
+        }
+        Ok(modules)
+    }
+}
+
+/// A config file, kept so that errors can point into it.
+struct Source {
+    path: PathBuf,
+    text: String,
+    root: toml_edit::Table,
+}
+
+impl Source {
+    fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+        Self::parse(path, text)
+    }
+
+    fn parse(path: &Path, text: String) -> Result<Self> {
+        let document = toml_edit::ImDocument::parse(text.clone())
+            .with_context(|| format!("parsing config file {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            text,
+            root: document.as_table().clone(),
+        })
+    }
+
+    /// `error`, prefixed with the file and the line and column of the value
+    /// it is about, else of `fallback`. Unknown keys get a suggestion from
+    /// the keys serde expected plus `also_known`.
+    fn describe(
+        &self,
+        error: &anyhow::Error,
+        fallback: Option<Range<usize>>,
+        also_known: &[&str],
+    ) -> String {
+        let mut span = fallback;
+        let mut parts = Vec::new();
+        for cause in error.chain() {
+            match cause.downcast_ref::<toml_edit::de::Error>() {
+                Some(error) => {
+                    span = error.span().or(span);
+                    parts.push(explain(error.message().trim(), also_known));
+                }
+                None => parts.push(cause.to_string()),
+            }
+        }
+        let message = parts.join(": ");
+        match span {
+            Some(span) => {
+                let before = &self.text[..span.start.min(self.text.len())];
+                let line = before.matches('\n').count() + 1;
+                let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+                format!("{}:{line}:{column}: {message}", self.path.display())
+            }
+            None => format!("{}: {message}", self.path.display()),
+        }
+    }
+}
+
+/// One error for all the problems found in the config.
+fn collect(errors: Vec<String>) -> anyhow::Error {
+    match errors.as_slice() {
+        [error] => anyhow!("{error}"),
+        errors => anyhow!(
+            "{} problems in the config:\n  {}",
+            errors.len(),
+            errors.join("\n  ")
+        ),
+    }
+}
+
+/// Serde's message for an unknown field, with a suggestion when the field
+/// looks like a typo of one it expected (or of `also_known`).
+fn explain(message: &str, also_known: &[&str]) -> String {
+    let Some((field, expected)) = unknown_field(message) else {
+        return message.to_string();
+    };
+    match did_you_mean(
+        field,
+        expected.into_iter().chain(also_known.iter().copied()),
+    ) {
+        Some(known) => format!("unknown key `{field}`; did you mean `{known}`?"),
+        None => message.to_string(),
+    }
+}
+
+/// Splits serde's "unknown field `x`, expected one of `a`, `b`" message into
+/// the field and the expected ones.
+fn unknown_field(message: &str) -> Option<(&str, Vec<&str>)> {
+    let (field, expected) = message.strip_prefix("unknown field `")?.split_once('`')?;
+    Some((field, expected.split('`').skip(1).step_by(2).collect()))
+}
+
+/// The candidate closest to `unknown`, if one is close enough to be what was
+/// meant.
+fn did_you_mean<'a>(
+    unknown: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    candidates
+        .into_iter()
+        .map(|candidate| (strsim::jaro_winkler(unknown, candidate), candidate))
+        .filter(|(similarity, _)| *similarity > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, candidate)| candidate)
+}
+
+/// The files matched by the `include` patterns, relative to `base` and each
+/// pattern's matches sorted, so the order of sections is predictable.
+fn included_files(base: &Path, include: toml_edit::Item) -> Result<Vec<PathBuf>> {
+    let patterns = include
+        .into_value()
+        .ok()
+        .and_then(|value| Vec::<String>::deserialize(value.into_deserializer()).ok())
+        .context("include must be a list of file patterns")?;
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let full = base.join(crate::paths::expand_tilde(&pattern)?);
+        let mut matches = glob::glob(&full.to_string_lossy())
+            .with_context(|| format!("invalid include pattern '{pattern}'"))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if matches.is_empty() && !pattern.contains(['*', '?', '[']) {
+            bail!("included file {} does not exist", full.display());
+        }
+        matches.sort();
+        files.extend(matches);
+    }
+    Ok(files)
+}
+
+/// Adds a module section to `sections`. Instances of a module (`[[github]]`)
+/// may be spread across files; a `[module]` table may only be set once.
+fn merge(sections: &mut toml::Table, key: &str, section: toml::Value) -> Result<()> {
+    match (sections.get_mut(key), section) {
+        (None, section) => {
+            sections.insert(key.to_string(), section);
+        }
+        (Some(toml::Value::Array(existing)), toml::Value::Array(more)) => existing.extend(more),
+        (Some(_), _) => bail!("[{key}] is already set in another file"),
+    }
+    Ok(())
+}
+
+/// A config error and the span of the value it is about.
+type Positioned = (anyhow::Error, Option<Range<usize>>);
+
+/// Replaces `${VAR}` with the value of `VAR` in every string of `table`;
+/// `$${` stands for a literal `${`. Strings that cannot be interpolated are
+/// left as they are, and their errors added to `errors`.
+fn interpolate_table(
+    table: &mut toml_edit::Table,
+    var: &dyn Fn(&str) -> Option<String>,
+    errors: &mut Vec<Positioned>,
+) {
+    for (_, item) in table.iter_mut() {
+        match item {
+            toml_edit::Item::Value(value) => interpolate_value(value, var, errors),
+            toml_edit::Item::Table(table) => interpolate_table(table, var, errors),
+            toml_edit::Item::ArrayOfTables(tables) => {
+                for table in tables.iter_mut() {
+                    interpolate_table(table, var, errors);
+                }
+            }
+            toml_edit::Item::None => {}
+        }
+    }
+}
+
+fn interpolate_value(
+    value: &mut toml_edit::Value,
+    var: &dyn Fn(&str) -> Option<String>,
+    errors: &mut Vec<Positioned>,
+) {
+    match value {
+        toml_edit::Value::String(text) => match interpolate(text.value(), var) {
+            // Replacing a value drops its position, so keep unchanged ones.
+            Ok(interpolated) if interpolated != *text.value() => *value = interpolated.into(),
+            Ok(_) => {}
+            Err(e) => errors.push((e, text.span())),
+        },
+        toml_edit::Value::Array(values) => {
+            for value in values.iter_mut() {
+                interpolate_value(value, var, errors);
+            }
+        }
+        toml_edit::Value::InlineTable(table) => {
+            for (_, value) in table.iter_mut() {
+                interpolate_value(value, var, errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn interpolate(text: &str, var: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = escaped;
+        } else if let Some(reference) = rest.strip_prefix("${") {
+            let end = reference
+                .find('}')
+                .with_context(|| format!("unclosed '${{' in '{text}'"))?;
+            let name = &reference[..end];
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                bail!("invalid variable name '{name}' in '{text}'");
+            }
+            let value =
+                var(name).with_context(|| format!("environment variable '{name}' is not set"))?;
+            out.push_str(&value);
+            rest = &reference[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const GLOBALS: &str = r#"
+beeminder_username = "me"
+beeminder_key = { env = "BEESYNC_TEST_KEY" }
+"#;
+
+    fn parse(text: &str) -> Result<Config> {
+        let source = Source::parse(Path::new("config.toml"), format!("{GLOBALS}{text}"))?;
+        Config::from_sources(vec![source], &|name| {
+            (name == "HOST").then(|| "aw.local".to_string())
+        })
+    }
+
+    fn error(text: &str) -> String {
+        format!("{:#}", parse(text).err().unwrap())
+    }
+
+    #[test]
+    fn config_is_located_by_flag_then_environment_then_xdg() {
+        let dir =
+            std::env::temp_dir().join(format!("beesync-config-{}-locate", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let xdg = dir.join("config.toml");
+        let local = dir.join("local.toml");
+        let located = |explicit: Option<&str>, env: Option<&str>| {
+            locate_in(
+                explicit.map(Path::new),
+                env.map(OsString::from),
+                Ok(xdg.clone()),
+                local.clone(),
+            )
+            .map_err(|e| e.to_string())
+        };
+
+        let missing = located(None, None);
+        fs::write(&local, "").unwrap();
+        let fallback = located(None, None);
+        fs::write(&xdg, "").unwrap();
+        let found = located(None, Some(""));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            located(Some("a.toml"), Some("b.toml")).unwrap(),
+            Path::new("a.toml")
+        );
+        assert_eq!(located(None, Some("b.toml")).unwrap(), Path::new("b.toml"));
+        assert_eq!(found.unwrap(), xdg);
+        assert_eq!(fallback.unwrap(), local);
+        assert!(missing.unwrap_err().starts_with("no config file found"));
+    }
+
+    #[test]
+    fn includes_add_module_sections() {
+        let dir =
+            std::env::temp_dir().join(format!("beesync-config-{}-include", std::process::id()));
+        fs::create_dir_all(dir.join("modules")).unwrap();
+        let main = dir.join("config.toml");
+        let github = "[[github]]\nname = \"personal\"\ngoal_name = \"commits\"\nusername = \"me\"";
+        fs::write(
+            &main,
+            format!(
+                "{GLOBALS}include = [\"modules/*.toml\"]\n{}",
+                github.replace("personal", "work")
+            ),
+        )
+        .unwrap();
+        fs::write(dir.join("modules/a.toml"), github).unwrap();
+        fs::write(
+            dir.join("modules/b.toml"),
+            "[fatebook]\nkey = { env = \"FB\" }",
+        )
+        .unwrap();
+
+        let config = Config::load(&main);
+        fs::write(
+            dir.join("modules/c.toml"),
+            "[fatebook]\nkey = { env = \"FB2\" }",
+        )
+        .unwrap();
+        let conflict = Config::load(&main);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.sections["github"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            config.sections["fatebook"]["key"]["env"].as_str(),
+            Some("FB")
+        );
+        assert!(!config.sections.contains_key("include"));
+        let conflict = format!("{:#}", conflict.err().unwrap());
+        assert!(
+            conflict.ends_with("c.toml:1:1: [fatebook] is already set in another file"),
+            "{conflict}"
+        );
+    }
+
+    #[test]
+    fn variables_are_interpolated_into_strings() {
+        let config = parse(
+            r#"
+[focusmate]
+key = { cmd = "echo $${HOME} $$" }
+goal_name = "${HOST}"
+auto_tags = []
+"#,
+        )
+        .unwrap();
+
+        let focusmate = &config.sections["focusmate"];
+        assert_eq!(focusmate["goal_name"].as_str(), Some("aw.local"));
+        assert_eq!(focusmate["key"]["cmd"].as_str(), Some("echo ${HOME} $$"));
+        assert_eq!(
+            error("[fatebook]\nkey = { env = \"${MISSING}\" }"),
+            "config.toml:5:15: environment variable 'MISSING' is not set"
+        );
+        assert!(interpolate("${unclosed", &|_| None).is_err());
+    }
+
+    #[test]
+    fn unknown_keys_are_located_with_a_suggestion() {
+        assert_eq!(
+            error("[clean-tube]\nlookback_days = 3"),
+            "config.toml:4:2: unknown key `clean-tube`; did you mean `clean_tube`?"
+        );
+        assert_eq!(
+            error("[github]\ngoal_name = \"commits\"\nusername = \"me\"\nshedule = { every = \"1h\" }"),
+            "config.toml:7:1: invalid [github] section: unknown key `shedule`; did you mean `schedule`?"
+        );
+        assert_eq!(
+            error("[fatebook]\nkey = { env = \"X\" }\n[[daylio]]\nsource = \"d.csv\"\nmappings = [{ activity = \"run\", beeminder_goal = \"run\", present_value = 1.0, absent_value = 0.0, prefil_value = 0.0 }]"),
+            "config.toml:8:98: invalid [[daylio]] entry 1: unknown key `prefil_value`; did you mean `prefill_value`?"
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let error =
+            error("[github]\ngoal_name = 3\nusername = \"me\"\n[focusmate]\nkey = { env = \"X\" }");
+
+        assert_eq!(
+            error,
+            "2 problems in the config:\n  \
+             config.toml:5:13: invalid [github] section: invalid type: integer `3`, expected a string\n  \
+             config.toml:7:1: invalid [focusmate] section: missing field `goal_name`"
+        );
+    }
+
+    #[test]
+    fn disabled_modules_are_configured_but_not_run() {
+        let config = parse(
+            "[[github]]\ngoal_name = \"commits\"\nusername = \"me\"\n\
+             [[github]]\nname = \"old\"\nenabled = false\ngoal_name = \"c\"\nusername = \"me\"",
+        )
+        .unwrap();
+
+        let labels: Vec<_> = config
+            .modules()
+            .unwrap()
+            .iter()
+            .map(|m| m.label())
+            .collect();
+        assert_eq!(labels, ["github"]);
+        assert_eq!(config.instances().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn typos_are_told_apart_from_other_keys() {
+        assert_eq!(
+            did_you_mean("lookback_day", ["goal_name", "lookback_days"]),
+            Some("lookback_days")
+        );
+        assert_eq!(did_you_mean("colour", ["goal_name", "lookback_days"]), None);
+        assert_eq!(
+            unknown_field("unknown field `b`, expected `a`"),
+            Some(("b", vec!["a"]))
+        );
+    }
 }
@@ -0,0 +1,86 @@
+use crate::logging::Verbosity;
+use crate::module::Module;
+use crate::runner::Runner;
+use crate::schedule::Schedule;
+use crate::timezone::TimeZone;
+use anyhow::{bail, Result};
+use std::{sync::Arc, time::Duration};
+use time::OffsetDateTime;
+use tokio::task::JoinSet;
+
+/// Longest single sleep while waiting for a run. Waking up regularly keeps
+/// schedules on time after the machine was suspended, since tokio's timers
+/// do not count suspended time.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// Runs every scheduled module on its own schedule, sharing one [`Runner`]
+/// (and so one Beeminder client, the concurrency limit and the goal locks).
+///
+/// A failed run is reported like in `beesync run` and the module simply runs
+/// again when it is next due. Returns once no schedule can fire again.
+pub async fn run(
+    runner: Arc<Runner>,
+    modules: Vec<Arc<dyn Module>>,
+    timezone: Arc<TimeZone>,
+    verbosity: Verbosity,
+) -> Result<()> {
+    let quiet = verbosity.is_quiet();
+    let mut schedules = JoinSet::new();
+    for module in modules {
+        let Some(schedule) = module.options().schedule.clone() else {
+            if !quiet {
+                println!(
+                    "⏸️  {}: no schedule, only runs with `beesync run`",
+                    module.title()
+                );
+            }
+            continue;
+        };
+        if !quiet {
+            println!("⏰ {}: {schedule}", module.title());
+        }
+        tracing::info!(module = %module.label(), %schedule, "scheduled");
+        schedules.spawn(run_on_schedule(
+            Arc::clone(&runner),
+            module,
+            schedule,
+            Arc::clone(&timezone),
+        ));
+    }
+    if schedules.is_empty() {
+        bail!("no selected module has a schedule; add e.g. `schedule = {{ every = \"1h\" }}` to its section");
+    }
+
+    while schedules.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn run_on_schedule(
+    runner: Arc<Runner>,
+    module: Arc<dyn Module>,
+    schedule: Schedule,
+    timezone: Arc<TimeZone>,
+) {
+    let mut due = schedule.first_run(timezone.now(), &timezone);
+    while let Some(at) = due {
+        tracing::debug!(module = %module.label(), next_run = %at, "waiting");
+        sleep_until(at, &timezone).await;
+        let started = timezone.now();
+        runner.run(vec![Arc::clone(&module)], started.date()).await;
+        // Runs that overlap several due times catch up with a single run.
+        due = schedule.next_after(started, &timezone);
+    }
+    tracing::warn!(module = %module.label(), "schedule never fires again");
+}
+
+async fn sleep_until(at: OffsetDateTime, timezone: &TimeZone) {
+    loop {
+        let Ok(remaining) = Duration::try_from(at - timezone.now()) else {
+            return;
+        };
+        if remaining.is_zero() {
+            return;
+        }
+        tokio::time::sleep(remaining.min(MAX_SLEEP)).await;
+    }
+}
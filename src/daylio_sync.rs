@@ -1,8 +1,8 @@
+use crate::check::Check;
+use crate::module::{report, BoxFuture, SyncContext, SyncModule};
+use crate::reconcile::{self, Desired};
+use crate::timezone::daystamp;
 use anyhow::{bail, Context, Result};
-use beeminder::{
-    types::{CreateDatapoint, DatapointFull, UpdateDatapoint},
-    BeeminderClient,
-};
 use serde::Deserialize;
 use std::{
     collections::{HashMap, HashSet},
@@ -11,6 +11,7 @@ use std::{
     time::SystemTime,
 };
 use time::{macros::format_description, Date, Duration};
+use tracing::debug;
 
 fn default_reconcile_days() -> i64 {
     7
@@ -21,6 +22,7 @@ fn default_prefill_horizon_days() -> i64 {
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct DaylioConfig {
     pub source: String,
     #[serde(default = "default_reconcile_days")]
@@ -33,6 +35,7 @@ pub struct DaylioConfig {
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct DaylioMapping {
     pub activity: String,
     pub beeminder_goal: String,
@@ -47,25 +50,6 @@ struct DaylioDay {
     activities: HashSet<String>,
 }
 
-#[derive(Debug)]
-struct ExistingPoint {
-    id: String,
-    value: Option<f64>,
-    comment: Option<String>,
-    requestid: Option<String>,
-    system: bool,
-}
-
-#[derive(Debug)]
-struct Target {
-    goal: String,
-    date: Date,
-    value: f64,
-    comment: String,
-    requestid: String,
-    existing: Vec<ExistingPoint>,
-}
-
 /// True when `source` is a glob pattern rather than a plain path.
 fn is_pattern(source: &str) -> bool {
     source.contains(['*', '?', '['])
@@ -235,15 +219,6 @@ fn date_range(start: Date, end: Date) -> Vec<Date> {
     dates
 }
 
-fn daystamp(date: Date) -> String {
-    format!(
-        "{:04}{:02}{:02}",
-        date.year(),
-        date.month() as u8,
-        date.day()
-    )
-}
-
 fn target_dates(
     config: &DaylioConfig,
     days: &[DaylioDay],
@@ -282,26 +257,17 @@ fn target_dates(
     Ok((reconcile, prefill))
 }
 
-fn point(point: DatapointFull) -> ExistingPoint {
-    ExistingPoint {
-        id: point.id,
-        value: point.value,
-        comment: point.comment,
-        requestid: point.requestid,
-        system: point.is_dummy.unwrap_or(false) || point.is_initial.unwrap_or(false),
-    }
-}
-
-fn plan(
+/// One datapoint per mapping and date: the export's verdict for the
+/// reconciled dates, the prefill value after them.
+fn desired(
     config: &DaylioConfig,
     days: &[DaylioDay],
     reconcile: &[Date],
     prefill: &[Date],
-    mut existing: HashMap<String, HashMap<String, Vec<ExistingPoint>>>,
-) -> Result<Vec<Target>> {
+) -> Vec<Desired> {
     let activities: HashMap<Date, &HashSet<String>> =
         days.iter().map(|day| (day.date, &day.activities)).collect();
-    let mut targets = Vec::new();
+    let mut desired = Vec::new();
     for mapping in &config.mappings {
         let goal = mapping.beeminder_goal.trim();
         for (date, value, state) in reconcile
@@ -328,151 +294,17 @@ fn plan(
                     .map(|date| (*date, mapping.prefill_value, "optimistic prefill")),
             )
         {
-            let stamp = daystamp(date);
-            let points = existing
-                .entry(goal.to_string())
-                .or_default()
-                .remove(&stamp)
-                .unwrap_or_default();
-            if points.iter().any(|point| point.system) {
-                bail!(
-                    "system datapoint found on {} {date}; refusing the entire Daylio plan",
-                    goal
-                )
-            }
-            let requestid = format!("beesync-daylio-v1:{stamp}");
-            if points
-                .iter()
-                .filter(|point| point.requestid.as_deref() == Some(&requestid))
-                .count()
-                > 1
-            {
-                bail!(
-                    "multiple canonical Daylio datapoints found on {} {date}",
-                    goal
-                )
-            }
-            targets.push(Target {
+            desired.push(Desired {
                 goal: goal.to_string(),
                 date,
                 value,
                 comment: format!("beesync/daylio: {} {state}", mapping.activity.trim()),
-                requestid,
-                existing: points,
+                requestid: format!("beesync-daylio-v1:{}", daystamp(date)),
+                adopt_unkeyed: false,
             });
         }
     }
-    Ok(targets)
-}
-
-fn same_value(actual: Option<f64>, expected: f64) -> bool {
-    actual.is_some_and(|actual| (actual - expected).abs() < 1e-9)
-}
-
-fn target_is_unchanged(target: &Target) -> bool {
-    target.existing.len() == 1
-        && target.existing[0].requestid.as_deref() == Some(&target.requestid)
-        && same_value(target.existing[0].value, target.value)
-        && target.existing[0].comment.as_deref() == Some(&target.comment)
-}
-
-fn target_action(target: &Target) -> &'static str {
-    if target_is_unchanged(target) {
-        return "✅ keep";
-    }
-
-    let canonical = target
-        .existing
-        .iter()
-        .any(|point| point.requestid.as_deref() == Some(&target.requestid));
-    match (canonical, target.existing.len()) {
-        (true, 1) => "✏️ update",
-        (true, _) => "🧹 update+prune",
-        (false, 0) => "➕ create",
-        (false, _) => "♻️ replace",
-    }
-}
-
-fn existing_values(target: &Target) -> String {
-    match target.existing.as_slice() {
-        [] => "-".to_string(),
-        points => points
-            .iter()
-            .map(|point| {
-                point
-                    .value
-                    .map_or_else(|| "?".to_string(), |value| value.to_string())
-            })
-            .collect::<Vec<_>>()
-            .join(","),
-    }
-}
-
-fn format_preview_table<'a>(targets: impl IntoIterator<Item = &'a Target>) -> String {
-    let headers = [
-        "goal".to_string(),
-        "date".to_string(),
-        "target".to_string(),
-        "existing".to_string(),
-        "action".to_string(),
-    ];
-    let rows: Vec<[String; 5]> = targets
-        .into_iter()
-        .map(|target| {
-            [
-                target.goal.clone(),
-                target.date.to_string(),
-                target.value.to_string(),
-                existing_values(target),
-                target_action(target).to_string(),
-            ]
-        })
-        .collect();
-
-    let widths = (0..5)
-        .map(|column| {
-            rows.iter()
-                .map(|row| row[column].chars().count())
-                .chain(std::iter::once(headers[column].chars().count()))
-                .max()
-                .unwrap_or(0)
-        })
-        .collect::<Vec<_>>();
-    let render = |row: &[String; 5]| {
-        format!(
-            "  {:<goal_width$} | {:<date_width$} | {:>target_width$} | {:>existing_width$} | {}\n",
-            row[0],
-            row[1],
-            row[2],
-            row[3],
-            row[4],
-            goal_width = widths[0],
-            date_width = widths[1],
-            target_width = widths[2],
-            existing_width = widths[3],
-        )
-    };
-    let separator = [
-        "-".repeat(widths[0]),
-        "-".repeat(widths[1]),
-        "-".repeat(widths[2]),
-        "-".repeat(widths[3]),
-        "-".repeat(widths[4]),
-    ];
-
-    let mut output = render(&headers);
-    output.push_str(&format!(
-        "  {} | {} | {} | {} | {}\n",
-        separator[0], separator[1], separator[2], separator[3], separator[4]
-    ));
-    for row in &rows {
-        output.push_str(&render(row));
-    }
-    output
-}
-
-fn pluralized(count: usize, singular: &str, plural: &str) -> String {
-    format!("{count} {}", if count == 1 { singular } else { plural })
+    desired
 }
 
 fn format_run_summary(rows: usize, latest: Date, apply: bool) -> String {
@@ -484,164 +316,79 @@ fn format_run_summary(rows: usize, latest: Date, apply: bool) -> String {
     format!("  {emoji} {mode} · source: {rows} rows, latest {latest}")
 }
 
-fn format_apply_plan(targets: &[Target]) -> String {
-    let mutations: Vec<&Target> = targets
+async fn daylio_sync(config: &DaylioConfig, ctx: &SyncContext<'_>) -> Result<()> {
+    let apply = config.apply && !ctx.dry_run;
+    let source = config.resolve_source()?;
+    config.validate(&source)?;
+    if is_pattern(&config.source) {
+        report!(ctx, "  📄 {}", source.display());
+    }
+    let days = parse_csv(&source)?;
+    ctx.seen(days.len());
+    let (reconcile, prefill) = target_dates(config, &days, ctx.today)?;
+    debug!(
+        days = days.len(),
+        reconcile = reconcile.len(),
+        prefill = prefill.len(),
+        "parsed Daylio export"
+    );
+
+    let goals: Vec<&str> = config
+        .mappings
         .iter()
-        .filter(|target| !target_is_unchanged(target))
+        .map(|mapping| mapping.beeminder_goal.trim())
         .collect();
-    let unchanged = targets.len() - mutations.len();
+    let existing = reconcile::fetch(ctx, goals).await?;
+    let targets = reconcile::plan(desired(config, &days, &reconcile, &prefill), existing)?;
 
-    if mutations.is_empty() {
-        let goals = targets
-            .iter()
-            .map(|target| target.goal.as_str())
-            .collect::<HashSet<_>>()
-            .len();
-        return format!(
-            "  ✅ already in sync — {} checked across {}\n",
-            pluralized(targets.len(), "datapoint", "datapoints"),
-            pluralized(goals, "goal", "goals")
-        );
-    }
+    report!(
+        ctx,
+        "{}",
+        format_run_summary(days.len(), days.last().unwrap().date, apply)
+    );
 
-    let mut output = format_preview_table(mutations.iter().copied());
-    output.push_str(&format!(
-        "  {}{}\n",
-        pluralized(mutations.len(), "change", "changes"),
-        if unchanged == 0 {
-            String::new()
+    if !apply {
+        reconcile::preview(ctx, &targets);
+        if config.apply {
+            report!(
+                ctx,
+                "  preview complete; run without --dry-run to apply all listed mutations"
+            );
         } else {
-            format!(
-                "; {} hidden",
-                pluralized(unchanged, "unchanged target", "unchanged targets")
-            )
-        }
-    ));
-    output
-}
-
-async fn apply_target(client: &BeeminderClient, target: &Target) -> Result<()> {
-    let canonical = target
-        .existing
-        .iter()
-        .find(|point| point.requestid.as_deref() == Some(&target.requestid));
-    let keeper_id = if let Some(canonical) = canonical {
-        if !same_value(canonical.value, target.value)
-            || canonical.comment.as_deref() != Some(&target.comment)
-        {
-            let update = UpdateDatapoint::new(canonical.id.clone())
-                .with_value(target.value)
-                .with_comment(&target.comment);
-            client.update_datapoint(&target.goal, &update).await?;
+            report!(
+                ctx,
+                "  preview complete; set daylio.apply = true to apply all listed mutations"
+            );
         }
-        canonical.id.clone()
-    } else {
-        let created = client
-            .create_datapoint(
-                &target.goal,
-                &CreateDatapoint {
-                    value: target.value,
-                    timestamp: None,
-                    daystamp: Some(daystamp(target.date)),
-                    comment: Some(target.comment.clone()),
-                    requestid: Some(target.requestid.clone()),
-                },
-            )
-            .await?;
-        created.id
-    };
-
-    for extra in target.existing.iter().filter(|point| point.id != keeper_id) {
-        client.delete_datapoint(&target.goal, &extra.id).await?;
+        return Ok(());
     }
 
-    let stamp = daystamp(target.date);
-    let current: Vec<DatapointFull> = client
-        .get_datapoints_full(&target.goal, None, None, None, None)
-        .await?
-        .into_iter()
-        .filter(|point| point.daystamp == stamp)
-        .collect();
-    if current.len() != 1
-        || current[0].id != keeper_id
-        || current[0].requestid.as_deref() != Some(&target.requestid)
-        || !same_value(current[0].value, target.value)
-        || current[0].is_dummy.unwrap_or(false)
-        || current[0].is_initial.unwrap_or(false)
-    {
-        bail!(
-            "post-write verification failed for {} {}",
-            target.goal,
-            target.date
-        )
-    }
-    Ok(())
+    reconcile::apply(ctx, &targets).await
 }
 
-pub async fn daylio_sync(
-    config: &DaylioConfig,
-    client: &BeeminderClient,
-    today: Date,
-) -> Result<()> {
-    println!("📔 daylio-sync");
-    let source = config.resolve_source()?;
-    config.validate(&source)?;
-    if is_pattern(&config.source) {
-        println!("  📄 {}", source.display());
-    }
-    let days = parse_csv(&source)?;
-    let (reconcile, prefill) = target_dates(config, &days, today)?;
+impl SyncModule for DaylioConfig {
+    const NAME: &'static str = "daylio";
+    const EMOJI: &'static str = "📔";
 
-    let mut snapshots = HashMap::new();
-    for mapping in &config.mappings {
-        let goal = mapping.beeminder_goal.trim().to_string();
-        if snapshots.contains_key(&goal) {
-            continue;
-        }
-        let mut by_day: HashMap<String, Vec<ExistingPoint>> = HashMap::new();
-        for datapoint in client
-            .get_datapoints_full(&goal, None, None, None, None)
-            .await
-            .with_context(|| format!("fetching all datapoints for {goal}"))?
-        {
-            by_day
-                .entry(datapoint.daystamp.clone())
-                .or_default()
-                .push(point(datapoint));
-        }
-        snapshots.insert(goal, by_day);
+    fn goals(&self) -> Vec<String> {
+        self.mappings
+            .iter()
+            .map(|mapping| mapping.beeminder_goal.trim().to_string())
+            .collect()
     }
-    let targets = plan(config, &days, &reconcile, &prefill, snapshots)?;
-
-    println!(
-        "{}",
-        format_run_summary(days.len(), days.last().unwrap().date, config.apply)
-    );
 
-    if !config.apply {
-        print!("{}", format_preview_table(&targets));
-        println!("  preview complete; set daylio.apply = true to apply all listed mutations");
-        return Ok(());
+    fn sync<'a>(&'a self, ctx: &'a SyncContext<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(daylio_sync(self, ctx))
     }
 
-    print!("{}", format_apply_plan(&targets));
-    let mutations: Vec<&Target> = targets
-        .iter()
-        .filter(|target| !target_is_unchanged(target))
-        .collect();
-    if mutations.is_empty() {
-        return Ok(());
-    }
-    for target in &mutations {
-        apply_target(client, target)
-            .await
-            .with_context(|| format!("applying {} {}", target.goal, target.date))?;
+    fn check(&self) -> BoxFuture<'_, Vec<Check>> {
+        Box::pin(async move {
+            let result = self
+                .resolve_source()
+                .and_then(|source| self.validate(&source));
+            vec![Check::new("source is a readable export", result)]
+        })
     }
-    println!(
-        "  ✅ applied and verified {}",
-        pluralized(mutations.len(), "change", "changes")
-    );
-    Ok(())
 }
 
 #[cfg(test)]
@@ -727,7 +474,7 @@ mod tests {
                 prefill_value: 1.0,
             }],
         };
-        let point = ExistingPoint {
+        let point = reconcile::ExistingPoint {
             id: "existing".into(),
             value: Some(0.0),
             comment: None,
@@ -739,130 +486,16 @@ mod tests {
             HashMap::from([(daystamp(target_date), vec![point])]),
         )]);
 
-        let targets = plan(&config, &days, &[target_date], &[], existing).unwrap();
+        let desired = desired(&config, &days, &[target_date], &[]);
+        let targets = reconcile::plan(desired, existing).unwrap();
 
         assert_eq!(targets[0].goal, "goal");
+        assert_eq!(targets[0].value, 0.0);
+        assert_eq!(targets[0].requestid, "beesync-daylio-v1:20260804");
         assert_eq!(targets[0].existing.len(), 1);
         assert_eq!(targets[0].existing[0].id, "existing");
     }
 
-    #[test]
-    fn preview_table_aligns_columns_and_separates_header() {
-        let targets = vec![
-            Target {
-                goal: "free".into(),
-                date: date("2026-08-07"),
-                value: 1.0,
-                comment: "beesync/daylio: non-user present".into(),
-                requestid: "canonical".into(),
-                existing: vec![ExistingPoint {
-                    id: "existing".into(),
-                    value: Some(1.0),
-                    comment: Some("beesync/daylio: non-user present".into()),
-                    requestid: Some("canonical".into()),
-                    system: false,
-                }],
-            },
-            Target {
-                goal: "free".into(),
-                date: date("2026-08-10"),
-                value: 1.0,
-                comment: "beesync/daylio: non-user present".into(),
-                requestid: "new".into(),
-                existing: vec![],
-            },
-        ];
-
-        assert_eq!(
-            format_preview_table(&targets),
-            concat!(
-                "  goal | date       | target | existing | action\n",
-                "  ---- | ---------- | ------ | -------- | --------\n",
-                "  free | 2026-08-07 |      1 |        1 | ✅ keep\n",
-                "  free | 2026-08-10 |      1 |        - | ➕ create\n"
-            )
-        );
-    }
-
-    #[test]
-    fn preview_table_shows_existing_values_instead_of_point_counts() {
-        let target = Target {
-            goal: "clean-twitch".into(),
-            date: date("2026-08-08"),
-            value: 0.0,
-            comment: "beesync/daylio: non-user absent (authoritative)".into(),
-            requestid: "canonical".into(),
-            existing: vec![ExistingPoint {
-                id: "existing".into(),
-                value: Some(0.0),
-                comment: None,
-                requestid: None,
-                system: false,
-            }],
-        };
-
-        let table = format_preview_table(&[target]);
-
-        assert!(table.contains("clean-twitch | 2026-08-08 |      0 |        0 | ♻️ replace"));
-    }
-
-    #[test]
-    fn apply_plan_hides_unchanged_targets() {
-        let targets = vec![
-            Target {
-                goal: "free".into(),
-                date: date("2026-08-07"),
-                value: 1.0,
-                comment: "beesync/daylio: non-user present".into(),
-                requestid: "canonical".into(),
-                existing: vec![ExistingPoint {
-                    id: "existing".into(),
-                    value: Some(1.0),
-                    comment: Some("beesync/daylio: non-user present".into()),
-                    requestid: Some("canonical".into()),
-                    system: false,
-                }],
-            },
-            Target {
-                goal: "free".into(),
-                date: date("2026-08-10"),
-                value: 1.0,
-                comment: "beesync/daylio: non-user present".into(),
-                requestid: "new".into(),
-                existing: vec![],
-            },
-        ];
-
-        let output = format_apply_plan(&targets);
-
-        assert!(!output.contains("2026-08-07"));
-        assert!(output.contains("2026-08-10"));
-        assert!(output.contains("1 change; 1 unchanged target hidden"));
-    }
-
-    #[test]
-    fn apply_plan_collapses_when_everything_is_unchanged() {
-        let targets = vec![Target {
-            goal: "free".into(),
-            date: date("2026-08-07"),
-            value: 1.0,
-            comment: "beesync/daylio: non-user present".into(),
-            requestid: "canonical".into(),
-            existing: vec![ExistingPoint {
-                id: "existing".into(),
-                value: Some(1.0),
-                comment: Some("beesync/daylio: non-user present".into()),
-                requestid: Some("canonical".into()),
-                system: false,
-            }],
-        }];
-
-        assert_eq!(
-            format_apply_plan(&targets),
-            "  ✅ already in sync — 1 datapoint checked across 1 goal\n"
-        );
-    }
-
     #[test]
     fn run_summary_combines_mode_and_source_with_a_mode_emoji() {
         assert_eq!(
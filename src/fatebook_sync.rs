@@ -1,62 +1,113 @@
+use crate::check::Check;
 use crate::key::Key;
+use crate::module::{report, BoxFuture, ClientCache, SyncContext, SyncModule};
+use crate::report::Change;
+use crate::timezone::GoalDays;
 use anyhow::Result;
-use beeminder::{types::CreateDatapoint, BeeminderClient};
+use beeminder::types::CreateDatapoint;
 use fatebook::FatebookClient;
 use serde::Deserialize;
-use std::collections::HashSet;
+use std::sync::Arc;
+use tracing::debug;
 
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct FatebookConfig {
     pub key: Key,
+    #[serde(default = "default_goal_name")]
+    pub goal_name: String,
+    #[serde(skip)]
+    client: ClientCache<FatebookClient>,
 }
 
-fn question_to_datapoint(question: &fatebook::Question) -> CreateDatapoint {
-    let daystamp = format!(
-        "{:04}{:02}{:02}",
-        question.created_at.year(),
-        question.created_at.month() as u8,
-        question.created_at.day()
-    );
+fn default_goal_name() -> String {
+    "fatebook".to_string()
+}
 
+fn question_to_datapoint(question: &fatebook::Question, days: &GoalDays<'_>) -> CreateDatapoint {
     CreateDatapoint {
         value: 1.0,
         timestamp: Some(question.created_at),
-        daystamp: Some(daystamp),
+        daystamp: Some(days.daystamp(question.created_at)),
         comment: Some(question.title.to_string()),
         requestid: Some(question.id.clone()),
     }
 }
 
-pub async fn fatebook_sync(config: &FatebookConfig, beeminder: &BeeminderClient) -> Result<()> {
-    println!("📚 fatebook-sync");
-    let goal = "fatebook";
+impl FatebookConfig {
+    async fn client(&self) -> Result<Arc<FatebookClient>> {
+        let key = self.key.resolve().await?;
+        self.client.get_or_try_init(key, |key| {
+            Ok(FatebookClient::new(key.expose().to_owned(), None))
+        })
+    }
+}
+
+async fn fatebook_sync(config: &FatebookConfig, ctx: &SyncContext<'_>) -> Result<()> {
+    let goal = config.goal_name.as_str();
 
-    let key = config.key.get_value()?;
-    let fatebook = FatebookClient::new(key, None);
+    let fatebook = config.client().await?;
 
-    let questions = fatebook.get_questions(None).await?;
-    let existing_dps = beeminder
-        .get_datapoints(goal, Some("timestamp"), Some(questions.len() as u64), None, None)
+    let questions = ctx
+        .retry("fetching Fatebook questions", || {
+            fatebook.get_questions(None)
+        })
         .await?;
+    let existing_ids = ctx.synced_requestids(goal).await?;
 
-    let existing_ids: HashSet<_> = existing_dps
-        .iter()
-        .filter_map(|dp| dp.requestid.as_ref())
-        .collect();
-
+    let question_count = questions.len();
+    ctx.seen(question_count);
     let new_questions: Vec<_> = questions
         .into_iter()
         .filter(|q| !existing_ids.contains(&q.id))
         .collect();
+    debug!(
+        questions = question_count,
+        new = new_questions.len(),
+        "fetched Fatebook questions"
+    );
+    ctx.count(Change::Skipped, question_count - new_questions.len());
 
+    let days = ctx.goal_days(goal).await?;
     for question in new_questions.into_iter().rev() {
-        let dp = question_to_datapoint(&question);
-        beeminder.create_datapoint(goal, &dp).await?;
+        let dp = question_to_datapoint(&question, &days);
+        ctx.create_datapoint(goal, &dp).await?;
 
         if let Some(comment) = dp.comment.as_ref() {
-            println!("  🆕 Created Fatebook datapoint: {comment}");
+            report!(ctx, "  🆕 {} Fatebook datapoint: {comment}", ctx.created());
         }
     }
 
     Ok(())
 }
+
+impl SyncModule for FatebookConfig {
+    const NAME: &'static str = "fatebook";
+    const EMOJI: &'static str = "📚";
+
+    fn goals(&self) -> Vec<String> {
+        vec![self.goal_name.clone()]
+    }
+
+    fn sync<'a>(&'a self, ctx: &'a SyncContext<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(fatebook_sync(self, ctx))
+    }
+
+    fn check(&self) -> BoxFuture<'_, Vec<Check>> {
+        Box::pin(async move {
+            let key = Check::key("key", &self.key).await;
+            if key.result.is_err() {
+                return vec![key];
+            }
+            let result = match self.client().await {
+                Ok(fatebook) => fatebook
+                    .get_questions(None)
+                    .await
+                    .map(drop)
+                    .map_err(anyhow::Error::from),
+                Err(e) => Err(e),
+            };
+            vec![key, Check::new("Fatebook accepts the key", result)]
+        })
+    }
+}
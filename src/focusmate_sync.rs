@@ -1,16 +1,26 @@
+use crate::check::Check;
 use crate::key::Key;
+use crate::module::{report, BoxFuture, ClientCache, SyncContext, SyncModule};
+use crate::timezone::{GoalDays, TimeZone};
 use anyhow::{anyhow, Result};
-use beeminder::{types::CreateDatapoint, BeeminderClient};
+use beeminder::types::CreateDatapoint;
 use focusmate::{FocusmateClient, Session};
 use serde::Deserialize;
-use std::collections::HashSet;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::Arc,
+};
 use time::{Duration, OffsetDateTime};
+use tracing::debug;
 
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct FocusmateConfig {
     pub key: Key,
     pub goal_name: String,
     pub auto_tags: Vec<String>,
+    #[serde(skip)]
+    client: ClientCache<FocusmateClient>,
 }
 
 fn find_matching_tags(tags: &[String], comment: &str) -> Vec<String> {
@@ -32,19 +42,15 @@ fn get_session_title(session: &Session) -> Result<String> {
 async fn session_to_datapoint(
     focusmate: &FocusmateClient,
     session: &Session,
+    timezone: &TimeZone,
+    days: &GoalDays<'_>,
 ) -> Result<CreateDatapoint> {
-    let daystamp = format!(
-        "{:04}{:02}{:02}",
-        session.start_time.year(),
-        session.start_time.month() as u8,
-        session.start_time.day()
-    );
-
+    let start = timezone.local(session.start_time);
     let formatted_time = format!(
-        "{}, {:02}:{:02} (UTC)",
-        session.start_time.weekday(),
-        session.start_time.hour(),
-        session.start_time.minute()
+        "{}, {:02}:{:02} ({timezone})",
+        start.weekday(),
+        start.hour(),
+        start.minute()
     );
 
     let session_title = get_session_title(session)?;
@@ -62,7 +68,7 @@ async fn session_to_datapoint(
     let dp = CreateDatapoint {
         value: 1.0,
         timestamp: Some(session.start_time),
-        daystamp: Some(daystamp),
+        daystamp: Some(days.daystamp(session.start_time)),
         comment: Some(comment),
         requestid: None,
     };
@@ -70,50 +76,150 @@ async fn session_to_datapoint(
     Ok(dp)
 }
 
-pub async fn focusmate_sync(config: &FocusmateConfig, beeminder: &BeeminderClient) -> Result<()> {
-    println!("🤝 focusmate-sync");
-    let key = config.key.get_value()?;
-    let focusmate = FocusmateClient::new(key);
+impl FocusmateConfig {
+    async fn client(&self) -> Result<Arc<FocusmateClient>> {
+        let key = self.key.resolve().await?;
+        self.client
+            .get_or_try_init(key, |key| Ok(FocusmateClient::new(key.expose().to_owned())))
+    }
+}
+
+async fn focusmate_sync(config: &FocusmateConfig, ctx: &SyncContext<'_>) -> Result<()> {
+    let beeminder = ctx.beeminder;
+    let focusmate = config.client().await?;
 
     let goal = &config.goal_name;
-    let most_recent_focusmate_dp = beeminder
-        .get_datapoints(goal, Some("timestamp"), Some(1), None, None)
-        .await?;
-    let start = match most_recent_focusmate_dp.first() {
-        Some(dp) if dp.value != 0.0 => dp.timestamp,
-        _ => OffsetDateTime::UNIX_EPOCH,
+    let state = ctx.state(goal);
+    let synced = match state.items()? {
+        Some(synced) => synced,
+        None => {
+            // Sessions are keyed by start time, which is each datapoint's
+            // timestamp; the newest one is where syncing resumes.
+            let datapoints = ctx
+                .retry("fetching Beeminder datapoints", || {
+                    beeminder.get_datapoints(goal, Some("timestamp"), None, None, None)
+                })
+                .await?;
+            let cursor = match datapoints.first() {
+                Some(dp) if dp.value != 0.0 => Some(session_key(dp.timestamp)),
+                _ => None,
+            };
+            let items: Vec<_> = datapoints
+                .into_iter()
+                .map(|dp| (session_key(dp.timestamp), Some(dp.id)))
+                .collect();
+            let synced = items.iter().map(|(key, _)| key.clone()).collect();
+            debug!(sessions = items.len(), "rebuilt sync state from Beeminder");
+            state.rebuild(items, cursor.as_deref())?;
+            synced
+        }
+    };
+    let start = match state.cursor()? {
+        Some(cursor) => OffsetDateTime::from_unix_timestamp(cursor.parse()?)?,
+        None => OffsetDateTime::UNIX_EPOCH,
     };
     let end = OffsetDateTime::now_utc() + Duration::days(1);
-    let fm_sessions = focusmate.get_sessions(&start, &end).await?;
-
-    // Get enough datapoints to check for duplicates
-    let existing_dps = beeminder
-        .get_datapoints(goal, Some("timestamp"), Some(fm_sessions.len() as u64), None, None)
+    let fm_sessions = ctx
+        .retry("fetching Focusmate sessions", || {
+            focusmate.get_sessions(&start, &end)
+        })
         .await?;
 
-    let existing_timestamps: HashSet<_> = existing_dps.iter().map(|dp| dp.timestamp).collect();
-
-    let new_sessions: Vec<_> = fm_sessions
+    let completed: Vec<_> = fm_sessions
         .into_iter()
         .filter(focusmate::Session::completed)
-        .filter(|session| !existing_timestamps.contains(&session.start_time))
+        .collect();
+    ctx.seen(completed.len());
+    let newest = completed.iter().map(|session| session.start_time).max();
+    let new_sessions: Vec<_> = completed
+        .into_iter()
+        .filter(|session| !synced.contains(&session_key(session.start_time)))
         .rev()
         .collect();
+    debug!(%start, new = new_sessions.len(), "fetched completed sessions");
+
+    // Each tag's goal may have a different deadline, looked up the first
+    // time a session carries the tag.
+    let days = ctx.goal_days(goal).await?;
+    let mut tag_days = HashMap::new();
 
     for session in new_sessions {
-        let dp = session_to_datapoint(&focusmate, &session).await?;
-        beeminder.create_datapoint(goal, &dp).await?;
+        let dp = session_to_datapoint(&focusmate, &session, ctx.timezone, &days).await?;
+        let created = ctx.create_datapoint(goal, &dp).await?;
+        state.record(
+            &session_key(session.start_time),
+            created.as_ref().map(|created| created.id.as_str()),
+        )?;
         assert!(dp.comment.is_some());
         if let Some(comment) = dp.comment.as_ref() {
-            println!("  🆕 Created Focusmate datapoint: {comment}");
+            report!(ctx, "  🆕 {} Focusmate datapoint: {comment}", ctx.created());
 
             let matching_tags = find_matching_tags(&config.auto_tags, comment);
             for tag in matching_tags {
-                beeminder.create_datapoint(&tag, &dp).await?;
-                println!("    📌 Created additional datapoint for goal: {tag}");
+                let tag_days = match tag_days.entry(tag.clone()) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(ctx.goal_days(&tag).await?),
+                };
+                let tagged = CreateDatapoint {
+                    value: dp.value,
+                    timestamp: dp.timestamp,
+                    daystamp: Some(tag_days.daystamp(session.start_time)),
+                    comment: dp.comment.clone(),
+                    requestid: dp.requestid.clone(),
+                };
+                ctx.create_datapoint(&tag, &tagged).await?;
+                report!(
+                    ctx,
+                    "    📌 {} additional datapoint for goal: {tag}",
+                    ctx.created()
+                );
             }
         }
     }
 
+    if let Some(newest) = newest {
+        state.set_cursor(&session_key(newest))?;
+    }
+
     Ok(())
 }
+
+/// State store key for the session starting at `start_time`.
+fn session_key(start_time: OffsetDateTime) -> String {
+    start_time.unix_timestamp().to_string()
+}
+
+impl SyncModule for FocusmateConfig {
+    const NAME: &'static str = "focusmate";
+    const EMOJI: &'static str = "🤝";
+
+    fn goals(&self) -> Vec<String> {
+        std::iter::once(&self.goal_name)
+            .chain(&self.auto_tags)
+            .cloned()
+            .collect()
+    }
+
+    fn sync<'a>(&'a self, ctx: &'a SyncContext<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(focusmate_sync(self, ctx))
+    }
+
+    fn check(&self) -> BoxFuture<'_, Vec<Check>> {
+        Box::pin(async move {
+            let key = Check::key("key", &self.key).await;
+            if key.result.is_err() {
+                return vec![key];
+            }
+            let end = OffsetDateTime::now_utc();
+            let result = match self.client().await {
+                Ok(focusmate) => focusmate
+                    .get_sessions(&(end - Duration::days(1)), &end)
+                    .await
+                    .map(drop)
+                    .map_err(anyhow::Error::from),
+                Err(e) => Err(e),
+            };
+            vec![key, Check::new("Focusmate accepts the key", result)]
+        })
+    }
+}
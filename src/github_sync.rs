@@ -1,80 +1,116 @@
+use crate::check::Check;
 use crate::key::Key;
+use crate::module::{report, BoxFuture, ClientCache, SyncContext, SyncModule};
+use crate::report::Change;
+use crate::timezone::GoalDays;
 use anyhow::Result;
-use beeminder::{types::CreateDatapoint, BeeminderClient};
+use beeminder::types::CreateDatapoint;
 use github_light::{Commit, GitHubClient};
 use serde::Deserialize;
-use std::collections::HashSet;
+use std::sync::Arc;
 use time::{Duration, OffsetDateTime};
+use tracing::debug;
+
+/// How far before the newest synced commit to list commits again, in case
+/// GitHub lists some late.
+const LOOKBACK: Duration = Duration::days(2);
 
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct GitHubConfig {
     pub key: Option<Key>,
     pub goal_name: String,
     pub username: String,
+    #[serde(skip)]
+    client: ClientCache<GitHubClient>,
 }
 
-fn commit_to_datapoint(commit: &Commit) -> CreateDatapoint {
-    let daystamp = format!(
-        "{:04}{:02}{:02}",
-        commit.committer_date.year(),
-        commit.committer_date.month() as u8,
-        commit.committer_date.day()
-    );
-
+fn commit_to_datapoint(commit: &Commit, days: &GoalDays<'_>) -> CreateDatapoint {
     let first_line = commit.message.lines().next().unwrap_or("").trim();
     let comment = format!("{}: {}", commit.repository, first_line);
 
     CreateDatapoint {
         value: 1.0,
         timestamp: Some(commit.committer_date),
-        daystamp: Some(daystamp),
+        daystamp: Some(days.daystamp(commit.committer_date)),
         comment: Some(comment),
         requestid: Some(commit.sha.clone()),
     }
 }
 
-pub async fn github_sync(config: &GitHubConfig, beeminder: &BeeminderClient) -> Result<()> {
-    println!("🐙 github-sync");
+impl GitHubConfig {
+    async fn client(&self) -> Result<Arc<GitHubClient>> {
+        let token = match &self.key {
+            Some(key) => Some(key.resolve().await?),
+            None => None,
+        };
+        self.client
+            .get_or_try_init(token, |token| Ok(GitHubClient::new(token.map(Into::into))))
+    }
+}
 
-    let token = match &config.key {
-        Some(key) => Some(key.get_value()?),
-        None => None,
-    };
-    let github = GitHubClient::new(token);
+async fn github_sync(config: &GitHubConfig, ctx: &SyncContext<'_>) -> Result<()> {
+    let github = config.client().await?;
 
     let goal = &config.goal_name;
-    let most_recent_github_dp = beeminder
-        .get_datapoints(goal, Some("timestamp"), Some(1), None, None)
-        .await?;
-
-    let start = match most_recent_github_dp.first() {
-        Some(dp) if dp.value != 0.0 => dp.timestamp - Duration::days(2),
-        _ => OffsetDateTime::UNIX_EPOCH,
+    let existing_shas = ctx.synced_requestids(goal).await?;
+    let state = ctx.state(goal);
+    let start = match state.cursor()? {
+        Some(cursor) => OffsetDateTime::from_unix_timestamp(cursor.parse()?)? - LOOKBACK,
+        None => start_without_cursor(ctx, goal).await?,
     };
 
-    let commits = github.get_commits(&config.username, &start).await?;
-    let existing_shas = existing_requestids(beeminder, goal, start).await?;
+    debug!(%start, "fetching commits");
+    let repositories = ctx
+        .retry("listing GitHub repositories", || {
+            github.get_user_repositories(&config.username)
+        })
+        .await?;
+    let mut commits = Vec::new();
+    for repo in &repositories {
+        // Retried per repository, so one failure does not refetch the rest.
+        commits.extend(
+            ctx.retry("fetching GitHub commits", || {
+                github.get_repository_commits(repo, &config.username, &start)
+            })
+            .await?,
+        );
+    }
+    let newest_commit = commits.iter().map(|commit| commit.committer_date).max();
 
+    let commit_count = commits.len();
+    ctx.seen(commit_count);
     let new_commits: Vec<_> = commits
         .into_iter()
         .filter(|commit| !existing_shas.contains(&commit.sha))
         .rev()
         .collect();
+    debug!(
+        commits = commit_count,
+        new = new_commits.len(),
+        "deduplicated commits"
+    );
+    ctx.count(Change::Skipped, commit_count - new_commits.len());
 
+    let days = ctx.goal_days(goal).await?;
     let mut failures = 0;
 
     for commit in new_commits {
-        let dp = commit_to_datapoint(&commit);
+        let dp = commit_to_datapoint(&commit, &days);
         let comment = dp.comment.clone().unwrap_or_else(|| commit.sha.clone());
 
-        match beeminder.create_datapoint(goal, &dp).await {
-            Ok(_) => println!("  🆕 Created GitHub datapoint: {comment}"),
+        match ctx.create_datapoint(goal, &dp).await {
+            Ok(_) => report!(ctx, "  🆕 {} GitHub datapoint: {comment}", ctx.created()),
             // Beeminder rejects a repeat POST of an unchanged requestid, so an
             // already-present datapoint means this commit is synced.
-            Err(e) if is_duplicate_request(&e) => println!("  ⏭️  Already synced: {comment}"),
+            Err(e) if e.downcast_ref().is_some_and(is_duplicate_request) => {
+                state.record(&commit.sha, None)?;
+                ctx.count(Change::Skipped, 1);
+                report!(ctx, "  ⏭️  Already synced: {comment}");
+            }
             Err(e) => {
                 failures += 1;
-                eprintln!("  ⚠️  Failed to sync {comment}: {e}");
+                report!(ctx, "  ⚠️  Failed to sync {comment}: {e}");
             }
         }
     }
@@ -82,44 +118,66 @@ pub async fn github_sync(config: &GitHubConfig, beeminder: &BeeminderClient) ->
     if failures > 0 {
         anyhow::bail!("{failures} commit(s) could not be synced");
     }
+    if let Some(newest) = newest_commit {
+        state.set_cursor(&newest.unix_timestamp().to_string())?;
+    }
 
     Ok(())
 }
 
-/// Collects the request IDs (commit SHAs) of datapoints already on the goal,
-/// reaching back at least as far as `start`.
-///
-/// The window is driven by `start` rather than by the number of commits found:
-/// a datapoint whose commit no longer appears in GitHub's listing (rebased or
-/// amended away) would otherwise push real commits out of view and make them
-/// look unsynced.
-async fn existing_requestids(
-    beeminder: &BeeminderClient,
-    goal: &str,
-    start: OffsetDateTime,
-) -> Result<HashSet<String>> {
-    let mut count = 100;
-
-    loop {
-        // Sorted by timestamp descending, so the last entry is the oldest.
-        let datapoints = beeminder
-            .get_datapoints(goal, Some("timestamp"), Some(count), None, None)
-            .await?;
-
-        let exhausted = (datapoints.len() as u64) < count;
-        let covers_window = datapoints.last().is_some_and(|dp| dp.timestamp < start);
-
-        if exhausted || covers_window {
-            return Ok(datapoints
-                .into_iter()
-                .filter_map(|dp| dp.requestid)
-                .collect());
-        }
+impl SyncModule for GitHubConfig {
+    const NAME: &'static str = "github";
+    const EMOJI: &'static str = "🐙";
+
+    fn goals(&self) -> Vec<String> {
+        vec![self.goal_name.clone()]
+    }
+
+    fn sync<'a>(&'a self, ctx: &'a SyncContext<'a>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(github_sync(self, ctx))
+    }
 
-        count *= 2;
+    fn check(&self) -> BoxFuture<'_, Vec<Check>> {
+        Box::pin(async move {
+            let mut checks = Vec::new();
+            if let Some(key) = &self.key {
+                checks.push(Check::key("key", key).await);
+                if checks[0].result.is_err() {
+                    return checks;
+                }
+            }
+            let result = match self.client().await {
+                Ok(github) => github
+                    .get_user_repositories(&self.username)
+                    .await
+                    .map(drop)
+                    .map_err(anyhow::Error::from),
+                Err(e) => Err(e),
+            };
+            checks.push(Check::new(
+                format!("GitHub user '{}' is reachable", self.username),
+                result,
+            ));
+            checks
+        })
     }
 }
 
+/// Where listing commits starts when the state store has no cursor yet: a
+/// little before the goal's newest datapoint.
+async fn start_without_cursor(ctx: &SyncContext<'_>, goal: &str) -> Result<OffsetDateTime> {
+    let newest = ctx
+        .retry("fetching Beeminder datapoints", || {
+            ctx.beeminder
+                .get_datapoints(goal, Some("timestamp"), Some(1), None, None)
+        })
+        .await?;
+    Ok(match newest.first() {
+        Some(dp) if dp.value != 0.0 => dp.timestamp - LOOKBACK,
+        _ => OffsetDateTime::UNIX_EPOCH,
+    })
+}
+
 fn is_duplicate_request(error: &beeminder::Error) -> bool {
     matches!(
         error,
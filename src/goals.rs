@@ -0,0 +1,424 @@
+use crate::backend::Beeminder;
+use crate::module::Module;
+use crate::secret::redact;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+/// A goal from a `[[goals]]` table, which `beesync goals sync` creates on
+/// Beeminder when it is missing.
+///
+/// Only the settings given here are compared with the goal's; Beeminder
+/// picks the rest when creating it.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GoalConfig {
+    pub slug: String,
+    /// The slug unless set.
+    pub title: Option<String>,
+    pub goal_type: GoalType,
+    pub rate: Rate,
+    /// What a datapoint counts, such as "hours"; "units" unless set.
+    pub units: Option<String>,
+    /// How the datapoints of a day are combined, such as "sum" or "last".
+    pub aggday: Option<Aggday>,
+}
+
+/// The kinds of goal that can be provisioned, by the names Beeminder's
+/// goal creation page uses.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GoalType {
+    DoMore,
+    DoLess,
+    Odometer,
+    WhittleDown,
+}
+
+const GOAL_TYPES: [GoalType; 4] = [
+    GoalType::DoMore,
+    GoalType::DoLess,
+    GoalType::Odometer,
+    GoalType::WhittleDown,
+];
+
+impl GoalType {
+    /// The name of the type in the Beeminder API.
+    pub fn api_name(self) -> &'static str {
+        match self {
+            Self::DoMore => "hustler",
+            Self::DoLess => "drinker",
+            Self::Odometer => "biker",
+            Self::WhittleDown => "inboxer",
+        }
+    }
+}
+
+impl fmt::Display for GoalType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::DoMore => "do-more",
+            Self::DoLess => "do-less",
+            Self::Odometer => "odometer",
+            Self::WhittleDown => "whittle-down",
+        })
+    }
+}
+
+/// A goal's rate, written like `"3/week"`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Rate {
+    pub value: f64,
+    /// Beeminder's rate unit: `y`, `m`, `w`, `d` or `h`.
+    pub unit: char,
+}
+
+const RATE_UNITS: [(char, &str); 5] = [
+    ('y', "year"),
+    ('m', "month"),
+    ('w', "week"),
+    ('d', "day"),
+    ('h', "hour"),
+];
+
+impl TryFrom<String> for Rate {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> Result<Self> {
+        let invalid = || anyhow::anyhow!("invalid rate '{text}'; use e.g. \"3/week\"");
+        let (value, per) = text.split_once('/').ok_or_else(invalid)?;
+        let value: f64 = value.trim().parse().map_err(|_| invalid())?;
+        let per = per.trim();
+        let unit = RATE_UNITS
+            .iter()
+            .find(|(unit, name)| per == *name || (per.len() == 1 && per.starts_with(*unit)))
+            .map(|(unit, _)| *unit)
+            .ok_or_else(|| {
+                anyhow::anyhow!("invalid rate '{text}'; use day, week, month, year or hour")
+            })?;
+        if !value.is_finite() {
+            return Err(invalid());
+        }
+        Ok(Self { value, unit })
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = RATE_UNITS
+            .iter()
+            .find(|(unit, _)| *unit == self.unit)
+            .map_or("?", |(_, name)| name);
+        write!(f, "{}/{name}", self.value)
+    }
+}
+
+/// One of Beeminder's aggregation functions.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Aggday(String);
+
+const AGGDAYS: [&str; 20] = [
+    "last", "first", "min", "max", "truemean", "uniqmean", "mean", "median", "mode", "trimmean",
+    "sum", "jolly", "binary", "nonzero", "triangle", "square", "clocky", "count", "skatesum",
+    "cap1",
+];
+
+impl TryFrom<String> for Aggday {
+    type Error = anyhow::Error;
+
+    fn try_from(name: String) -> Result<Self> {
+        if !AGGDAYS.contains(&name.as_str()) {
+            bail!(
+                "unknown aggday '{name}'; Beeminder has {}",
+                AGGDAYS.join(", ")
+            );
+        }
+        Ok(Self(name))
+    }
+}
+
+impl Aggday {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The settings of an existing goal, as Beeminder names them.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct GoalSettings {
+    pub title: String,
+    pub goal_type: String,
+    pub gunits: String,
+    pub aggday: Option<String>,
+    pub rate: Option<f64>,
+    pub runits: String,
+}
+
+impl GoalConfig {
+    pub fn title(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.slug)
+    }
+
+    pub fn units(&self) -> &str {
+        self.units.as_deref().unwrap_or("units")
+    }
+
+    /// The settings a goal gets from this definition.
+    pub fn settings(&self) -> GoalSettings {
+        GoalSettings {
+            title: self.title().to_string(),
+            goal_type: self.goal_type.api_name().to_string(),
+            gunits: self.units().to_string(),
+            aggday: self
+                .aggday
+                .as_ref()
+                .map(|aggday| aggday.as_str().to_string()),
+            rate: Some(self.rate.value),
+            runits: self.rate.unit.to_string(),
+        }
+    }
+
+    /// How `actual` differs from this definition, one sentence per setting.
+    pub fn drift(&self, actual: &GoalSettings) -> Vec<String> {
+        let mut drift = Vec::new();
+        if let Some(title) = &self.title {
+            if actual.title != *title {
+                drift.push(format!("title is '{}', not '{title}'", actual.title));
+            }
+        }
+        if actual.goal_type != self.goal_type.api_name() {
+            let name = GOAL_TYPES
+                .iter()
+                .find(|kind| kind.api_name() == actual.goal_type)
+                .map_or_else(|| actual.goal_type.clone(), ToString::to_string);
+            drift.push(format!("type is {name}, not {}", self.goal_type));
+        }
+        let rate = actual.rate.and_then(|value| {
+            let unit = actual.runits.chars().next()?;
+            Some(Rate { value, unit })
+        });
+        let same_rate = rate.is_some_and(|rate| {
+            rate.unit == self.rate.unit && (rate.value - self.rate.value).abs() < 1e-9
+        });
+        if !same_rate {
+            let actual = rate.map_or_else(|| "unset".to_string(), |rate| rate.to_string());
+            drift.push(format!("rate is {actual}, not {}", self.rate));
+        }
+        if let Some(units) = &self.units {
+            if actual.gunits != *units {
+                drift.push(format!("units are '{}', not '{units}'", actual.gunits));
+            }
+        }
+        if let Some(aggday) = &self.aggday {
+            if actual.aggday.as_deref() != Some(aggday.as_str()) {
+                let actual = actual.aggday.as_deref().unwrap_or("unset");
+                drift.push(format!("aggday is {actual}, not {}", aggday.as_str()));
+            }
+        }
+        drift
+    }
+}
+
+/// What `beesync goals sync` found for a goal.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Matches,
+    Created,
+    WouldCreate,
+    Differs(Vec<String>),
+    /// A goal some module writes to is neither on Beeminder nor defined.
+    Undefined(Vec<String>),
+}
+
+/// Creates the defined goals that are missing and compares the others with
+/// their definition, then looks for goals modules write to that are neither
+/// defined nor on Beeminder. Nothing is changed with `dry_run`.
+///
+/// Existing goals are never changed: their settings may have been tuned on
+/// Beeminder since, and some cannot be undone there.
+pub async fn sync(
+    beeminder: &Beeminder,
+    goals: &[GoalConfig],
+    modules: &[Arc<dyn Module>],
+    dry_run: bool,
+) -> Result<Vec<(String, Result<Outcome>)>> {
+    for (index, goal) in goals.iter().enumerate() {
+        if goals[..index].iter().any(|other| other.slug == goal.slug) {
+            bail!(
+                "goal '{}' is defined more than once in [[goals]]",
+                goal.slug
+            );
+        }
+    }
+
+    let mut rows = Vec::new();
+    for goal in goals {
+        let outcome = match beeminder.goal(&goal.slug).await {
+            Ok(Some(actual)) => match goal.drift(&actual) {
+                drift if drift.is_empty() => Ok(Outcome::Matches),
+                drift => Ok(Outcome::Differs(drift)),
+            },
+            Ok(None) if dry_run => Ok(Outcome::WouldCreate),
+            Ok(None) => beeminder.create_goal(goal).await.map(|()| Outcome::Created),
+            Err(e) => Err(e),
+        };
+        rows.push((goal.slug.clone(), outcome));
+    }
+
+    let mut users: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for module in modules {
+        for goal in module.goals() {
+            if !goals.iter().any(|defined| defined.slug == goal) {
+                users.entry(goal).or_default().push(module.label());
+            }
+        }
+    }
+    for (goal, labels) in users {
+        let outcome = match beeminder.goal(&goal).await {
+            Ok(Some(_)) => continue,
+            Ok(None) => Ok(Outcome::Undefined(labels)),
+            Err(e) => Err(e),
+        };
+        rows.push((goal, outcome));
+    }
+    Ok(rows)
+}
+
+/// Prints the rows of [`sync`] as a table and returns how many need
+/// attention: goals that differ, are missing, or could not be looked up.
+pub fn print_table(rows: &[(String, Result<Outcome>)]) -> usize {
+    let width = rows
+        .iter()
+        .map(|(goal, _)| goal.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!("{:<width$}  RESULT", "GOAL");
+    let mut problems = 0;
+    for (goal, outcome) in rows {
+        let result = match outcome {
+            Ok(Outcome::Matches) => "✅ matches the config".to_string(),
+            Ok(Outcome::Created) => "🆕 created".to_string(),
+            Ok(Outcome::WouldCreate) => "🆕 would be created".to_string(),
+            Ok(Outcome::Differs(drift)) => format!("⚠️ differs: {}", drift.join("; ")),
+            Ok(Outcome::Undefined(labels)) => format!(
+                "❌ missing, used by {} but not defined in [[goals]]",
+                labels.join(", ")
+            ),
+            Err(e) => format!("❌ {}", redact(&format!("{e:#}"))),
+        };
+        if !matches!(
+            outcome,
+            Ok(Outcome::Matches | Outcome::Created | Outcome::WouldCreate)
+        ) {
+            problems += 1;
+        }
+        println!("{goal:<width$}  {result}");
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn goal(toml: &str) -> GoalConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn rates_are_parsed() {
+        let parse = |text: &str| Rate::try_from(text.to_string());
+
+        assert_eq!(
+            parse("3/week").unwrap(),
+            Rate {
+                value: 3.0,
+                unit: 'w'
+            }
+        );
+        assert_eq!(
+            parse("0.5 / d").unwrap(),
+            Rate {
+                value: 0.5,
+                unit: 'd'
+            }
+        );
+        assert_eq!(parse("-2/month").unwrap().to_string(), "-2/month");
+        assert!(parse("3").is_err());
+        assert!(parse("3/fortnight").is_err());
+        assert!(parse("many/day").is_err());
+    }
+
+    #[test]
+    fn goal_definitions_are_validated() {
+        let defined = goal("slug = \"focus\"\ngoal_type = \"do-more\"\nrate = \"5/week\"");
+
+        assert_eq!(defined.title(), "focus");
+        assert_eq!(defined.settings().goal_type, "hustler");
+        assert_eq!(defined.settings().runits, "w");
+        assert!(toml::from_str::<GoalConfig>(
+            "slug = \"focus\"\ngoal_type = \"do-some\"\nrate = \"5/week\""
+        )
+        .is_err());
+        assert!(toml::from_str::<GoalConfig>(
+            "slug = \"focus\"\ngoal_type = \"do-more\"\nrate = \"5/week\"\naggday = \"total\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn only_configured_settings_drift() {
+        let defined =
+            goal("slug = \"focus\"\ngoal_type = \"do-more\"\nrate = \"5/week\"\naggday = \"sum\"");
+        let mut actual = defined.settings();
+        actual.title = "Focus sessions".to_string();
+        actual.gunits = "sessions".to_string();
+
+        assert!(defined.drift(&actual).is_empty());
+
+        actual.goal_type = "drinker".to_string();
+        actual.rate = Some(1.0);
+        actual.runits = "d".to_string();
+        actual.aggday = Some("last".to_string());
+
+        assert_eq!(
+            defined.drift(&actual),
+            [
+                "type is do-less, not do-more",
+                "rate is 1/day, not 5/week",
+                "aggday is last, not sum",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_goals_are_created_once() {
+        let dir = std::env::temp_dir().join(format!("beesync-goals-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let beeminder = Beeminder::Local(crate::local_backend::LocalBeeminder::open(&dir).unwrap());
+        let goals = [goal(
+            "slug = \"focus\"\ngoal_type = \"do-more\"\nrate = \"5/week\"",
+        )];
+
+        let preview = sync(&beeminder, &goals, &[], true).await.unwrap();
+        let first = sync(&beeminder, &goals, &[], false).await.unwrap();
+        let second = sync(&beeminder, &goals, &[], false).await.unwrap();
+        let changed = [goal(
+            "slug = \"focus\"\ngoal_type = \"do-more\"\nrate = \"1/day\"",
+        )];
+        let drifted = sync(&beeminder, &changed, &[], false).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(*preview[0].1.as_ref().unwrap(), Outcome::WouldCreate);
+        assert_eq!(*first[0].1.as_ref().unwrap(), Outcome::Created);
+        assert_eq!(*second[0].1.as_ref().unwrap(), Outcome::Matches);
+        assert_eq!(
+            *drifted[0].1.as_ref().unwrap(),
+            Outcome::Differs(vec!["rate is 5/week, not 1/day".to_string()])
+        );
+    }
+}
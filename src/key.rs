@@ -1,35 +1,379 @@
-use anyhow::{anyhow, Result};
+use crate::module::BoxFuture;
+use crate::secret::Secret;
+use anyhow::{anyhow, bail, Result};
 use serde::Deserialize;
-use std::process::Command;
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    future::Future,
+    io::ErrorKind,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{process::Command, sync::OnceCell};
 
-#[derive(Deserialize, Debug, Clone)]
+/// How long a key command may run before it is killed, so a stuck command
+/// cannot hang a run. Generous enough to type a passphrase.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Keys resolved outside of a run (see [`RunKeys`]), such as by `beesync
+/// check`, which resolves each once.
+static RESOLVED: Mutex<Resolved> = Mutex::new(BTreeMap::new());
+
+/// Each key's value, set by whichever module resolves it first while the
+/// others wait for it.
+type Resolved = BTreeMap<Key, Arc<OnceCell<Secret>>>;
+
+tokio::task_local! {
+    static RUN: RunKeys;
+}
+
+/// The keys resolved during one run, so each command runs and each file is
+/// read once per run, however many modules share the key. The next run
+/// resolves them afresh, so the daemon picks up a rotated token.
+#[derive(Clone, Default)]
+pub struct RunKeys(Arc<Mutex<Resolved>>);
+
+impl RunKeys {
+    /// Runs `future`, resolving keys with this run's cache.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        RUN.scope(self, future).await
+    }
+}
+
+/// Calls `f` with the cache of the current run, or the process's outside of
+/// one.
+fn with_cache<T>(f: impl FnOnce(&mut Resolved) -> T) -> T {
+    let run = RUN.try_with(|run| Arc::clone(&run.0)).ok();
+    let cache = match &run {
+        Some(run) => run.as_ref(),
+        None => &RESOLVED,
+    };
+    let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut cache)
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(untagged)]
 pub enum Key {
-    Env { env: String },
-    Cmd { cmd: String },
+    Env {
+        env: String,
+    },
+    Cmd {
+        cmd: String,
+    },
+    /// A file holding only the key; `~` is expanded.
+    File {
+        file: String,
+    },
+    /// A systemd credential (`LoadCredential=`), read from
+    /// `$CREDENTIALS_DIRECTORY`.
+    Credential {
+        credential: String,
+    },
+    /// An entry in an encrypted vault file; `~` is expanded. See
+    /// [`crate::vault`].
+    Vault {
+        vault: String,
+        entry: String,
+    },
 }
 
 impl Key {
-    pub fn get_value(&self) -> Result<String> {
-        match self {
-            Self::Env { env } => {
-                std::env::var(env).map_err(|_| anyhow!("Environment variable '{}' not found", env))
-            }
-            Self::Cmd { cmd } => {
-                let output = Command::new("sh")
-                    .arg("-c")
-                    .arg(cmd)
-                    .output()
-                    .map_err(|e| anyhow!("Failed to execute command '{}': {}", cmd, e))?;
-
-                if !output.status.success() {
-                    let stderr = String::from_utf8_lossy(&output.stderr);
-                    return Err(anyhow!("Command '{}' failed: {}", cmd, stderr));
-                }
-
-                let stdout = String::from_utf8_lossy(&output.stdout);
-                Ok(stdout.trim().to_string())
-            }
-        }
+    /// The key's value as a credential, scrubbed from output from now on.
+    /// Resolved on first use and cached for the rest of the run.
+    pub async fn resolve(&self) -> Result<Secret> {
+        let secret = self.cached().await?;
+        secret.scrub();
+        Ok(secret)
+    }
+
+    /// The value of a setting that is looked up like a key but is not a
+    /// credential, such as a URL or user name. It is left in output, so
+    /// errors that mention it stay readable.
+    pub async fn value(&self) -> Result<String> {
+        self.cached().await.map(|value| value.expose().to_string())
+    }
+
+    async fn cached(&self) -> Result<Secret> {
+        let cell = with_cache(|cache| Arc::clone(cache.entry(self.clone()).or_default()));
+        cell.get_or_try_init(|| self.read()).await.cloned()
+    }
+
+    // Boxed, as a vault's passphrase is itself a key.
+    fn read(&self) -> BoxFuture<'_, Result<Secret>> {
+        Box::pin(async move {
+            let value = match self {
+                Self::Env { env } => std::env::var(env)
+                    .map_err(|_| anyhow!("Environment variable '{}' not found", env))?,
+                Self::Cmd { cmd } => run_command(cmd, COMMAND_TIMEOUT).await?,
+                Self::File { file } => read_file(&crate::paths::expand_tilde(file)?)?,
+                Self::Credential { credential } => read_file(&credential_path(
+                    std::env::var_os("CREDENTIALS_DIRECTORY"),
+                    credential,
+                )?)?,
+                Self::Vault { vault, entry } => return crate::vault::entry(vault, entry).await,
+            };
+            Ok(Secret::new(value))
+        })
+    }
+}
+
+fn credential_path(directory: Option<OsString>, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains('/') {
+        bail!("Invalid credential name '{}'", name);
+    }
+    match directory {
+        Some(directory) if !directory.is_empty() => Ok(Path::new(&directory).join(name)),
+        _ => bail!(
+            "Cannot read credential '{}': CREDENTIALS_DIRECTORY is not set \
+             (credentials need systemd's LoadCredential=)",
+            name
+        ),
+    }
+}
+
+/// Reads a key from `path`, which only its owner may access.
+pub(crate) fn read_file(path: &Path) -> Result<String> {
+    let shown = path.display();
+    let metadata = fs::metadata(path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => anyhow!("Key file '{}' does not exist", shown),
+        ErrorKind::PermissionDenied => anyhow!("Key file '{}' is not accessible: {}", shown, e),
+        _ => anyhow!("Failed to read key file '{}': {}", shown, e),
+    })?;
+    if !metadata.is_file() {
+        bail!("Key file '{}' is not a regular file", shown);
+    }
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        bail!(
+            "Key file '{}' is accessible by group or others (mode {:04o}); \
+             restrict it with `chmod 600 {}`",
+            shown,
+            mode,
+            shown
+        );
+    }
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read key file '{}': {}", shown, e))?;
+    let value = contents.trim();
+    if value.is_empty() {
+        bail!("Key file '{}' is empty", shown);
+    }
+    Ok(value.to_string())
+}
+
+/// Runs `cmd`, killing it after `timeout`, or as soon as the resolving
+/// module is cancelled.
+async fn run_command(cmd: &str, timeout: Duration) -> Result<String> {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("Failed to execute command '{}': {}", cmd, e))?;
+
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "Command '{}' timed out after {}",
+                cmd,
+                humantime::format_duration(timeout)
+            )
+        })??;
+    if !output.status.success() {
+        // Its output may hold part of a secret, so it only goes to the
+        // debug log, and through redaction.
+        tracing::debug!(
+            stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+            "key command '{cmd}' failed"
+        );
+        return Err(anyhow!(
+            "Command '{}' failed ({}); run with -v to see its output",
+            cmd,
+            output.status
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn command_output_is_trimmed() {
+        let key = Key::Cmd {
+            cmd: "echo '  trimmed-command-output  '".to_string(),
+        };
+        assert_eq!(
+            key.resolve().await.unwrap().expose(),
+            "trimmed-command-output"
+        );
+    }
+
+    #[tokio::test]
+    async fn only_credentials_are_redacted() {
+        let setting = Key::Cmd {
+            cmd: "echo https://marvin.example/setting".to_string(),
+        };
+        let credential = Key::Cmd {
+            cmd: "echo credential-redacted".to_string(),
+        };
+        setting.value().await.unwrap();
+        credential.resolve().await.unwrap();
+
+        assert_eq!(
+            crate::secret::redact("https://marvin.example/setting credential-redacted"),
+            "https://marvin.example/setting [redacted]"
+        );
+    }
+
+    #[tokio::test]
+    async fn keys_are_resolved_once() {
+        let runs = std::env::temp_dir().join(format!("beesync-key-{}-runs", std::process::id()));
+        let key = Key::Cmd {
+            cmd: format!("echo run >> '{}'; echo token", runs.display()),
+        };
+
+        key.resolve().await.unwrap();
+        key.resolve().await.unwrap();
+        let count = fs::read_to_string(&runs).unwrap().lines().count();
+        fs::remove_file(&runs).unwrap();
+
+        assert_eq!(count, 1);
+    }
+
+    #[tokio::test]
+    async fn keys_are_resolved_again_on_the_next_run() {
+        let runs = std::env::temp_dir().join(format!("beesync-key-{}-rerun", std::process::id()));
+        let key = Key::Cmd {
+            cmd: format!("echo run >> '{}'; echo token", runs.display()),
+        };
+        let run = || async {
+            key.resolve().await.unwrap();
+            key.resolve().await.unwrap();
+        };
+
+        RunKeys::default().scope(run()).await;
+        RunKeys::default().scope(run()).await;
+        let count = fs::read_to_string(&runs).unwrap().lines().count();
+        fs::remove_file(&runs).unwrap();
+
+        assert_eq!(count, 2);
+    }
+
+    #[tokio::test]
+    async fn failed_command_does_not_echo_its_output() {
+        let key = Key::Cmd {
+            cmd: "echo $((6 * 7))-leaked >&2; exit 3".to_string(),
+        };
+        let error = key.resolve().await.unwrap_err().to_string();
+        assert!(!error.contains("42-leaked"), "{error}");
+        assert!(error.contains("exit status: 3"), "{error}");
+    }
+
+    fn key_file(test: &str, contents: &str, mode: u32) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("beesync-key-{}-{test}", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn key_file_is_trimmed() {
+        let path = key_file("trimmed", "  token\n", 0o600);
+        let key = Key::File {
+            file: path.display().to_string(),
+        };
+        let value = key
+            .resolve()
+            .await
+            .map(|secret| secret.expose().to_string());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(value.unwrap(), "token");
+    }
+
+    #[test]
+    fn key_file_readable_by_others_is_refused() {
+        let path = key_file("readable", "token", 0o644);
+        let error = read_file(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Key file '{0}' is accessible by group or others (mode 0644); \
+                 restrict it with `chmod 600 {0}`",
+                path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn missing_and_empty_key_files_are_named() {
+        let path = key_file("empty", "\n", 0o600);
+        let empty = read_file(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        let missing = read_file(&path).unwrap_err();
+
+        assert_eq!(
+            empty.to_string(),
+            format!("Key file '{}' is empty", path.display())
+        );
+        assert_eq!(
+            missing.to_string(),
+            format!("Key file '{}' does not exist", path.display())
+        );
+    }
+
+    #[test]
+    fn credentials_are_looked_up_in_the_credentials_directory() {
+        let directory = Some(OsString::from("/run/credentials/beesync.service"));
+        assert_eq!(
+            credential_path(directory.clone(), "github").unwrap(),
+            Path::new("/run/credentials/beesync.service/github")
+        );
+        assert!(credential_path(directory, "../github").is_err());
+        assert!(credential_path(None, "github")
+            .unwrap_err()
+            .to_string()
+            .contains("CREDENTIALS_DIRECTORY is not set"));
+    }
+
+    #[tokio::test]
+    async fn stuck_command_is_killed() {
+        let started = Instant::now();
+        let error = run_command("exec sleep 10", Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Command 'exec sleep 10' timed out after 100ms"
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn cancelled_command_is_killed() {
+        let done = std::env::temp_dir().join(format!("beesync-key-{}-cancel", std::process::id()));
+        let key = Key::Cmd {
+            cmd: format!("sleep 0.5; touch '{}'", done.display()),
+        };
+
+        let resolved = tokio::time::timeout(Duration::from_millis(100), key.resolve()).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        assert!(resolved.is_err());
+        assert!(!done.exists());
     }
 }
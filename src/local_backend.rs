@@ -0,0 +1,477 @@
+use crate::goals::GoalSettings;
+use crate::timezone::daystamp;
+use anyhow::{Context, Result};
+use beeminder::types::{CreateDatapoint, Datapoint, DatapointFull, UpdateDatapoint};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use time::OffsetDateTime;
+
+/// Makes datapoint ids unique within a process; the time tells processes
+/// apart.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A stand-in for Beeminder that keeps each goal in `<dir>/<goal>.json`, for
+/// trying out a config, offline runs and end-to-end tests.
+///
+/// Like the API, a goal has to exist before it takes datapoints, and a
+/// missing goal is a 404: `beesync goals sync` creates its file, or it can be
+/// written by hand (`{}` will do). Also like the API, a
+/// create repeating the requestid of an unchanged datapoint fails with a 422
+/// "Duplicate request", and one with a changed value or comment updates that
+/// datapoint instead. A datapoint without a daystamp counts for its UTC day.
+pub struct LocalBeeminder {
+    dir: PathBuf,
+    /// Serializes the read-modify-write of goal files between modules.
+    lock: Mutex<()>,
+}
+
+/// A goal file. `deadline` can be edited by hand to try out goals whose day
+/// does not end at midnight.
+#[derive(Default, Deserialize, Serialize)]
+struct Goal {
+    #[serde(default)]
+    deadline: i32,
+    /// Empty in a goal file written by hand, until `beesync goals sync`
+    /// creates the goal.
+    #[serde(flatten)]
+    settings: GoalSettings,
+    #[serde(default)]
+    datapoints: Vec<Point>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Point {
+    id: String,
+    timestamp: i64,
+    daystamp: String,
+    value: f64,
+    #[serde(default)]
+    comment: String,
+    requestid: Option<String>,
+    updated_at: i64,
+}
+
+impl Point {
+    /// The datapoint as the API returns it.
+    fn to_api<T: DeserializeOwned>(&self) -> Result<T> {
+        let json = serde_json::json!({
+            "id": self.id,
+            "timestamp": self.timestamp,
+            "daystamp": self.daystamp,
+            "value": self.value,
+            "comment": self.comment,
+            "requestid": self.requestid,
+            "updated_at": self.updated_at,
+            "created_at": self.updated_at,
+            "origin": "beesync-local",
+            "is_dummy": false,
+            "is_initial": false,
+        });
+        serde_json::from_value(json).context("converting a local datapoint")
+    }
+}
+
+fn http_status(status: u16, reason: &str, error: &str) -> anyhow::Error {
+    beeminder::Error::HttpStatus {
+        status,
+        reason: reason.to_string(),
+        body: serde_json::json!({ "errors": error }).to_string(),
+    }
+    .into()
+}
+
+fn no_goal(goal: &str) -> anyhow::Error {
+    http_status(404, "Not Found", &format!("no goal '{goal}'"))
+}
+
+fn is_not_found(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref(),
+        Some(beeminder::Error::HttpStatus { status: 404, .. })
+    )
+}
+
+impl LocalBeeminder {
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("creating local Beeminder directory {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn path(&self, goal: &str) -> Result<PathBuf> {
+        // Slugs are all Beeminder allows, and keep goals inside the directory.
+        if goal.is_empty()
+            || !goal
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        {
+            return Err(no_goal(goal));
+        }
+        Ok(self.dir.join(format!("{goal}.json")))
+    }
+
+    fn read(&self, goal: &str) -> Result<Goal> {
+        let path = self.path(goal)?;
+        match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)
+                .with_context(|| format!("reading local goal {}", path.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(no_goal(goal)),
+            Err(e) => Err(e).with_context(|| format!("reading local goal {}", path.display())),
+        }
+    }
+
+    /// Changes `goal` with `change`, writing it back unless `change` fails.
+    fn modify<T>(&self, goal: &str, change: impl FnOnce(&mut Goal) -> Result<T>) -> Result<T> {
+        let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut contents = self.read(goal)?;
+        let result = change(&mut contents)?;
+        self.write(goal, &contents)?;
+        Ok(result)
+    }
+
+    fn write(&self, goal: &str, contents: &Goal) -> Result<()> {
+        let path = self.path(goal)?;
+        // Written whole and renamed, so readers never see half a file.
+        let temporary = path.with_extension("json.tmp");
+        fs::write(&temporary, serde_json::to_string_pretty(contents)?)
+            .and_then(|()| fs::rename(&temporary, &path))
+            .with_context(|| format!("writing local goal {}", path.display()))
+    }
+
+    pub fn goal_deadline(&self, goal: &str) -> Result<i32> {
+        Ok(self.read(goal)?.deadline)
+    }
+
+    /// A goal file written by hand has no settings, so it counts as missing
+    /// until created.
+    pub fn goal(&self, goal: &str) -> Result<Option<GoalSettings>> {
+        let settings = match self.read(goal) {
+            Err(e) if is_not_found(&e) => return Ok(None),
+            contents => contents?.settings,
+        };
+        Ok(Some(settings).filter(|settings| !settings.goal_type.is_empty()))
+    }
+
+    /// Creates the goal's file, or gives settings to one written by hand.
+    pub fn create_goal(&self, goal: &str, settings: GoalSettings) -> Result<()> {
+        let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut contents = match self.read(goal) {
+            Err(e) if is_not_found(&e) => Goal::default(),
+            contents => contents?,
+        };
+        if !contents.settings.goal_type.is_empty() {
+            return Err(http_status(
+                422,
+                "Unprocessable Entity",
+                "Slug has already been taken",
+            ));
+        }
+        contents.settings = settings;
+        self.write(goal, &contents)
+    }
+
+    /// The goal's datapoints, newest first by `sort` (`timestamp`,
+    /// `daystamp` or `updated_at`; by creation otherwise), paged like the
+    /// API.
+    fn sorted(
+        &self,
+        goal: &str,
+        sort: Option<&str>,
+        count: Option<u64>,
+        page: Option<u64>,
+        per: Option<u64>,
+    ) -> Result<Vec<Point>> {
+        let mut points = self.read(goal)?.datapoints;
+        points.reverse();
+        match sort {
+            Some("timestamp") => points.sort_by_key(|point| std::cmp::Reverse(point.timestamp)),
+            Some("daystamp") => {
+                points.sort_by(|a, b| b.daystamp.cmp(&a.daystamp));
+            }
+            Some("updated_at") => points.sort_by_key(|point| std::cmp::Reverse(point.updated_at)),
+            _ => {}
+        }
+        let limit = |n: u64| usize::try_from(n).unwrap_or(usize::MAX);
+        if let Some(per) = per {
+            let page = page.unwrap_or(1).max(1) - 1;
+            points = points
+                .into_iter()
+                .skip(limit(page.saturating_mul(per)))
+                .take(limit(per))
+                .collect();
+        }
+        if let Some(count) = count {
+            points.truncate(limit(count));
+        }
+        Ok(points)
+    }
+
+    pub fn get_datapoints(
+        &self,
+        goal: &str,
+        sort: Option<&str>,
+        count: Option<u64>,
+        page: Option<u64>,
+        per: Option<u64>,
+    ) -> Result<Vec<Datapoint>> {
+        self.sorted(goal, sort, count, page, per)?
+            .iter()
+            .map(Point::to_api)
+            .collect()
+    }
+
+    pub fn get_datapoints_full(
+        &self,
+        goal: &str,
+        sort: Option<&str>,
+        count: Option<u64>,
+        page: Option<u64>,
+        per: Option<u64>,
+    ) -> Result<Vec<DatapointFull>> {
+        self.sorted(goal, sort, count, page, per)?
+            .iter()
+            .map(Point::to_api)
+            .collect()
+    }
+
+    pub fn create_datapoint(&self, goal: &str, datapoint: &CreateDatapoint) -> Result<Datapoint> {
+        let now = OffsetDateTime::now_utc();
+        let timestamp = datapoint.timestamp.unwrap_or(now);
+        let daystamp = datapoint
+            .daystamp
+            .clone()
+            .unwrap_or_else(|| daystamp(timestamp.date()));
+        let comment = datapoint.comment.clone().unwrap_or_default();
+        let point = self.modify(goal, |contents| {
+            let repeated = datapoint.requestid.as_ref().and_then(|requestid| {
+                contents
+                    .datapoints
+                    .iter_mut()
+                    .find(|point| point.requestid.as_ref() == Some(requestid))
+            });
+            if let Some(existing) = repeated {
+                if existing.value == datapoint.value && existing.comment == comment {
+                    return Err(http_status(
+                        422,
+                        "Unprocessable Entity",
+                        "Duplicate request",
+                    ));
+                }
+                existing.value = datapoint.value;
+                existing.comment = comment;
+                existing.updated_at = now.unix_timestamp();
+                return Ok(existing.clone());
+            }
+            let point = Point {
+                id: new_id(now),
+                timestamp: timestamp.unix_timestamp(),
+                daystamp,
+                value: datapoint.value,
+                comment,
+                requestid: datapoint.requestid.clone(),
+                updated_at: now.unix_timestamp(),
+            };
+            contents.datapoints.push(point.clone());
+            Ok(point)
+        })?;
+        point.to_api()
+    }
+
+    pub fn update_datapoint(&self, goal: &str, update: &UpdateDatapoint) -> Result<Datapoint> {
+        let now = OffsetDateTime::now_utc();
+        let point = self.modify(goal, |contents| {
+            let point = contents
+                .datapoints
+                .iter_mut()
+                .find(|point| point.id == update.id)
+                .ok_or_else(|| missing(&update.id))?;
+            if let Some(value) = update.value {
+                point.value = value;
+            }
+            if let Some(comment) = &update.comment {
+                point.comment.clone_from(comment);
+            }
+            if let Some(timestamp) = update.timestamp {
+                point.timestamp = timestamp.unix_timestamp();
+            }
+            point.updated_at = now.unix_timestamp();
+            Ok(point.clone())
+        })?;
+        point.to_api()
+    }
+
+    pub fn delete_datapoint(&self, goal: &str, id: &str) -> Result<Datapoint> {
+        let point = self.modify(goal, |contents| {
+            let index = contents
+                .datapoints
+                .iter()
+                .position(|point| point.id == id)
+                .ok_or_else(|| missing(id))?;
+            Ok(contents.datapoints.remove(index))
+        })?;
+        point.to_api()
+    }
+}
+
+fn missing(id: &str) -> anyhow::Error {
+    http_status(404, "Not Found", &format!("no datapoint '{id}'"))
+}
+
+/// A 24-digit hex id, like Beeminder's.
+fn new_id(now: OffsetDateTime) -> String {
+    format!(
+        "{:08x}{:016x}",
+        now.unix_timestamp() as u32,
+        u64::from(now.nanosecond()) << 32 | NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn scratch(test: &str) -> (PathBuf, LocalBeeminder) {
+        let dir = std::env::temp_dir().join(format!("beesync-local-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let local = LocalBeeminder::open(&dir).unwrap();
+        local
+            .create_goal("commits", GoalSettings::default())
+            .unwrap();
+        (dir, local)
+    }
+
+    fn commit(requestid: &str, comment: &str) -> CreateDatapoint {
+        CreateDatapoint {
+            value: 1.0,
+            timestamp: Some(datetime!(2026-08-04 18:30 UTC)),
+            daystamp: Some("20260804".to_string()),
+            comment: Some(comment.to_string()),
+            requestid: Some(requestid.to_string()),
+        }
+    }
+
+    #[test]
+    fn datapoints_round_trip_through_the_goal_file() {
+        let (dir, local) = scratch("round-trip");
+
+        let created = local
+            .create_datapoint("commits", &commit("abc", "beesync: fix"))
+            .unwrap();
+        let update = UpdateDatapoint::new(created.id.clone()).with_value(2.0);
+        local.update_datapoint("commits", &update).unwrap();
+        let reopened = LocalBeeminder::open(&dir).unwrap();
+        let points = reopened
+            .get_datapoints_full("commits", None, None, None, None)
+            .unwrap();
+        reopened.delete_datapoint("commits", &created.id).unwrap();
+        let after_delete = reopened
+            .get_datapoints("commits", None, None, None, None)
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].id, created.id);
+        assert_eq!(points[0].value, Some(2.0));
+        assert_eq!(points[0].daystamp, "20260804");
+        assert_eq!(points[0].requestid.as_deref(), Some("abc"));
+        assert_eq!(points[0].timestamp, datetime!(2026-08-04 18:30 UTC));
+        assert!(after_delete.is_empty());
+    }
+
+    #[test]
+    fn repeated_requestids_are_rejected_or_update() {
+        let (dir, local) = scratch("duplicate");
+
+        local
+            .create_datapoint("commits", &commit("abc", "first"))
+            .unwrap();
+        let duplicate = local
+            .create_datapoint("commits", &commit("abc", "first"))
+            .unwrap_err();
+        let changed = local
+            .create_datapoint("commits", &commit("abc", "second"))
+            .unwrap();
+        let points = local
+            .get_datapoints("commits", None, None, None, None)
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(
+            duplicate.downcast_ref(),
+            Some(beeminder::Error::HttpStatus { status: 422, body, .. })
+                if body.contains("Duplicate request")
+        ));
+        assert_eq!(changed.comment.as_deref(), Some("second"));
+        assert_eq!(points.len(), 1);
+    }
+
+    #[test]
+    fn datapoints_are_listed_newest_first() {
+        let (dir, local) = scratch("sorted");
+        for (requestid, hour) in [("b", 12), ("c", 18), ("a", 6)] {
+            let mut datapoint = commit(requestid, requestid);
+            datapoint.timestamp =
+                Some(datetime!(2026-08-04 00:00 UTC) + time::Duration::hours(hour));
+            local.create_datapoint("commits", &datapoint).unwrap();
+        }
+
+        let ids = |sort, count| {
+            local
+                .get_datapoints("commits", sort, count, None, None)
+                .unwrap()
+                .into_iter()
+                .map(|point| point.requestid.unwrap())
+                .collect::<Vec<_>>()
+        };
+        let by_time = ids(Some("timestamp"), None);
+        let newest = ids(None, Some(1));
+        let missing = local.delete_datapoint("commits", "nope").unwrap_err();
+        let outside = local.get_datapoints("../commits", None, None, None, None);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(by_time, ["c", "b", "a"]);
+        assert_eq!(newest, ["a"]);
+        assert!(matches!(
+            missing.downcast_ref(),
+            Some(beeminder::Error::HttpStatus { status: 404, .. })
+        ));
+        assert!(outside.is_err());
+    }
+
+    #[test]
+    fn missing_goals_are_not_found_until_created() {
+        let (dir, local) = scratch("missing");
+        let settings = GoalSettings {
+            goal_type: "hustler".to_string(),
+            ..GoalSettings::default()
+        };
+
+        let read = local.get_datapoints("typo", None, None, None, None);
+        let written = local.create_datapoint("typo", &commit("abc", "lost"));
+        let deadline = local.goal_deadline("typo");
+        let before = local.goal("typo").unwrap();
+        local.create_goal("typo", settings.clone()).unwrap();
+        let after = local.goal("typo").unwrap();
+        let again = local.create_goal("typo", settings.clone());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(is_not_found(&read.unwrap_err()));
+        assert!(is_not_found(&written.unwrap_err()));
+        assert!(is_not_found(&deadline.unwrap_err()));
+        assert_eq!(before, None);
+        assert_eq!(after, Some(settings));
+        assert!(again.is_err());
+    }
+}
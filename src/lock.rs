@@ -0,0 +1,261 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::{
+    fmt,
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// How often a waiting run checks whether a lock was released.
+const POLL: Duration = Duration::from_millis(500);
+
+/// What a run does when another beesync process holds its lock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    /// Wait until the other run finishes.
+    Wait,
+    /// Do nothing and succeed.
+    Skip,
+    /// Give up with an error.
+    #[default]
+    Fail,
+}
+
+/// Another process holds a lock and the policy is [`OnConflict::Fail`].
+#[derive(Debug)]
+pub struct Locked {
+    pub path: PathBuf,
+    /// Who holds the lock, as written to the lock file.
+    pub holder: String,
+}
+
+impl fmt::Display for Locked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is locked by another beesync run",
+            self.path.display()
+        )?;
+        if !self.holder.is_empty() {
+            write!(f, " ({})", self.holder)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Locked {}
+
+/// Advisory locks that keep concurrent beesync processes using the same
+/// config file from syncing the same modules at once.
+///
+/// Each config file gets its own directory of lock files in the state
+/// directory: `run.lock` for a whole `beesync run`, and one per module
+/// instance for each of its syncs, whether started by `run` or the daemon.
+///
+/// Locks are held with OS file locks, so a crashed process never blocks
+/// later runs. Each lock file records its holder, and a holder that finds a
+/// previous record knows the previous run did not exit cleanly.
+pub struct LockDir {
+    dir: PathBuf,
+    on_conflict: OnConflict,
+}
+
+impl LockDir {
+    /// The locks for `config`, in `locks/` in the XDG state directory.
+    pub fn for_config(config: &Path, on_conflict: OnConflict) -> Result<Self> {
+        let config = config
+            .canonicalize()
+            .with_context(|| format!("resolving config path {}", config.display()))?;
+        let stem = config
+            .file_stem()
+            .map_or_else(|| "config".into(), |stem| stem.to_string_lossy());
+        let name = format!(
+            "{stem}-{:016x}",
+            fnv1a(config.as_os_str().as_encoded_bytes())
+        );
+        Ok(Self::new(
+            crate::paths::state_dir()?.join("locks").join(name),
+            on_conflict,
+        ))
+    }
+
+    pub(crate) fn new(dir: PathBuf, on_conflict: OnConflict) -> Self {
+        Self { dir, on_conflict }
+    }
+
+    /// Locks a whole `beesync run`. `None` means the run should be skipped.
+    pub async fn run(&self) -> Result<Option<Lock>> {
+        self.acquire("run").await
+    }
+
+    /// Locks one sync of the module instance `label`. `None` means the sync
+    /// should be skipped.
+    pub async fn module(&self, label: &str) -> Result<Option<Lock>> {
+        self.acquire(&format!("module-{label}")).await
+    }
+
+    async fn acquire(&self, name: &str) -> Result<Option<Lock>> {
+        let path = self.dir.join(format!("{name}.lock"));
+        let mut file =
+            open(&path).with_context(|| format!("opening lock file {}", path.display()))?;
+
+        let mut waiting = false;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => {
+                    return Err(e).with_context(|| format!("locking {}", path.display()))
+                }
+            }
+            let holder = read_holder(&mut file).unwrap_or_default();
+            match self.on_conflict {
+                OnConflict::Fail => return Err(Locked { path, holder }.into()),
+                OnConflict::Skip => {
+                    tracing::info!(lock = %path.display(), holder, "locked by another run, skipping");
+                    return Ok(None);
+                }
+                OnConflict::Wait => {
+                    if !waiting {
+                        tracing::info!(lock = %path.display(), holder, "locked by another run, waiting");
+                        waiting = true;
+                    }
+                    tokio::time::sleep(POLL).await;
+                }
+            }
+        }
+
+        let stale = read_holder(&mut file)?;
+        if !stale.is_empty() {
+            tracing::warn!(
+                lock = %path.display(),
+                "taking over a lock left by a run that did not exit cleanly ({stale})"
+            );
+        }
+        let holder = format!(
+            "pid {}, started {}",
+            std::process::id(),
+            humantime::format_rfc3339_seconds(SystemTime::now())
+        );
+        record_holder(&mut file, &holder)
+            .with_context(|| format!("writing lock file {}", path.display()))?;
+        Ok(Some(Lock { file }))
+    }
+}
+
+/// A held lock, released when dropped.
+pub struct Lock {
+    file: File,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // A lock file left with a holder marks a run that crashed. Closing
+        // the file releases the lock itself.
+        let _ = self.file.set_len(0);
+    }
+}
+
+fn open(path: &Path) -> std::io::Result<File> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+fn read_holder(file: &mut File) -> std::io::Result<String> {
+    let mut holder = String::new();
+    file.rewind()?;
+    file.read_to_string(&mut holder)?;
+    Ok(holder.trim().to_string())
+}
+
+fn record_holder(file: &mut File, holder: &str) -> std::io::Result<()> {
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{holder}")?;
+    file.sync_data()
+}
+
+/// 64-bit FNV-1a, which stays the same across builds, unlike std's hasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock_dir(test: &str, on_conflict: OnConflict) -> LockDir {
+        let dir = std::env::temp_dir().join(format!("beesync-lock-{}-{test}", std::process::id()));
+        LockDir::new(dir, on_conflict)
+    }
+
+    #[tokio::test]
+    async fn second_holder_fails_or_skips() {
+        let locks = lock_dir("conflict", OnConflict::Fail);
+        let held = locks.run().await.unwrap().unwrap();
+
+        let error = locks.run().await.err().unwrap();
+        let locked = error.downcast_ref::<Locked>().unwrap();
+        assert!(locked
+            .holder
+            .starts_with(&format!("pid {}", std::process::id())));
+
+        let skipping = LockDir::new(locks.dir.clone(), OnConflict::Skip);
+        assert!(skipping.run().await.unwrap().is_none());
+        // Modules lock independently of the run.
+        assert!(skipping.module("github:work").await.unwrap().is_some());
+
+        drop(held);
+        assert!(locks.run().await.unwrap().is_some());
+        std::fs::remove_dir_all(&locks.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn waiting_holder_gets_the_lock_once_released() {
+        let locks = lock_dir("wait", OnConflict::Wait);
+        let held = locks.module("github").await.unwrap().unwrap();
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(held);
+        });
+
+        assert!(locks.module("github").await.unwrap().is_some());
+        release.await.unwrap();
+        std::fs::remove_dir_all(&locks.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lock_left_by_a_crash_is_taken_over() {
+        let locks = lock_dir("stale", OnConflict::Fail);
+        std::fs::create_dir_all(&locks.dir).unwrap();
+        let path = locks.dir.join("run.lock");
+        std::fs::write(&path, "pid 1, started 2025-01-31T08:00:00Z\n").unwrap();
+
+        let lock = locks.run().await.unwrap().unwrap();
+        let holder = std::fs::read_to_string(&path).unwrap();
+        assert!(holder.starts_with(&format!("pid {}", std::process::id())));
+
+        drop(lock);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        std::fs::remove_dir_all(&locks.dir).unwrap();
+    }
+
+    #[test]
+    fn fnv1a_is_stable() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::secret::Redacting;
+use anyhow::{Context, Result};
+use std::{io::IsTerminal, path::Path};
+use tracing::level_filters::LevelFilter;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    filter::Targets, fmt, layer::SubscriberExt, util::SubscriberInitExt, Layer,
+};
+
+/// Target of events that repeat the human-readable output (module reports,
+/// run summaries). The terminal already shows that output, so these only go
+/// to the log file.
+pub const OUTPUT: &str = "beesync::output";
+
+/// Crates whose events are logged at the chosen level; everything else
+/// (HTTP internals and the like) only logs warnings.
+const OWN_CRATES: [&str; 5] = [
+    "beesync",
+    "amazing_marvin_light",
+    "aw_client_light",
+    "github_light",
+    "llm",
+];
+
+/// Rotated log files to keep next to the current one.
+const KEEP_LOG_FILES: usize = 7;
+
+/// How much beesync prints, set with `-v`/`-q`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only failures: failed module reports and errors.
+    Quiet,
+    /// The module reports plus warnings.
+    Normal,
+    /// Also debug detail: HTTP requests, dedup decisions, LLM prompts.
+    Verbose,
+    /// Everything.
+    Trace,
+}
+
+impl Verbosity {
+    pub const fn new(verbose: u8, quiet: bool) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Self::Quiet,
+            (false, 0) => Self::Normal,
+            (false, 1) => Self::Verbose,
+            (false, _) => Self::Trace,
+        }
+    }
+
+    pub const fn is_quiet(self) -> bool {
+        matches!(self, Self::Quiet)
+    }
+
+    /// Level of log events shown in the terminal.
+    const fn console_level(self) -> LevelFilter {
+        match self {
+            Self::Quiet => LevelFilter::ERROR,
+            Self::Normal => LevelFilter::WARN,
+            Self::Verbose => LevelFilter::DEBUG,
+            Self::Trace => LevelFilter::TRACE,
+        }
+    }
+
+    /// Level of log events written to the log file, which always includes
+    /// the module reports.
+    const fn file_level(self) -> LevelFilter {
+        match self {
+            Self::Quiet | Self::Normal => LevelFilter::INFO,
+            Self::Verbose => LevelFilter::DEBUG,
+            Self::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+fn targets(level: LevelFilter) -> Targets {
+    Targets::new()
+        .with_default(LevelFilter::WARN.min(level))
+        .with_targets(OWN_CRATES.map(|target| (target, level)))
+}
+
+/// Sets up logging to stderr and, with `log_file`, to a file rotated daily
+/// (`beesync.log.2025-01-31`, …), keeping the last week.
+///
+/// Keep the returned guard alive until exit: dropping it flushes the file.
+pub fn init(verbosity: Verbosity, log_file: Option<&Path>) -> Result<Option<WorkerGuard>> {
+    let console = fmt::layer()
+        .with_writer(Redacting(std::io::stderr))
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_target(false)
+        .with_filter(targets(verbosity.console_level()))
+        .with_filter(tracing_subscriber::filter::filter_fn(|metadata| {
+            metadata.target() != OUTPUT
+        }));
+
+    let (file, guard) = match log_file {
+        Some(path) => {
+            let (writer, guard) = tracing_appender::non_blocking(rolling_file(path)?);
+            let layer = fmt::layer()
+                .with_writer(Redacting(writer))
+                .with_ansi(false)
+                .with_filter(targets(verbosity.file_level()));
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(console)
+        .with(file)
+        .try_init()
+        .context("setting up logging")?;
+    Ok(guard)
+}
+
+fn rolling_file(path: &Path) -> Result<RollingFileAppender> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("log file {} has no file name", path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("creating log directory {}", dir.display()))?;
+    RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(name.to_string_lossy())
+        .max_log_files(KEEP_LOG_FILES + 1)
+        .build(dir)
+        .with_context(|| format!("opening log file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_wins_over_verbose() {
+        assert_eq!(Verbosity::new(0, false), Verbosity::Normal);
+        assert_eq!(Verbosity::new(1, false), Verbosity::Verbose);
+        assert_eq!(Verbosity::new(3, false), Verbosity::Trace);
+        assert_eq!(Verbosity::new(2, true), Verbosity::Quiet);
+    }
+
+    #[test]
+    fn log_file_keeps_module_reports_when_quiet() {
+        assert_eq!(Verbosity::Quiet.console_level(), LevelFilter::ERROR);
+        assert_eq!(Verbosity::Quiet.file_level(), LevelFilter::INFO);
+        assert_eq!(Verbosity::Verbose.file_level(), LevelFilter::DEBUG);
+    }
+
+    #[test]
+    fn other_crates_only_log_warnings() {
+        let debug = targets(LevelFilter::DEBUG);
+        assert!(debug.would_enable("beesync::runner", &tracing::Level::DEBUG));
+        assert!(debug.would_enable("llm", &tracing::Level::DEBUG));
+        assert!(!debug.would_enable("hyper::client", &tracing::Level::DEBUG));
+        assert!(debug.would_enable("hyper::client", &tracing::Level::WARN));
+        assert!(!targets(LevelFilter::ERROR).would_enable("hyper::client", &tracing::Level::WARN));
+    }
+}
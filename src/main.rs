@@ -1,72 +1,412 @@
-use anyhow::Result;
-use beeminder::BeeminderClient;
+use anyhow::{anyhow, bail, Result};
+use backend::Beeminder;
+use clap::Parser;
+use cli::{
+    CheckArgs, Cli, Command, DaemonArgs, GoalsCommand, ReportFormat, RunArgs, SecretsCommand,
+};
 use config::Config;
-use time::{Date, OffsetDateTime, UtcOffset};
+use lock::{LockDir, Locked};
+use logging::Verbosity;
+use metrics::MetricsFile;
+use module::REGISTRY;
+use notify::Notifier;
+use report::{RunReport, EXIT_CONFIG, EXIT_DRIFT, EXIT_LOCKED, EXIT_SYNC_FAILED};
+use runner::Runner;
+use state::StateStore;
+use std::{io::IsTerminal, path::Path, process::ExitCode, sync::Arc, time::Instant};
+use timezone::TimeZone;
+use vault::Vault;
+mod backend;
 mod category_sync;
+mod check;
 mod clean_tube_sync;
 mod clean_view_sync;
+mod cli;
 mod config;
+mod daemon;
 mod daylio_sync;
 mod fatebook_sync;
 mod focusmate_sync;
 mod github_sync;
+mod goals;
 mod key;
+mod local_backend;
+mod lock;
+mod logging;
+mod metrics;
+mod module;
+mod notify;
+mod paths;
+mod reconcile;
+mod report;
+mod retry;
+mod runner;
+mod schedule;
+mod secret;
+mod state;
+mod timezone;
+mod vault;
 
-async fn run_sync<F, Fut>(f: F)
-where
-    F: FnOnce() -> Fut,
-    Fut: std::future::Future<Output = Result<()>>,
-{
-    match f().await {
-        Ok(()) => println!("  ✅ completed successfully"),
-        Err(e) => eprintln!("  ❌ failed: {e}"),
+fn list(config: &Config) {
+    for spec in REGISTRY {
+        let status = match config.sections.get(spec.name) {
+            Some(toml::Value::Array(entries)) => {
+                format!("configured ({} instances)", entries.len())
+            }
+            Some(_) => "configured".to_string(),
+            None => "not configured".to_string(),
+        };
+        println!("{} {:<10} {status}", spec.emoji, spec.name);
     }
 }
 
-fn local_today() -> Date {
-    let offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
-    OffsetDateTime::now_utc().to_offset(offset).date()
+fn check(config: &Config, args: &CheckArgs) -> Result<()> {
+    let selection = &args.selection;
+    selection.validate()?;
+    let mut failures = 0;
+    for spec in REGISTRY
+        .iter()
+        .filter(|spec| selection.includes_module(spec.name))
+    {
+        let Some(section) = config.sections.get(spec.name) else {
+            continue;
+        };
+        match spec.parse(section.clone()) {
+            Ok(instances) => {
+                for instance in instances
+                    .iter()
+                    .filter(|module| selection.includes(&module.label()))
+                {
+                    println!("  ✅ {}", instance.label());
+                }
+            }
+            Err(e) => {
+                failures += 1;
+                eprintln!("  ❌ {e:#}");
+            }
+        }
+    }
+    if failures > 0 {
+        bail!("{failures} module section(s) are invalid");
+    }
+    if args.offline {
+        return Ok(());
+    }
+
+    let modules = selection.apply(config.modules()?);
+    println!();
+    let failures = runtime()?.block_on(check::live(
+        &config.beeminder_backend,
+        &config.beeminder_key,
+        &config.beeminder_username,
+        &modules,
+    ));
+    if failures > 0 {
+        bail!("{failures} live check(s) failed");
+    }
+    Ok(())
+}
+
+fn secrets(config: &Config, command: SecretsCommand) -> Result<()> {
+    runtime()?.block_on(async {
+        match command {
+            SecretsCommand::Set { vault, entry } => {
+                let mut vault = Vault::open_or_create(&vault, &config.vault).await?;
+                let value = read_secret(&format!("Value for '{entry}': "))?;
+                if value.is_empty() {
+                    bail!("no value given for '{entry}'");
+                }
+                vault.set(&entry, &value);
+                vault.save()?;
+                eprintln!("🔒 stored '{entry}'");
+            }
+            SecretsCommand::Get { vault, entry } => {
+                println!("{}", Vault::open(&vault, &config.vault).await?.get(&entry)?);
+            }
+            SecretsCommand::List { vault } => {
+                for name in Vault::open(&vault, &config.vault).await?.names() {
+                    println!("{name}");
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Errors are config errors; the exit status tells goals that could not be
+/// looked up or created apart from goals that drifted.
+fn goals(config: &Config, command: GoalsCommand) -> Result<ExitCode> {
+    let GoalsCommand::Sync { dry_run } = command;
+    if config.goals.is_empty() {
+        bail!("no goals are defined; add [[goals]] tables to the config");
+    }
+    let beeminder = beeminder_client(config)?;
+    let modules = config.modules()?;
+    let rows = runtime()?.block_on(goals::sync(&beeminder, &config.goals, &modules, dry_run))?;
+    let problems = goals::print_table(&rows);
+    let failed = rows.iter().filter(|(_, outcome)| outcome.is_err()).count();
+    Ok(if failed > 0 {
+        error_exit(
+            &anyhow!("{failed} goal(s) could not be looked up or created"),
+            EXIT_SYNC_FAILED,
+        )
+    } else if problems > 0 {
+        error_exit(&anyhow!("{problems} goal(s) need attention"), EXIT_DRIFT)
+    } else {
+        ExitCode::SUCCESS
+    })
 }
 
-fn main() -> Result<()> {
-    let today = local_today();
-    let runtime = tokio::runtime::Builder::new_multi_thread()
+/// Reads one line from stdin, without echoing it when typed at a terminal.
+fn read_secret(prompt: &str) -> Result<String> {
+    let stdin = std::io::stdin();
+    let terminal = stdin.is_terminal();
+    let stty = |setting: &str| {
+        std::process::Command::new("stty")
+            .arg(setting)
+            .stdin(std::process::Stdio::inherit())
+            .status()
+    };
+    if terminal {
+        eprint!("{prompt}");
+        stty("-echo")?;
+    }
+    let mut line = String::new();
+    let read = stdin.read_line(&mut line);
+    if terminal {
+        stty("echo")?;
+        eprintln!();
+    }
+    read?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn beeminder_client(config: &Config) -> Result<Arc<Beeminder>> {
+    let backend = runtime()?.block_on(
+        config
+            .beeminder_backend
+            .connect(&config.beeminder_key, &config.beeminder_username),
+    )?;
+    Ok(Arc::new(backend))
+}
+
+fn runtime() -> Result<tokio::runtime::Runtime> {
+    Ok(tokio::runtime::Builder::new_multi_thread()
         .enable_all()
-        .build()?;
+        .build()?)
+}
 
-    runtime.block_on(async move {
-        let config = Config::load()?;
-        let bee_key = config.beeminder_key.get_value()?;
-        let bee_client = BeeminderClient::new(bee_key).with_username(config.beeminder_username);
+/// Prints a notice about the whole run, keeping stdout free for a report.
+fn notice(message: &str, report: Option<ReportFormat>, verbosity: Verbosity) {
+    match (verbosity.is_quiet(), report) {
+        (true, _) => {}
+        (false, Some(_)) => eprintln!("{message}"),
+        (false, None) => println!("{message}"),
+    }
+}
 
-        if let Some(focusmate_config) = config.focusmate {
-            run_sync(|| focusmate_sync::focusmate_sync(&focusmate_config, &bee_client)).await;
+/// Runs the selected modules. Errors mean the run could not start at all;
+/// module failures are recorded in the returned report instead.
+fn sync(
+    config: Config,
+    args: &RunArgs,
+    config_path: &Path,
+    timezone: &TimeZone,
+    verbosity: Verbosity,
+) -> Result<RunReport> {
+    let started = Instant::now();
+    args.selection.validate()?;
+    let modules = args.selection.apply(config.modules()?);
+    let mut runner = Runner::new(
+        beeminder_client(&config)?,
+        StateStore::open(&config.beeminder_backend.state_path()?)?,
+        config.concurrency,
+        args.dry_run,
+        args.report.is_some(),
+        verbosity,
+    )
+    .with_retry(config.retry.clone())
+    .with_timezone(timezone.clone());
+    if let Some(timeout) = config.timeout {
+        runner = runner.with_timeout(timeout);
+    }
+    if let Some(path) = &config.metrics_file {
+        runner = runner.with_metrics(MetricsFile::new(path.clone()));
+    }
+    // Dry runs write nothing, so they never wait for or block other runs,
+    // and never notify.
+    let locks = if args.dry_run {
+        None
+    } else {
+        let on_conflict = args.on_conflict.unwrap_or(config.on_conflict);
+        let locks = Arc::new(LockDir::for_config(config_path, on_conflict)?);
+        runner = runner.with_locks(Arc::clone(&locks));
+        if let Some(notifier) = Notifier::new(&config.notify) {
+            runner = runner.with_notifier(notifier);
         }
+        Some(locks)
+    };
 
-        if let Some(fatebook_config) = config.fatebook {
-            run_sync(|| fatebook_sync::fatebook_sync(&fatebook_config, &bee_client)).await;
-        }
+    if args.dry_run {
+        notice(
+            "🔍 dry run: nothing will be written to Beeminder",
+            args.report,
+            verbosity,
+        );
+    }
 
-        if let Some(category_config) = config.category {
-            run_sync(|| category_sync::category_sync(&category_config, &bee_client)).await;
-        }
+    runtime()?.block_on(async {
+        let _run_lock = match &locks {
+            Some(locks) => match locks.run().await? {
+                Some(lock) => Some(lock),
+                None => {
+                    notice(
+                        "⏭️  another beesync run with this config is in progress; skipping",
+                        args.report,
+                        verbosity,
+                    );
+                    return Ok(RunReport::skipped(args.dry_run, started.elapsed()));
+                }
+            },
+            None => None,
+        };
+        let modules = runner.run(modules, timezone.today()).await;
+        Ok(RunReport::new(args.dry_run, started.elapsed(), modules))
+    })
+}
 
-        if let Some(clean_tube_config) = config.clean_tube {
-            run_sync(|| clean_tube_sync::clean_tube_sync(&clean_tube_config, &bee_client)).await;
+/// Runs the selected modules on their schedules until stopped.
+fn daemon(
+    config: Config,
+    args: &DaemonArgs,
+    config_path: &Path,
+    timezone: TimeZone,
+    verbosity: Verbosity,
+) -> Result<()> {
+    args.selection.validate()?;
+    let modules = args.selection.apply(config.modules()?);
+    let mut runner = Runner::new(
+        beeminder_client(&config)?,
+        StateStore::open(&config.beeminder_backend.state_path()?)?,
+        config.concurrency,
+        args.dry_run,
+        false,
+        verbosity,
+    )
+    .with_retry(config.retry.clone())
+    .with_timezone(timezone.clone());
+    if let Some(timeout) = config.timeout {
+        runner = runner.with_timeout(timeout);
+    }
+    if let Some(path) = &config.metrics_file {
+        runner = runner.with_metrics(MetricsFile::new(path.clone()));
+    }
+    if !args.dry_run {
+        let on_conflict = args.on_conflict.unwrap_or(config.on_conflict);
+        runner = runner.with_locks(Arc::new(LockDir::for_config(config_path, on_conflict)?));
+        if let Some(notifier) = Notifier::new(&config.notify) {
+            runner = runner.with_notifier(notifier);
         }
+    }
 
-        if let Some(clean_view_config) = config.clean_view {
-            run_sync(|| clean_view_sync::clean_view_sync(&clean_view_config, &bee_client)).await;
-        }
+    if args.dry_run {
+        notice(
+            "🔍 dry run: nothing will be written to Beeminder",
+            None,
+            verbosity,
+        );
+    }
 
-        if let Some(github_config) = config.github {
-            run_sync(|| github_sync::github_sync(&github_config, &bee_client)).await;
-        }
+    runtime()?.block_on(daemon::run(
+        Arc::new(runner),
+        modules,
+        Arc::new(timezone),
+        verbosity,
+    ))
+}
 
-        if let Some(daylio_config) = config.daylio {
-            run_sync(|| daylio_sync::daylio_sync(&daylio_config, &bee_client, today)).await;
-        }
+/// Prints `report` if requested and turns it into the process exit status.
+fn finish(report: Result<RunReport>, format: Option<ReportFormat>) -> ExitCode {
+    let report = match report {
+        Ok(report) => report,
+        Err(e) if e.is::<Locked>() => return error_exit(&e, EXIT_LOCKED),
+        Err(e) => return config_error(&e),
+    };
+    match format {
+        Some(ReportFormat::Json) => println!(
+            "{}",
+            serde_json::to_string(&report).expect("run reports always serialize")
+        ),
+        None => {}
+    }
+    ExitCode::from(report.exit_code())
+}
 
-        Ok(())
-    })
+fn config_error(error: &anyhow::Error) -> ExitCode {
+    error_exit(error, EXIT_CONFIG)
+}
+
+fn error_exit(error: &anyhow::Error, code: u8) -> ExitCode {
+    let error = secret::redact(&format!("{error:#}")).into_owned();
+    eprintln!("Error: {error}");
+    tracing::error!(target: logging::OUTPUT, "{error}");
+    ExitCode::from(code)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let verbosity = Verbosity::new(cli.verbose, cli.quiet);
+    let _log_file = match logging::init(verbosity, cli.log_file.as_deref()) {
+        Ok(guard) => guard,
+        Err(e) => return config_error(&e),
+    };
+    let config_path = match config::locate(cli.config.as_deref()) {
+        Ok(path) => path,
+        Err(e) => return config_error(&e),
+    };
+    let config = match Config::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => return config_error(&e),
+    };
+    vault::configure(&config.vault);
+    let timezone = config.timezone.clone().unwrap_or_else(TimeZone::system);
+
+    match cli.command.unwrap_or(Command::Run(RunArgs::default())) {
+        Command::Run(args) => finish(
+            sync(config, &args, &config_path, &timezone, verbosity),
+            args.report,
+        ),
+        Command::List => {
+            list(&config);
+            ExitCode::SUCCESS
+        }
+        Command::Check(args) => match check(&config, &args) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => config_error(&e),
+        },
+        Command::Preview(args) => {
+            let args = RunArgs {
+                selection: args.selection,
+                dry_run: true,
+                on_conflict: None,
+                report: args.report,
+            };
+            finish(
+                sync(config, &args, &config_path, &timezone, verbosity),
+                args.report,
+            )
+        }
+        Command::Daemon(args) => match daemon(config, &args, &config_path, timezone, verbosity) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => config_error(&e),
+        },
+        Command::Secrets(command) => match secrets(&config, command) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => config_error(&e),
+        },
+        Command::Goals(command) => match goals(&config, command) {
+            Ok(code) => code,
+            Err(e) => config_error(&e),
+        },
+    }
 }
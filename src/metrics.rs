@@ -0,0 +1,250 @@
+use crate::state::{ModuleRun, StateStore};
+use anyhow::{Context, Result};
+use std::{
+    ffi::OsString,
+    fmt::Write as _,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+/// Writes Prometheus metrics for every module instance that ever ran to a
+/// file for the node exporter's textfile collector.
+pub struct MetricsFile {
+    path: PathBuf,
+}
+
+impl MetricsFile {
+    pub const fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Rewrites the file from the runs recorded in `state`, plus the run that
+    /// just finished after `duration`.
+    ///
+    /// The file is replaced atomically, so the collector never reads half of
+    /// it.
+    pub fn write(&self, state: &StateStore, duration: Duration) -> Result<()> {
+        let finished = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let text = render(&state.runs()?, duration, finished);
+
+        let mut temporary = OsString::from(self.path.as_os_str());
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        let write = || -> std::io::Result<()> {
+            std::fs::write(&temporary, &text)?;
+            std::fs::rename(&temporary, &self.path)
+        };
+        write().with_context(|| format!("writing metrics file {}", self.path.display()))
+    }
+}
+
+/// A metric family: its `# HELP`/`# TYPE` header and one sample per line.
+struct Family<'a> {
+    out: &'a mut String,
+}
+
+impl<'a> Family<'a> {
+    fn new(out: &'a mut String, name: &str, kind: &str, help: &str) -> Self {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        Self { out }
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let _ = write!(self.out, "{name}");
+        if !labels.is_empty() {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+                .collect();
+            let _ = write!(self.out, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.out, " {value}");
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// `module` and, for named instances, `instance` labels for a module label.
+fn module_labels(label: &str) -> Vec<(&'static str, &str)> {
+    match label.split_once(':') {
+        Some((module, instance)) => vec![("module", module), ("instance", instance)],
+        None => vec![("module", label)],
+    }
+}
+
+/// Each module metric: name, type, help, and its value for a run (`None`
+/// leaves the module out).
+type ModuleMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&ModuleRun) -> Option<f64>,
+);
+
+#[allow(clippy::cast_precision_loss)]
+const MODULE_METRICS: [ModuleMetric; 6] = [
+    (
+        "beesync_module_last_run_timestamp_seconds",
+        "gauge",
+        "Unix time the module's last run finished.",
+        |run| Some(run.finished_at as f64),
+    ),
+    (
+        "beesync_module_last_success_timestamp_seconds",
+        "gauge",
+        "Unix time the module last synced successfully.",
+        |run| run.succeeded_at.map(|at| at as f64),
+    ),
+    (
+        "beesync_module_last_run_success",
+        "gauge",
+        "Whether the module's last run succeeded (1) or failed (0).",
+        |run| Some(if run.succeeded { 1.0 } else { 0.0 }),
+    ),
+    (
+        "beesync_module_last_run_duration_seconds",
+        "gauge",
+        "How long the module's last run took.",
+        |run| Some(run.duration_ms as f64 / 1000.0),
+    ),
+    (
+        "beesync_module_last_run_source_items",
+        "gauge",
+        "Source items the module read in its last run.",
+        |run| Some(run.tally.seen as f64),
+    ),
+    (
+        "beesync_module_errors_total",
+        "counter",
+        "Failed runs of the module.",
+        |run| Some(run.errors as f64),
+    ),
+];
+
+#[allow(clippy::cast_precision_loss)]
+fn render(runs: &[ModuleRun], duration: Duration, finished: Duration) -> String {
+    let mut out = String::new();
+
+    let mut family = Family::new(
+        &mut out,
+        "beesync_run_timestamp_seconds",
+        "gauge",
+        "Unix time the last beesync run finished.",
+    );
+    family.sample(
+        "beesync_run_timestamp_seconds",
+        &[],
+        finished.as_secs() as f64,
+    );
+    let mut family = Family::new(
+        &mut out,
+        "beesync_run_duration_seconds",
+        "gauge",
+        "How long the last beesync run took.",
+    );
+    family.sample("beesync_run_duration_seconds", &[], duration.as_secs_f64());
+
+    for (name, kind, help, value) in MODULE_METRICS {
+        let mut family = Family::new(&mut out, name, kind, help);
+        for run in runs {
+            if let Some(value) = value(run) {
+                family.sample(name, &module_labels(&run.module), value);
+            }
+        }
+    }
+
+    let name = "beesync_module_last_run_datapoints";
+    let mut family = Family::new(
+        &mut out,
+        name,
+        "gauge",
+        "Datapoints the module changed in its last run, by change.",
+    );
+    for run in runs {
+        let changes = [
+            ("created", run.tally.created),
+            ("updated", run.tally.updated),
+            ("deleted", run.tally.deleted),
+            ("skipped", run.tally.skipped),
+        ];
+        for (change, count) in changes {
+            let mut labels = module_labels(&run.module);
+            labels.push(("change", change));
+            family.sample(name, &labels, count as f64);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::Tally;
+
+    #[test]
+    fn renders_the_textfile_format() {
+        let runs = vec![
+            ModuleRun {
+                module: "clean_tube".to_string(),
+                finished_at: 1_738_310_400,
+                succeeded_at: Some(1_738_310_400),
+                succeeded: true,
+                duration_ms: 1500,
+                tally: Tally {
+                    created: 2,
+                    ..Tally::default()
+                },
+                errors: 0,
+            },
+            ModuleRun {
+                module: "github:work".to_string(),
+                finished_at: 1_738_310_400,
+                succeeded_at: None,
+                succeeded: false,
+                duration_ms: 20,
+                tally: Tally::default(),
+                errors: 3,
+            },
+        ];
+
+        let text = render(
+            &runs,
+            Duration::from_millis(2500),
+            Duration::from_secs(1_738_310_401),
+        );
+
+        for line in [
+            "# TYPE beesync_module_errors_total counter",
+            "beesync_run_duration_seconds 2.5",
+            "beesync_run_timestamp_seconds 1738310401",
+            r#"beesync_module_last_success_timestamp_seconds{module="clean_tube"} 1738310400"#,
+            r#"beesync_module_last_run_success{module="github",instance="work"} 0"#,
+            r#"beesync_module_errors_total{module="github",instance="work"} 3"#,
+            r#"beesync_module_last_run_source_items{module="clean_tube"} 0"#,
+            r#"beesync_module_last_run_duration_seconds{module="clean_tube"} 1.5"#,
+            r#"beesync_module_last_run_datapoints{module="clean_tube",change="created"} 2"#,
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+        // A module that never succeeded has no last success.
+        assert!(!text.contains(
+            r#"beesync_module_last_success_timestamp_seconds{module="github",instance="work"}"#
+        ));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape(r#"a"b\c"#), r#"a\"b\\c"#);
+    }
+}
@@ -0,0 +1,635 @@
+use anyhow::{Context, Result};
+use beeminder::types::{CreateDatapoint, Datapoint};
+use serde::{
+    de::{DeserializeOwned, IntoDeserializer},
+    Deserialize, Serialize,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use time::Date;
+
+use crate::backend::Beeminder;
+use crate::category_sync::CategorySyncConfig;
+use crate::check::Check;
+use crate::clean_tube_sync::CleanTubeConfig;
+use crate::clean_view_sync::CleanViewConfig;
+use crate::daylio_sync::DaylioConfig;
+use crate::fatebook_sync::FatebookConfig;
+use crate::focusmate_sync::FocusmateConfig;
+use crate::github_sync::GitHubConfig;
+use crate::logging::OUTPUT;
+use crate::report::{Change, Tally};
+use crate::retry::{RetryConfig, RetryPolicy};
+use crate::schedule::Schedule;
+use crate::secret::redact;
+use crate::state::{GoalState, StateStore};
+use crate::timezone::{GoalDays, TimeZone};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Everything a module needs from the runner for a single sync.
+pub struct SyncContext<'a> {
+    pub beeminder: &'a Beeminder,
+    state: &'a StateStore,
+    /// Label of the module instance syncing, which keys its state.
+    module: &'a str,
+    pub today: Date,
+    /// The zone deciding which day datapoints are for; see
+    /// [`SyncContext::goal_days`].
+    pub timezone: &'a TimeZone,
+    /// Whether days end at each goal's deadline rather than at midnight;
+    /// see [`SyncContext::goal_days`].
+    goal_deadlines: bool,
+    /// Deadlines of the goals looked up so far.
+    deadlines: Mutex<HashMap<String, time::Duration>>,
+    /// Report what would change without writing to Beeminder.
+    pub dry_run: bool,
+    retry: RetryPolicy,
+    output: Mutex<String>,
+    tally: Mutex<Tally>,
+}
+
+/// Like `println!`, but into the module's buffered output (see
+/// [`SyncContext::report`]).
+macro_rules! report {
+    ($ctx:expr, $($arg:tt)*) => {
+        $ctx.report(format_args!($($arg)*))
+    };
+}
+pub(crate) use report;
+
+impl<'a> SyncContext<'a> {
+    pub fn new(
+        beeminder: &'a Beeminder,
+        state: &'a StateStore,
+        module: &'a str,
+        today: Date,
+        timezone: &'a TimeZone,
+        dry_run: bool,
+        retry: RetryPolicy,
+    ) -> Self {
+        Self {
+            beeminder,
+            state,
+            module,
+            today,
+            timezone,
+            goal_deadlines: true,
+            deadlines: Mutex::new(HashMap::new()),
+            dry_run,
+            retry,
+            output: Mutex::new(String::new()),
+            tally: Mutex::new(Tally::default()),
+        }
+    }
+
+    /// With `false`, days end at midnight for every goal, whatever its
+    /// deadline.
+    pub fn with_goal_deadlines(mut self, goal_deadlines: bool) -> Self {
+        self.goal_deadlines = goal_deadlines;
+        self
+    }
+
+    /// Appends a line to the module's output. Modules run concurrently, so
+    /// the runner prints each module's output as one block once it finishes.
+    /// The line is logged right away as well, within the module's span.
+    pub fn report(&self, line: fmt::Arguments) {
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        let line = line.to_string();
+        let line = redact(&line);
+        tracing::info!(target: OUTPUT, "{}", line.trim());
+        output.push_str(&line);
+        output.push('\n');
+    }
+
+    /// Takes everything reported so far.
+    pub fn take_output(&self) -> String {
+        std::mem::take(&mut *self.output.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// How `goal` divides time into days. Beeminder days end at the goal's
+    /// deadline, which may be before or after midnight, so every datapoint
+    /// for a moment in time takes its daystamp from here; that way all
+    /// modules agree on where a day ends. The deadline is looked up once per
+    /// sync.
+    pub async fn goal_days(&self, goal: &str) -> Result<GoalDays<'a>> {
+        if !self.goal_deadlines {
+            return Ok(self.timezone.goal_days(time::Duration::ZERO));
+        }
+        let cached = self
+            .deadlines
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(goal)
+            .copied();
+        let deadline = match cached {
+            Some(deadline) => deadline,
+            None => {
+                let seconds = self
+                    .retry("fetching the Beeminder goal", || {
+                        self.beeminder.goal_deadline(goal)
+                    })
+                    .await?;
+                let deadline = time::Duration::seconds(seconds.into());
+                tracing::debug!(goal, %deadline, "goal deadline");
+                self.deadlines
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(goal.to_string(), deadline);
+                deadline
+            }
+        };
+        Ok(self.timezone.goal_days(deadline))
+    }
+
+    /// Records `count` datapoint changes for the run report. Creates and
+    /// deletes made through the helpers below are counted automatically.
+    pub fn count(&self, change: Change, count: usize) {
+        self.tally
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .add(change, count);
+    }
+
+    /// Records that the module read `count` items from its source, for the
+    /// run report and metrics.
+    pub fn seen(&self, count: usize) {
+        self.tally.lock().unwrap_or_else(|e| e.into_inner()).seen += count;
+    }
+
+    pub fn tally(&self) -> Tally {
+        *self.tally.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Makes `call`, retrying transient failures (timeouts, rate limits,
+    /// server errors) with the module's retry policy. Only use this for calls
+    /// that are safe to repeat: reads, updates and creates with a requestid.
+    pub async fn retry<T, E, F, Fut>(&self, what: &str, call: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Into<anyhow::Error>,
+    {
+        self.retry.run(what, call).await
+    }
+
+    /// This module's local sync state for `goal`.
+    pub fn state(&self, goal: &str) -> GoalState<'a> {
+        GoalState::new(self.state, self.module, goal, self.dry_run)
+    }
+
+    /// Request ids already synced to `goal`. They come from the state store;
+    /// the first time, the state is rebuilt from the goal's datapoints.
+    pub async fn synced_requestids(&self, goal: &str) -> Result<HashSet<String>> {
+        let state = self.state(goal);
+        if let Some(items) = state.items()? {
+            tracing::debug!(goal, synced = items.len(), "using recorded sync state");
+            return Ok(items);
+        }
+        let items: Vec<_> = self
+            .retry("fetching Beeminder datapoints", || {
+                self.beeminder
+                    .get_datapoints(goal, Some("timestamp"), None, None, None)
+            })
+            .await?
+            .into_iter()
+            .filter_map(|dp| Some((dp.requestid?, Some(dp.id))))
+            .collect();
+        let synced = items.iter().map(|(item, _)| item.clone()).collect();
+        tracing::debug!(
+            goal,
+            synced = items.len(),
+            "rebuilt sync state from Beeminder"
+        );
+        state.rebuild(items, None)?;
+        Ok(synced)
+    }
+
+    /// Creates `datapoint` on `goal`, unless this is a dry run. A request id
+    /// is recorded as synced in the goal's state; see
+    /// [`SyncContext::synced_requestids`].
+    ///
+    /// Only datapoints with a request id are retried: Beeminder rejects a
+    /// repeat of one that was created after all, where a datapoint without
+    /// one would be created twice.
+    pub async fn create_datapoint(
+        &self,
+        goal: &str,
+        datapoint: &CreateDatapoint,
+    ) -> Result<Option<Datapoint>> {
+        tracing::debug!(
+            goal,
+            value = datapoint.value,
+            requestid = datapoint.requestid.as_deref(),
+            dry_run = self.dry_run,
+            "creating datapoint"
+        );
+        if self.dry_run {
+            self.count(Change::Created, 1);
+            return Ok(None);
+        }
+        let create = || self.beeminder.create_datapoint(goal, datapoint);
+        let created = if datapoint.requestid.is_some() {
+            self.retry("creating a Beeminder datapoint", create).await?
+        } else {
+            create().await?
+        };
+        self.count(Change::Created, 1);
+        if let Some(requestid) = &datapoint.requestid {
+            self.state(goal).record(requestid, Some(&created.id))?;
+        }
+        Ok(Some(created))
+    }
+
+    /// Verb for reporting a create, so dry-run output reads as a plan.
+    pub const fn created(&self) -> &'static str {
+        if self.dry_run {
+            "Would create"
+        } else {
+            "Created"
+        }
+    }
+}
+
+/// A client a module builds on its first sync and reuses on later ones, so
+/// the daemon keeps connections open across runs. The client is built again
+/// when the inputs it was built from, such as a rotated key, change.
+pub struct ClientCache<T>(Mutex<Option<(u64, Arc<T>)>>);
+
+impl<T> Default for ClientCache<T> {
+    fn default() -> Self {
+        Self(Mutex::new(None))
+    }
+}
+
+impl<T> ClientCache<T> {
+    pub fn get_or_try_init<I: Hash>(
+        &self,
+        inputs: I,
+        init: impl FnOnce(I) -> Result<T>,
+    ) -> Result<Arc<T>> {
+        let mut hasher = DefaultHasher::new();
+        inputs.hash(&mut hasher);
+        let hash = hasher.finish();
+        let mut cached = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((built_from, client)) = cached.as_ref() {
+            if *built_from == hash {
+                return Ok(Arc::clone(client));
+            }
+        }
+        let client = Arc::new(init(inputs)?);
+        *cached = Some((hash, Arc::clone(&client)));
+        Ok(client)
+    }
+}
+
+/// Keys every module section accepts on top of the module's own config.
+/// They are read by the runner and the daemon, never by the module itself.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModuleOptions {
+    /// Tells apart several `[[module]]` instances in output, reports, state
+    /// and `--only`/`--skip`.
+    pub name: Option<String>,
+    /// `false` keeps the section configured without ever running it.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// When `beesync daemon` runs the module. Unscheduled modules only run
+    /// from `beesync run`.
+    pub schedule: Option<Schedule>,
+    /// Overrides the global `[retry]` settings for this module.
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Overrides the global `timeout` for this module.
+    #[serde(default, deserialize_with = "crate::config::duration")]
+    pub timeout: Option<Duration>,
+    /// `false` credits datapoints to the calendar day instead of the goal's
+    /// day, which ends at its Beeminder deadline.
+    #[serde(default = "default_true")]
+    pub goal_deadline: bool,
+}
+
+const fn default_true() -> bool {
+    true
+}
+
+impl ModuleOptions {
+    pub const KEYS: &'static [&'static str] = &[
+        "name",
+        "enabled",
+        "schedule",
+        "retry",
+        "timeout",
+        "goal_deadline",
+    ];
+}
+
+/// A Beeminder integration.
+///
+/// The implementing type is the module's config section, so adding an
+/// integration means writing its config struct, implementing this trait for
+/// it, and listing it in [`REGISTRY`].
+pub trait SyncModule: DeserializeOwned + Send + Sync + 'static {
+    /// Config section name, also used to select modules on the command line.
+    const NAME: &'static str;
+    const EMOJI: &'static str;
+
+    /// Beeminder goals this module writes to. Modules sharing a goal never
+    /// run at the same time.
+    fn goals(&self) -> Vec<String>;
+
+    /// Syncs the module. Writes must go through the [`SyncContext`] helpers
+    /// (or check [`SyncContext::dry_run`]) so dry runs stay read-only.
+    fn sync<'a>(&'a self, ctx: &'a SyncContext<'a>) -> BoxFuture<'a, Result<()>>;
+
+    /// Verifies the module's keys, sources and services for `beesync check`,
+    /// without writing anything. [`SyncModule::goals`] are checked separately.
+    fn check(&self) -> BoxFuture<'_, Vec<Check>> {
+        Box::pin(async { Vec::new() })
+    }
+}
+
+/// Object-safe view of a configured [`SyncModule`].
+pub trait Module: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn emoji(&self) -> &'static str;
+    fn options(&self) -> &ModuleOptions;
+    fn goals(&self) -> Vec<String>;
+    fn sync<'a>(&'a self, ctx: &'a SyncContext<'a>) -> BoxFuture<'a, Result<()>>;
+    fn check(&self) -> BoxFuture<'_, Vec<Check>>;
+
+    /// Identifies the instance: the module name, plus `:<name>` for a named
+    /// instance, e.g. `github:work`.
+    fn label(&self) -> String {
+        match &self.options().name {
+            Some(name) => format!("{}:{name}", self.name()),
+            None => self.name().to_string(),
+        }
+    }
+
+    /// How output refers to the instance, e.g. `clean-view-sync` or
+    /// `github-sync (work)`.
+    fn title(&self) -> String {
+        let title = format!("{}-sync", self.name().replace('_', "-"));
+        match &self.options().name {
+            Some(name) => format!("{title} ({name})"),
+            None => title,
+        }
+    }
+}
+
+/// A parsed module section: the module's config plus its [`ModuleOptions`].
+struct Configured<M> {
+    options: ModuleOptions,
+    config: M,
+}
+
+impl<M: SyncModule> Module for Configured<M> {
+    fn name(&self) -> &'static str {
+        M::NAME
+    }
+
+    fn emoji(&self) -> &'static str {
+        M::EMOJI
+    }
+
+    fn options(&self) -> &ModuleOptions {
+        &self.options
+    }
+
+    fn goals(&self) -> Vec<String> {
+        self.config.goals()
+    }
+
+    fn sync<'a>(&'a self, ctx: &'a SyncContext<'a>) -> BoxFuture<'a, Result<()>> {
+        self.config.sync(ctx)
+    }
+
+    fn check(&self) -> BoxFuture<'_, Vec<Check>> {
+        self.config.check()
+    }
+}
+
+/// Registry entry: how to recognise and parse one module's config section.
+pub struct ModuleSpec {
+    pub name: &'static str,
+    pub emoji: &'static str,
+    parse: fn(toml_edit::Value) -> Result<Arc<dyn Module>>,
+}
+
+impl ModuleSpec {
+    const fn of<M: SyncModule>() -> Self {
+        Self {
+            name: M::NAME,
+            emoji: M::EMOJI,
+            parse: parse_section::<M>,
+        }
+    }
+
+    /// Parses the module's config: a `[name]` table for a single instance or
+    /// a `[[name]]` array of tables for several.
+    pub fn parse(&self, section: toml::Value) -> Result<Vec<Arc<dyn Module>>> {
+        let section = section
+            .serialize(toml_edit::ser::ValueSerializer::new())
+            .context("converting the section")?;
+        self.parse_spanned(section)
+    }
+
+    /// Like [`ModuleSpec::parse`], for a section read with `toml_edit`: an
+    /// invalid value fails with a [`toml_edit::de::Error`] pointing at it.
+    pub fn parse_spanned(&self, section: toml_edit::Value) -> Result<Vec<Arc<dyn Module>>> {
+        let instances = match section {
+            toml_edit::Value::Array(entries) => entries
+                .into_iter()
+                .enumerate()
+                .map(|(index, entry)| {
+                    (self.parse)(entry)
+                        .with_context(|| format!("invalid [[{}]] entry {}", self.name, index + 1))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            table => {
+                vec![(self.parse)(table)
+                    .with_context(|| format!("invalid [{}] section", self.name))?]
+            }
+        };
+        let mut labels = HashSet::new();
+        for instance in &instances {
+            if !labels.insert(instance.label()) {
+                anyhow::bail!(
+                    "[[{}]] entries need distinct names; '{}' is used twice",
+                    self.name,
+                    instance.label()
+                );
+            }
+        }
+        Ok(instances)
+    }
+}
+
+fn parse_section<M: SyncModule>(section: toml_edit::Value) -> Result<Arc<dyn Module>> {
+    let mut section = match section {
+        toml_edit::Value::InlineTable(table) => table,
+        other => anyhow::bail!("expected a table, found {}", other.type_name()),
+    };
+    let mut options = toml_edit::InlineTable::new();
+    for key in ModuleOptions::KEYS {
+        if let Some((key, value)) = section.remove_entry(key) {
+            options.insert_formatted(&key, value);
+        }
+    }
+    Ok(Arc::new(Configured::<M> {
+        options: ModuleOptions::deserialize(toml_edit::Value::from(options).into_deserializer())?,
+        config: M::deserialize(toml_edit::Value::from(section).into_deserializer())?,
+    }))
+}
+
+/// Every known module, in the order they run.
+pub const REGISTRY: &[ModuleSpec] = &[
+    ModuleSpec::of::<FocusmateConfig>(),
+    ModuleSpec::of::<FatebookConfig>(),
+    ModuleSpec::of::<CategorySyncConfig>(),
+    ModuleSpec::of::<CleanTubeConfig>(),
+    ModuleSpec::of::<CleanViewConfig>(),
+    ModuleSpec::of::<GitHubConfig>(),
+    ModuleSpec::of::<DaylioConfig>(),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn registry_names_are_unique() {
+        let names: HashSet<_> = REGISTRY.iter().map(|spec| spec.name).collect();
+        assert_eq!(names.len(), REGISTRY.len());
+    }
+
+    #[test]
+    fn section_is_parsed_into_its_module() {
+        let spec = REGISTRY.iter().find(|spec| spec.name == "github").unwrap();
+        let section = toml::toml! {
+            goal_name = "commits"
+            username = "octocat"
+        };
+
+        let module = &spec.parse(toml::Value::Table(section)).unwrap()[0];
+
+        assert_eq!(module.name(), "github");
+        assert_eq!(module.emoji(), "🐙");
+        assert!(module.options().schedule.is_none());
+    }
+
+    #[test]
+    fn common_options_are_split_from_the_module_config() {
+        let spec = REGISTRY.iter().find(|spec| spec.name == "github").unwrap();
+        let section = toml::toml! {
+            goal_name = "commits"
+            username = "octocat"
+            schedule = { every = "15m" }
+        };
+
+        let module = &spec.parse(toml::Value::Table(section)).unwrap()[0];
+
+        assert_eq!(
+            module.options().schedule,
+            Some(Schedule::Every(std::time::Duration::from_secs(900)))
+        );
+    }
+
+    #[test]
+    fn invalid_section_names_the_module() {
+        let spec = REGISTRY.iter().find(|spec| spec.name == "github").unwrap();
+        let section = toml::toml! { goal_name = "commits" };
+
+        let error = spec.parse(toml::Value::Table(section)).err().unwrap();
+
+        assert_eq!(error.to_string(), "invalid [github] section");
+    }
+
+    #[test]
+    fn array_of_tables_gives_one_instance_per_entry() {
+        let spec = REGISTRY.iter().find(|spec| spec.name == "github").unwrap();
+        let section = toml::toml! {
+            [[github]]
+            goal_name = "commits"
+            username = "me"
+
+            [[github]]
+            name = "work"
+            goal_name = "work-commits"
+            username = "me-at-work"
+        };
+
+        let instances = spec.parse(section["github"].clone()).unwrap();
+
+        let labels: Vec<_> = instances.iter().map(|module| module.label()).collect();
+        assert_eq!(labels, ["github", "github:work"]);
+        assert_eq!(instances[1].title(), "github-sync (work)");
+        assert_eq!(instances[1].goals(), ["work-commits"]);
+    }
+
+    #[test]
+    fn instances_need_distinct_names() {
+        let spec = REGISTRY.iter().find(|spec| spec.name == "github").unwrap();
+        let section = toml::toml! {
+            [[github]]
+            goal_name = "commits"
+            username = "me"
+
+            [[github]]
+            goal_name = "work-commits"
+            username = "me-at-work"
+        };
+
+        let error = spec.parse(section["github"].clone()).err().unwrap();
+
+        assert_eq!(
+            error.to_string(),
+            "[[github]] entries need distinct names; 'github' is used twice"
+        );
+    }
+
+    #[test]
+    fn invalid_entry_is_numbered() {
+        let spec = REGISTRY.iter().find(|spec| spec.name == "github").unwrap();
+        let section = toml::toml! {
+            [[github]]
+            goal_name = "commits"
+            username = "me"
+
+            [[github]]
+            name = "work"
+        };
+
+        let error = spec.parse(section["github"].clone()).err().unwrap();
+
+        assert_eq!(error.to_string(), "invalid [[github]] entry 2");
+    }
+
+    #[test]
+    fn client_is_rebuilt_when_its_key_changes() {
+        let cache = ClientCache::default();
+        let built = Mutex::new(0);
+        let client = |key: &'static str| {
+            cache
+                .get_or_try_init(key, |key| {
+                    *built.lock().unwrap() += 1;
+                    Ok(key.to_string())
+                })
+                .unwrap()
+        };
+
+        assert_eq!(*client("old"), "old");
+        assert_eq!(*client("old"), "old");
+        assert_eq!(*client("new"), "new");
+        assert_eq!(*built.lock().unwrap(), 2);
+    }
+}
@@ -0,0 +1,254 @@
+use crate::report::{ModuleReport, Status};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// How long a hook command or webhook may take before it is abandoned.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where module outcomes are sent, from the `[notify]` table. Every
+/// configured target receives each notification.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// Also notify when a module succeeds, not only when it fails.
+    #[serde(default)]
+    pub on_success: bool,
+    /// Shell command run with the event as JSON on stdin.
+    pub command: Option<String>,
+    /// File the events are appended to, one JSON object per line.
+    pub file: Option<PathBuf>,
+    /// URL the events are POSTed to as JSON.
+    pub webhook: Option<String>,
+}
+
+/// What a notification carries: the module's report plus what happened.
+#[derive(Serialize)]
+struct Event<'a> {
+    event: &'static str,
+    time: String,
+    #[serde(flatten)]
+    report: &'a ModuleReport,
+}
+
+/// Sends module outcomes to the `[notify]` targets. Failing to notify is
+/// logged and never fails the module.
+pub struct Notifier {
+    config: NotifyConfig,
+    http: reqwest::Client,
+}
+
+impl Notifier {
+    /// A notifier for `config`, or `None` when it has no targets.
+    pub fn new(config: &NotifyConfig) -> Option<Self> {
+        if config.command.is_none() && config.file.is_none() && config.webhook.is_none() {
+            return None;
+        }
+        Some(Self {
+            config: config.clone(),
+            http: reqwest::Client::new(),
+        })
+    }
+
+    /// Notifies every target that a module finished, if its outcome is one
+    /// to notify about.
+    pub async fn module_finished(&self, report: &ModuleReport) {
+        let event = match report.status {
+            Status::Failed | Status::TimedOut => "module_failed",
+            Status::Ok if self.config.on_success => "module_succeeded",
+            Status::Ok | Status::Skipped => return,
+        };
+        let event = Event {
+            event,
+            time: humantime::format_rfc3339_seconds(std::time::SystemTime::now()).to_string(),
+            report,
+        };
+        let payload = serde_json::to_string(&event).expect("events always serialize");
+        tracing::debug!(event = event.event, "sending notifications");
+
+        if let Some(command) = &self.config.command {
+            if let Err(e) = run_command(command, &payload).await {
+                tracing::warn!("notify command failed: {e:#}");
+            }
+        }
+        if let Some(path) = &self.config.file {
+            if let Err(e) = append_line(path, &payload) {
+                tracing::warn!("writing notification to {} failed: {e:#}", path.display());
+            }
+        }
+        if let Some(url) = &self.config.webhook {
+            if let Err(e) = self.post(url, payload).await {
+                tracing::warn!("notify webhook failed: {e:#}");
+            }
+        }
+    }
+
+    async fn post(&self, url: &str, payload: String) -> Result<()> {
+        self.http
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload)
+            .timeout(HOOK_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+async fn run_command(command: &str, payload: &str) -> Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("running '{command}'"))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let output = tokio::time::timeout(HOOK_TIMEOUT, async move {
+        // A command that ignores its input may exit before reading it.
+        let _ = stdin.write_all(payload.as_bytes()).await;
+        drop(stdin);
+        child.wait_with_output().await
+    })
+    .await
+    .with_context(|| format!("'{command}' timed out"))??;
+    if !output.status.success() {
+        bail!(
+            "'{command}' exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn append_line(path: &Path, payload: &str) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    // One write per event keeps concurrent modules' lines whole.
+    file.write_all(format!("{payload}\n").as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::Tally;
+    use std::{io::Read, net::TcpListener};
+
+    fn temp_path(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("beesync-notify-{}-{test}", std::process::id()))
+    }
+
+    fn failed() -> ModuleReport {
+        let error = Err(anyhow::anyhow!("GitHub is down"));
+        ModuleReport::new(
+            "github",
+            Some("work".to_string()),
+            Tally::default(),
+            Duration::from_millis(20),
+            &error,
+        )
+    }
+
+    fn succeeded() -> ModuleReport {
+        ModuleReport::new("daylio", None, Tally::default(), Duration::ZERO, &Ok(()))
+    }
+
+    #[tokio::test]
+    async fn failures_are_appended_to_the_file() {
+        let path = temp_path("file");
+        let config = NotifyConfig {
+            file: Some(path.clone()),
+            ..NotifyConfig::default()
+        };
+        let notifier = Notifier::new(&config).unwrap();
+
+        notifier.module_finished(&failed()).await;
+        notifier.module_finished(&succeeded()).await;
+        notifier
+            .module_finished(&ModuleReport::skipped("github", None))
+            .await;
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["event"], "module_failed");
+        assert_eq!(lines[0]["module"], "github");
+        assert_eq!(lines[0]["instance"], "work");
+        assert_eq!(lines[0]["error"], "GitHub is down");
+    }
+
+    #[tokio::test]
+    async fn command_receives_the_event_on_stdin() {
+        let path = temp_path("command");
+        let config = NotifyConfig {
+            on_success: true,
+            command: Some(format!("cat > '{}'", path.display())),
+            ..NotifyConfig::default()
+        };
+
+        Notifier::new(&config)
+            .unwrap()
+            .module_finished(&succeeded())
+            .await;
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let event: serde_json::Value = serde_json::from_str(&written).unwrap();
+        assert_eq!(event["event"], "module_succeeded");
+        assert_eq!(event["status"], "ok");
+    }
+
+    #[tokio::test]
+    async fn webhook_receives_a_json_post() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            // Read until the body, sent after the headers, has arrived.
+            while !String::from_utf8_lossy(&request).ends_with('}') {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        let config = NotifyConfig {
+            webhook: Some(url),
+            ..NotifyConfig::default()
+        };
+
+        Notifier::new(&config)
+            .unwrap()
+            .module_finished(&failed())
+            .await;
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1"));
+        assert!(request.contains("content-type: application/json"));
+        assert!(request.contains(r#""event":"module_failed""#));
+    }
+
+    #[test]
+    fn no_targets_means_no_notifier() {
+        assert!(Notifier::new(&NotifyConfig::default()).is_none());
+    }
+}
@@ -0,0 +1,59 @@
+use anyhow::{bail, Result};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+/// Directory for beesync's persistent state, following the XDG base
+/// directory spec: `$XDG_STATE_HOME/beesync`, else `~/.local/state/beesync`.
+pub fn state_dir() -> Result<PathBuf> {
+    xdg_dir("XDG_STATE_HOME", ".local/state")
+}
+
+/// Directory for beesync's configuration: `$XDG_CONFIG_HOME/beesync`, else
+/// `~/.config/beesync`.
+pub fn config_dir() -> Result<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+/// Expands a leading `~` in `path` to the home directory.
+pub fn expand_tilde(path: &str) -> Result<PathBuf> {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest.trim_start_matches('/'),
+        _ => return Ok(PathBuf::from(path)),
+    };
+    match env::var_os("HOME") {
+        Some(home) if !home.is_empty() => Ok(Path::new(&home).join(rest)),
+        _ => bail!("cannot expand '~' in '{path}': HOME is not set"),
+    }
+}
+
+fn xdg_dir(variable: &str, fallback: &str) -> Result<PathBuf> {
+    // The spec says relative paths are invalid and must be ignored.
+    if let Some(dir) = env::var_os(variable).map(PathBuf::from) {
+        if dir.is_absolute() {
+            return Ok(dir.join("beesync"));
+        }
+    }
+    match env::var_os("HOME") {
+        Some(home) if !home.is_empty() => Ok(PathBuf::from(home).join(fallback).join("beesync")),
+        _ => bail!("cannot locate the {variable} directory: neither {variable} nor HOME is set"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_leading_tilde_is_expanded() {
+        let home = PathBuf::from(env::var_os("HOME").unwrap());
+        assert_eq!(expand_tilde("~/.key").unwrap(), home.join(".key"));
+        assert_eq!(expand_tilde("~").unwrap(), home);
+        assert_eq!(
+            expand_tilde("~other/.key").unwrap(),
+            Path::new("~other/.key")
+        );
+        assert_eq!(expand_tilde("/etc/~").unwrap(), Path::new("/etc/~"));
+    }
+}
@@ -0,0 +1,830 @@
+use crate::module::{report, SyncContext};
+use crate::report::Change;
+use crate::timezone::daystamp;
+use anyhow::{bail, Context, Result};
+use beeminder::types::{CreateDatapoint, DatapointFull, UpdateDatapoint};
+use std::collections::{HashMap, HashSet};
+use time::Date;
+use tracing::debug;
+
+/// A datapoint a module wants on one of a goal's days. The engine makes it
+/// the only datapoint of that day, identified by `requestid`.
+#[derive(Debug)]
+pub struct Desired {
+    pub goal: String,
+    pub date: Date,
+    pub value: f64,
+    pub comment: String,
+    pub requestid: String,
+    /// Whether a datapoint without a requestid on the day is kept and
+    /// rewritten rather than replaced; see [`Target::keeper`].
+    pub adopt_unkeyed: bool,
+}
+
+#[derive(Debug)]
+pub struct ExistingPoint {
+    pub id: String,
+    pub value: Option<f64>,
+    pub comment: Option<String>,
+    pub requestid: Option<String>,
+    /// A dummy or initial datapoint Beeminder made itself.
+    pub system: bool,
+}
+
+impl From<DatapointFull> for ExistingPoint {
+    fn from(point: DatapointFull) -> Self {
+        Self {
+            id: point.id,
+            value: point.value,
+            comment: point.comment,
+            requestid: point.requestid,
+            system: point.is_dummy.unwrap_or(false) || point.is_initial.unwrap_or(false),
+        }
+    }
+}
+
+/// Datapoints already on each goal, by goal and then by daystamp.
+pub type Existing = HashMap<String, HashMap<String, Vec<ExistingPoint>>>;
+
+/// A desired datapoint together with what its day holds now.
+#[derive(Debug)]
+pub struct Target {
+    pub goal: String,
+    pub date: Date,
+    pub value: f64,
+    pub comment: String,
+    pub requestid: String,
+    pub adopt_unkeyed: bool,
+    pub existing: Vec<ExistingPoint>,
+}
+
+impl Target {
+    /// The existing datapoint to keep and rewrite: the one with the target's
+    /// requestid, or else, when the target adopts unkeyed datapoints, one
+    /// without a requestid, such as a datapoint entered by hand or written
+    /// before the module reconciled. Adopting the latter keeps its history
+    /// instead of deleting and recreating it; Beeminder cannot add a
+    /// requestid to it, so it stays without one.
+    fn keeper(&self) -> Option<&ExistingPoint> {
+        self.existing
+            .iter()
+            .find(|point| point.requestid.as_deref() == Some(&self.requestid))
+            .or_else(|| {
+                self.existing
+                    .iter()
+                    .find(|point| self.adopt_unkeyed && point.requestid.is_none())
+            })
+    }
+}
+
+/// Reconciles `desired` against Beeminder: every desired day ends up with
+/// exactly that one datapoint, and everything else on the day is pruned. A
+/// dry run only previews the changes.
+pub async fn reconcile(ctx: &SyncContext<'_>, desired: Vec<Desired>) -> Result<()> {
+    let goals: Vec<&str> = desired
+        .iter()
+        .map(|desired| desired.goal.as_str())
+        .collect();
+    let existing = fetch(ctx, goals).await?;
+    let targets = plan(desired, existing)?;
+    if ctx.dry_run {
+        preview(ctx, &targets);
+        Ok(())
+    } else {
+        apply(ctx, &targets).await
+    }
+}
+
+/// Fetches every datapoint of `goals`.
+pub async fn fetch<'g>(
+    ctx: &SyncContext<'_>,
+    goals: impl IntoIterator<Item = &'g str>,
+) -> Result<Existing> {
+    let mut existing = Existing::new();
+    for goal in goals {
+        if existing.contains_key(goal) {
+            continue;
+        }
+        let mut by_day: HashMap<String, Vec<ExistingPoint>> = HashMap::new();
+        for datapoint in ctx
+            .retry("fetching Beeminder datapoints", || {
+                ctx.beeminder
+                    .get_datapoints_full(goal, None, None, None, None)
+            })
+            .await
+            .with_context(|| format!("fetching all datapoints for {goal}"))?
+        {
+            by_day
+                .entry(datapoint.daystamp.clone())
+                .or_default()
+                .push(datapoint.into());
+        }
+        existing.insert(goal.to_string(), by_day);
+    }
+    Ok(existing)
+}
+
+/// Pairs each desired datapoint with what its day holds. Refuses the whole
+/// plan when a day holds a datapoint Beeminder made itself, or the same
+/// requestid twice, since neither can be safely rewritten.
+pub fn plan(desired: Vec<Desired>, mut existing: Existing) -> Result<Vec<Target>> {
+    let mut targets = Vec::with_capacity(desired.len());
+    for desired in desired {
+        let points = existing
+            .entry(desired.goal.clone())
+            .or_default()
+            .remove(&daystamp(desired.date))
+            .unwrap_or_default();
+        if points.iter().any(|point| point.system) {
+            bail!(
+                "system datapoint found on {} {}; refusing the entire plan",
+                desired.goal,
+                desired.date
+            )
+        }
+        if points
+            .iter()
+            .filter(|point| point.requestid.as_deref() == Some(&desired.requestid))
+            .count()
+            > 1
+        {
+            bail!(
+                "multiple canonical datapoints found on {} {}",
+                desired.goal,
+                desired.date
+            )
+        }
+        targets.push(Target {
+            goal: desired.goal,
+            date: desired.date,
+            value: desired.value,
+            comment: desired.comment,
+            requestid: desired.requestid,
+            adopt_unkeyed: desired.adopt_unkeyed,
+            existing: points,
+        });
+    }
+    Ok(targets)
+}
+
+/// Reports the plan as a table. In a dry run its changes are counted as
+/// planned; otherwise, as when Daylio is not set to apply, nothing is written
+/// and nothing is counted.
+pub fn preview(ctx: &SyncContext<'_>, targets: &[Target]) {
+    if ctx.dry_run {
+        for target in targets {
+            count_target(ctx, target);
+        }
+    }
+    report!(ctx, "{}", format_preview_table(targets).trim_end());
+}
+
+/// Reports the changes in the plan, then writes them one target at a time,
+/// verifying each before moving on. A dry run only previews them.
+pub async fn apply(ctx: &SyncContext<'_>, targets: &[Target]) -> Result<()> {
+    if ctx.dry_run {
+        preview(ctx, targets);
+        return Ok(());
+    }
+    report!(ctx, "{}", format_apply_plan(targets).trim_end());
+    let (unchanged, mutations): (Vec<&Target>, Vec<&Target>) = targets
+        .iter()
+        .partition(|target| target_is_unchanged(target));
+    ctx.count(Change::Skipped, unchanged.len());
+    if mutations.is_empty() {
+        return Ok(());
+    }
+    let mut progress = ApplyProgress {
+        ctx,
+        total: mutations.len(),
+        verified: 0,
+        current: None,
+    };
+    for target in &mutations {
+        progress.current = Some(target);
+        apply_target(ctx, target)
+            .await
+            .with_context(|| format!("applying {} {}", target.goal, target.date))?;
+        progress.current = None;
+        progress.verified += 1;
+        count_target(ctx, target);
+    }
+    report!(
+        ctx,
+        "  ✅ applied and verified {}",
+        pluralized(mutations.len(), "change", "changes")
+    );
+    Ok(())
+}
+
+fn same_value(actual: Option<f64>, expected: f64) -> bool {
+    actual.is_some_and(|actual| (actual - expected).abs() < 1e-9)
+}
+
+fn target_is_unchanged(target: &Target) -> bool {
+    target.existing.len() == 1
+        && target
+            .keeper()
+            .is_some_and(|keeper| !needs_update(keeper, target))
+}
+
+fn needs_update(keeper: &ExistingPoint, target: &Target) -> bool {
+    !same_value(keeper.value, target.value) || keeper.comment.as_deref() != Some(&target.comment)
+}
+
+fn target_action(target: &Target) -> &'static str {
+    if target_is_unchanged(target) {
+        return "✅ keep";
+    }
+
+    match (target.keeper().is_some(), target.existing.len()) {
+        (true, 1) => "✏️ update",
+        (true, _) => "🧹 update+prune",
+        (false, 0) => "➕ create",
+        (false, _) => "♻️ replace",
+    }
+}
+
+/// Tallies the datapoint changes `apply_target` makes for `target`.
+fn count_target(ctx: &SyncContext<'_>, target: &Target) {
+    if target_is_unchanged(target) {
+        ctx.count(Change::Skipped, 1);
+        return;
+    }
+
+    match target.keeper() {
+        Some(keeper) => {
+            if needs_update(keeper, target) {
+                ctx.count(Change::Updated, 1);
+            }
+            ctx.count(Change::Deleted, target.existing.len() - 1);
+        }
+        None => {
+            ctx.count(Change::Created, 1);
+            ctx.count(Change::Deleted, target.existing.len());
+        }
+    }
+}
+
+fn existing_values(target: &Target) -> String {
+    match target.existing.as_slice() {
+        [] => "-".to_string(),
+        points => points
+            .iter()
+            .map(|point| {
+                point
+                    .value
+                    .map_or_else(|| "?".to_string(), |value| value.to_string())
+            })
+            .collect::<Vec<_>>()
+            .join(","),
+    }
+}
+
+fn format_preview_table<'a>(targets: impl IntoIterator<Item = &'a Target>) -> String {
+    let headers = [
+        "goal".to_string(),
+        "date".to_string(),
+        "target".to_string(),
+        "existing".to_string(),
+        "action".to_string(),
+    ];
+    let rows: Vec<[String; 5]> = targets
+        .into_iter()
+        .map(|target| {
+            [
+                target.goal.clone(),
+                target.date.to_string(),
+                target.value.to_string(),
+                existing_values(target),
+                target_action(target).to_string(),
+            ]
+        })
+        .collect();
+
+    let widths = (0..5)
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].chars().count())
+                .chain(std::iter::once(headers[column].chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+    let render = |row: &[String; 5]| {
+        format!(
+            "  {:<goal_width$} | {:<date_width$} | {:>target_width$} | {:>existing_width$} | {}\n",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            row[4],
+            goal_width = widths[0],
+            date_width = widths[1],
+            target_width = widths[2],
+            existing_width = widths[3],
+        )
+    };
+    let separator = [
+        "-".repeat(widths[0]),
+        "-".repeat(widths[1]),
+        "-".repeat(widths[2]),
+        "-".repeat(widths[3]),
+        "-".repeat(widths[4]),
+    ];
+
+    let mut output = render(&headers);
+    output.push_str(&format!(
+        "  {} | {} | {} | {} | {}\n",
+        separator[0], separator[1], separator[2], separator[3], separator[4]
+    ));
+    for row in &rows {
+        output.push_str(&render(row));
+    }
+    output
+}
+
+fn pluralized(count: usize, singular: &str, plural: &str) -> String {
+    format!("{count} {}", if count == 1 { singular } else { plural })
+}
+
+fn format_apply_plan(targets: &[Target]) -> String {
+    let mutations: Vec<&Target> = targets
+        .iter()
+        .filter(|target| !target_is_unchanged(target))
+        .collect();
+    let unchanged = targets.len() - mutations.len();
+
+    if mutations.is_empty() {
+        let goals = targets
+            .iter()
+            .map(|target| target.goal.as_str())
+            .collect::<HashSet<_>>()
+            .len();
+        return format!(
+            "  ✅ already in sync — {} checked across {}\n",
+            pluralized(targets.len(), "datapoint", "datapoints"),
+            pluralized(goals, "goal", "goals")
+        );
+    }
+
+    let mut output = format_preview_table(mutations.iter().copied());
+    output.push_str(&format!(
+        "  {}{}\n",
+        pluralized(mutations.len(), "change", "changes"),
+        if unchanged == 0 {
+            String::new()
+        } else {
+            format!(
+                "; {} hidden",
+                pluralized(unchanged, "unchanged target", "unchanged targets")
+            )
+        }
+    ));
+    output
+}
+
+/// Writes one target and verifies it. Updates, creates (which carry the
+/// target's requestid) and the verifying read are retried; deletes are not.
+async fn apply_target(ctx: &SyncContext<'_>, target: &Target) -> Result<()> {
+    let client = ctx.beeminder;
+    debug!(
+        goal = %target.goal,
+        date = %target.date,
+        value = target.value,
+        existing = target.existing.len(),
+        "applying target"
+    );
+    let (keeper_id, keeper_requestid) = if let Some(keeper) = target.keeper() {
+        if needs_update(keeper, target) {
+            let update = UpdateDatapoint::new(keeper.id.clone())
+                .with_value(target.value)
+                .with_comment(&target.comment);
+            ctx.retry("updating a Beeminder datapoint", || {
+                client.update_datapoint(&target.goal, &update)
+            })
+            .await?;
+        }
+        (keeper.id.clone(), keeper.requestid.as_deref())
+    } else {
+        let datapoint = CreateDatapoint {
+            value: target.value,
+            timestamp: None,
+            daystamp: Some(daystamp(target.date)),
+            comment: Some(target.comment.clone()),
+            requestid: Some(target.requestid.clone()),
+        };
+        let created = ctx
+            .retry("creating a Beeminder datapoint", || {
+                client.create_datapoint(&target.goal, &datapoint)
+            })
+            .await?;
+        (created.id, Some(target.requestid.as_str()))
+    };
+
+    for extra in target.existing.iter().filter(|point| point.id != keeper_id) {
+        client.delete_datapoint(&target.goal, &extra.id).await?;
+    }
+
+    let stamp = daystamp(target.date);
+    let current: Vec<DatapointFull> = ctx
+        .retry("verifying Beeminder datapoints", || {
+            client.get_datapoints_full(&target.goal, None, None, None, None)
+        })
+        .await?
+        .into_iter()
+        .filter(|point| point.daystamp == stamp)
+        .collect();
+    if current.len() != 1
+        || current[0].id != keeper_id
+        || current[0].requestid.as_deref() != keeper_requestid
+        || !same_value(current[0].value, target.value)
+        || current[0].is_dummy.unwrap_or(false)
+        || current[0].is_initial.unwrap_or(false)
+    {
+        bail!(
+            "post-write verification failed for {} {}",
+            target.goal,
+            target.date
+        )
+    }
+    Ok(())
+}
+
+/// Tracks the apply loop, so that a run stopped part way, by an error or by
+/// the module timeout cancelling it, says what it left behind.
+struct ApplyProgress<'a, 'c> {
+    ctx: &'a SyncContext<'c>,
+    total: usize,
+    verified: usize,
+    /// The target being written, until it is verified.
+    current: Option<&'a Target>,
+}
+
+impl Drop for ApplyProgress<'_, '_> {
+    fn drop(&mut self) {
+        if let Some(target) = self.current {
+            report!(
+                self.ctx,
+                "  ⚠️ stopped while applying {} {}, which may be partly written and is unverified; \
+                 {} of {} verified. Rerun to finish.",
+                target.goal,
+                target.date,
+                self.verified,
+                pluralized(self.total, "change", "changes")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::date;
+
+    fn kept(date: Date) -> Target {
+        Target {
+            goal: "free".into(),
+            date,
+            value: 1.0,
+            comment: "beesync/daylio: non-user present".into(),
+            requestid: "canonical".into(),
+            adopt_unkeyed: false,
+            existing: vec![ExistingPoint {
+                id: "existing".into(),
+                value: Some(1.0),
+                comment: Some("beesync/daylio: non-user present".into()),
+                requestid: Some("canonical".into()),
+                system: false,
+            }],
+        }
+    }
+
+    fn created(date: Date) -> Target {
+        Target {
+            goal: "free".into(),
+            date,
+            value: 1.0,
+            comment: "beesync/daylio: non-user present".into(),
+            requestid: "new".into(),
+            adopt_unkeyed: false,
+            existing: vec![],
+        }
+    }
+
+    fn desired(goal: &str, date: Date) -> Desired {
+        Desired {
+            goal: goal.into(),
+            date,
+            value: 1.0,
+            comment: "clean".into(),
+            requestid: format!("test:{}", daystamp(date)),
+            adopt_unkeyed: false,
+        }
+    }
+
+    fn point(id: &str, requestid: Option<&str>, system: bool) -> ExistingPoint {
+        ExistingPoint {
+            id: id.into(),
+            value: Some(1.0),
+            comment: None,
+            requestid: requestid.map(Into::into),
+            system,
+        }
+    }
+
+    #[test]
+    fn plan_pairs_each_day_with_its_existing_points() {
+        let existing = Existing::from([(
+            "goal".into(),
+            HashMap::from([
+                ("20260804".into(), vec![point("a", None, false)]),
+                ("20260805".into(), vec![point("b", None, false)]),
+            ]),
+        )]);
+
+        let targets = plan(
+            vec![
+                desired("goal", date!(2026 - 08 - 04)),
+                desired("other", date!(2026 - 08 - 04)),
+            ],
+            existing,
+        )
+        .unwrap();
+
+        assert_eq!(targets[0].existing.len(), 1);
+        assert_eq!(targets[0].existing[0].id, "a");
+        assert!(targets[1].existing.is_empty());
+        assert_eq!(target_action(&targets[0]), "♻️ replace");
+        assert_eq!(target_action(&targets[1]), "➕ create");
+    }
+
+    #[test]
+    fn unkeyed_points_are_adopted_only_when_asked() {
+        let target = |existing, adopt_unkeyed| Target {
+            existing,
+            adopt_unkeyed,
+            ..created(date!(2026 - 08 - 04))
+        };
+        let points = || vec![point("a", Some("other"), false), point("b", None, false)];
+
+        let adopting = target(points(), true);
+        let replacing = target(points(), false);
+        let foreign = target(vec![point("a", Some("other"), false)], true);
+
+        assert_eq!(adopting.keeper().unwrap().id, "b");
+        assert_eq!(target_action(&adopting), "🧹 update+prune");
+        assert!(replacing.keeper().is_none());
+        assert_eq!(target_action(&replacing), "♻️ replace");
+        assert!(foreign.keeper().is_none());
+        assert_eq!(target_action(&foreign), "♻️ replace");
+    }
+
+    #[test]
+    fn plan_refuses_days_it_cannot_safely_rewrite() {
+        let day = date!(2026 - 08 - 04);
+        let with = |points| {
+            Existing::from([("goal".into(), HashMap::from([("20260804".into(), points)]))])
+        };
+
+        let system = plan(
+            vec![desired("goal", day)],
+            with(vec![point("a", None, true)]),
+        );
+        let twice = plan(
+            vec![desired("goal", day)],
+            with(vec![
+                point("a", Some("test:20260804"), false),
+                point("b", Some("test:20260804"), false),
+            ]),
+        );
+
+        assert!(system.unwrap_err().to_string().contains("system datapoint"));
+        assert!(twice
+            .unwrap_err()
+            .to_string()
+            .contains("multiple canonical datapoints"));
+    }
+
+    #[test]
+    fn previews_only_count_changes_in_a_dry_run() {
+        let beeminder =
+            crate::backend::Beeminder::Api(beeminder::BeeminderClient::new(String::new()));
+        let state = crate::state::StateStore::in_memory();
+        let timezone = crate::timezone::TimeZone::utc();
+        let tally = |dry_run| {
+            let ctx = SyncContext::new(
+                &beeminder,
+                &state,
+                "daylio",
+                date!(2026 - 08 - 10),
+                &timezone,
+                dry_run,
+                crate::retry::RetryPolicy::default(),
+            );
+            preview(&ctx, &[created(date!(2026 - 08 - 09))]);
+            ctx.tally()
+        };
+
+        assert_eq!(tally(false), crate::report::Tally::default());
+        assert_eq!(tally(true).created, 1);
+    }
+
+    #[test]
+    fn interrupted_apply_reports_the_unverified_target() {
+        let beeminder =
+            crate::backend::Beeminder::Api(beeminder::BeeminderClient::new(String::new()));
+        let state = crate::state::StateStore::in_memory();
+        let retry = crate::retry::RetryPolicy::default();
+        let timezone = crate::timezone::TimeZone::utc();
+        let ctx = SyncContext::new(
+            &beeminder,
+            &state,
+            "daylio",
+            date!(2026 - 08 - 10),
+            &timezone,
+            false,
+            retry,
+        );
+        let target = created(date!(2026 - 08 - 09));
+
+        drop(ApplyProgress {
+            ctx: &ctx,
+            total: 3,
+            verified: 1,
+            current: Some(&target),
+        });
+
+        assert_eq!(
+            ctx.take_output(),
+            "  ⚠️ stopped while applying free 2026-08-09, which may be partly written and is \
+             unverified; 1 of 3 changes verified. Rerun to finish.\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn reconciling_again_against_a_local_goal_changes_nothing() {
+        let dir = std::env::temp_dir().join(format!("beesync-reconcile-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let local = crate::local_backend::LocalBeeminder::open(&dir).unwrap();
+        local
+            .create_goal("clean", crate::goals::GoalSettings::default())
+            .unwrap();
+        let beeminder = crate::backend::Beeminder::Local(local);
+        let state = crate::state::StateStore::in_memory();
+        let timezone = crate::timezone::TimeZone::utc();
+        let ctx = SyncContext::new(
+            &beeminder,
+            &state,
+            "clean_view",
+            date!(2026 - 08 - 10),
+            &timezone,
+            false,
+            crate::retry::RetryPolicy::default(),
+        );
+        let day = date!(2026 - 08 - 09);
+
+        reconcile(&ctx, vec![desired("clean", day)]).await.unwrap();
+        let first = beeminder
+            .get_datapoints_full("clean", None, None, None, None)
+            .await
+            .unwrap();
+        reconcile(&ctx, vec![desired("clean", day)]).await.unwrap();
+        let second = beeminder
+            .get_datapoints_full("clean", None, None, None, None)
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].daystamp, "20260809");
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].id, first[0].id);
+        assert_eq!(second[0].updated_at, first[0].updated_at);
+    }
+
+    #[tokio::test]
+    async fn unkeyed_datapoint_is_adopted_rather_than_recreated() {
+        let dir = std::env::temp_dir().join(format!("beesync-adopt-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let local = crate::local_backend::LocalBeeminder::open(&dir).unwrap();
+        local
+            .create_goal("clean", crate::goals::GoalSettings::default())
+            .unwrap();
+        let beeminder = crate::backend::Beeminder::Local(local);
+        let state = crate::state::StateStore::in_memory();
+        let timezone = crate::timezone::TimeZone::utc();
+        let ctx = SyncContext::new(
+            &beeminder,
+            &state,
+            "clean_view",
+            date!(2026 - 08 - 10),
+            &timezone,
+            false,
+            crate::retry::RetryPolicy::default(),
+        );
+        let day = date!(2026 - 08 - 09);
+        let adopting = || Desired {
+            adopt_unkeyed: true,
+            ..desired("clean", day)
+        };
+        let manual = beeminder
+            .create_datapoint(
+                "clean",
+                &CreateDatapoint {
+                    value: 0.0,
+                    timestamp: None,
+                    daystamp: Some(daystamp(day)),
+                    comment: Some("by hand".into()),
+                    requestid: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        reconcile(&ctx, vec![adopting()]).await.unwrap();
+        let first = beeminder
+            .get_datapoints_full("clean", None, None, None, None)
+            .await
+            .unwrap();
+        reconcile(&ctx, vec![adopting()]).await.unwrap();
+        let second = beeminder
+            .get_datapoints_full("clean", None, None, None, None)
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].id, manual.id);
+        assert_eq!(first[0].value, Some(1.0));
+        assert_eq!(first[0].comment.as_deref(), Some("clean"));
+        assert_eq!(second[0].updated_at, first[0].updated_at);
+        assert_eq!(
+            ctx.tally(),
+            crate::report::Tally {
+                updated: 1,
+                skipped: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn preview_table_aligns_columns_and_separates_header() {
+        let targets = vec![kept(date!(2026 - 08 - 07)), created(date!(2026 - 08 - 10))];
+
+        assert_eq!(
+            format_preview_table(&targets),
+            concat!(
+                "  goal | date       | target | existing | action\n",
+                "  ---- | ---------- | ------ | -------- | --------\n",
+                "  free | 2026-08-07 |      1 |        1 | ✅ keep\n",
+                "  free | 2026-08-10 |      1 |        - | ➕ create\n"
+            )
+        );
+    }
+
+    #[test]
+    fn preview_table_shows_existing_values_instead_of_point_counts() {
+        let target = Target {
+            goal: "clean-twitch".into(),
+            date: date!(2026 - 08 - 08),
+            value: 0.0,
+            comment: "beesync/daylio: non-user absent (authoritative)".into(),
+            requestid: "canonical".into(),
+            adopt_unkeyed: false,
+            existing: vec![ExistingPoint {
+                id: "existing".into(),
+                value: Some(0.0),
+                comment: None,
+                requestid: None,
+                system: false,
+            }],
+        };
+
+        let table = format_preview_table(&[target]);
+
+        assert!(table.contains("clean-twitch | 2026-08-08 |      0 |        0 | ♻️ replace"));
+    }
+
+    #[test]
+    fn apply_plan_hides_unchanged_targets() {
+        let targets = vec![kept(date!(2026 - 08 - 07)), created(date!(2026 - 08 - 10))];
+
+        let output = format_apply_plan(&targets);
+
+        assert!(!output.contains("2026-08-07"));
+        assert!(output.contains("2026-08-10"));
+        assert!(output.contains("1 change; 1 unchanged target hidden"));
+    }
+
+    #[test]
+    fn apply_plan_collapses_when_everything_is_unchanged() {
+        let targets = vec![kept(date!(2026 - 08 - 07))];
+
+        assert_eq!(
+            format_apply_plan(&targets),
+            "  ✅ already in sync — 1 datapoint checked across 1 goal\n"
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use crate::secret::redact;
+use serde::Serialize;
+use std::{fmt, time::Duration};
+
+/// Exit status when the configuration cannot be loaded or is invalid
+/// (`EX_CONFIG` from sysexits.h).
+pub const EXIT_CONFIG: u8 = 78;
+/// Exit status when the run completed but at least one module failed.
+pub const EXIT_SYNC_FAILED: u8 = 1;
+/// Exit status when another run holds the lock and `on_conflict = "fail"`
+/// (`EX_TEMPFAIL` from sysexits.h).
+pub const EXIT_LOCKED: u8 = 75;
+/// Exit status when `goals sync` finds goals that differ from their
+/// definitions or are missing from them. Not 2, which is a usage error.
+pub const EXIT_DRIFT: u8 = 3;
+
+/// Kinds of datapoint changes a module tallies while it syncs.
+#[derive(Clone, Copy, Debug)]
+pub enum Change {
+    Created,
+    Updated,
+    Deleted,
+    /// A source item that was already in sync.
+    Skipped,
+}
+
+/// Datapoint changes made by one module; planned changes in a dry run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Tally {
+    pub created: usize,
+    pub updated: usize,
+    pub deleted: usize,
+    pub skipped: usize,
+    /// Source items the module read, whether or not they needed syncing.
+    pub seen: usize,
+}
+
+impl Tally {
+    pub fn add(&mut self, change: Change, count: usize) {
+        let field = match change {
+            Change::Created => &mut self.created,
+            Change::Updated => &mut self.updated,
+            Change::Deleted => &mut self.deleted,
+            Change::Skipped => &mut self.skipped,
+        };
+        *field += count;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Failed,
+    /// Another beesync run held the lock and `on_conflict = "skip"`.
+    Skipped,
+    /// The module was cancelled after its timeout; a failure too.
+    TimedOut,
+}
+
+/// A module was cancelled because it synced longer than its timeout.
+#[derive(Debug)]
+pub struct TimedOut(pub Duration);
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out after {}", humantime::format_duration(self.0))
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+#[derive(Debug, Serialize)]
+pub struct ModuleReport {
+    pub module: &'static str,
+    /// Name of the `[[module]]` instance, if it has one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    pub status: Status,
+    #[serde(flatten)]
+    pub tally: Tally,
+    pub duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ModuleReport {
+    pub fn new(
+        module: &'static str,
+        instance: Option<String>,
+        tally: Tally,
+        duration: Duration,
+        result: &anyhow::Result<()>,
+    ) -> Self {
+        Self {
+            module,
+            instance,
+            status: match result {
+                Ok(()) => Status::Ok,
+                Err(e) if e.is::<TimedOut>() => Status::TimedOut,
+                Err(_) => Status::Failed,
+            },
+            tally,
+            duration_ms: duration.as_millis(),
+            error: result
+                .as_ref()
+                .err()
+                .map(|e| redact(&format!("{e:#}")).into_owned()),
+        }
+    }
+
+    /// A module that did not sync because another run held its lock.
+    pub fn skipped(module: &'static str, instance: Option<String>) -> Self {
+        Self {
+            module,
+            instance,
+            status: Status::Skipped,
+            tally: Tally::default(),
+            duration_ms: 0,
+            error: None,
+        }
+    }
+}
+
+/// Outcome of a whole run, emitted with `--report json`.
+#[derive(Debug, Serialize)]
+pub struct RunReport {
+    pub status: Status,
+    pub dry_run: bool,
+    pub duration_ms: u128,
+    pub modules: Vec<ModuleReport>,
+}
+
+impl RunReport {
+    pub fn new(dry_run: bool, duration: Duration, modules: Vec<ModuleReport>) -> Self {
+        let status = if modules
+            .iter()
+            .any(|module| matches!(module.status, Status::Failed | Status::TimedOut))
+        {
+            Status::Failed
+        } else {
+            Status::Ok
+        };
+        Self {
+            status,
+            dry_run,
+            duration_ms: duration.as_millis(),
+            modules,
+        }
+    }
+
+    /// A run that did not start because another run held the lock.
+    pub const fn skipped(dry_run: bool, duration: Duration) -> Self {
+        Self {
+            status: Status::Skipped,
+            dry_run,
+            duration_ms: duration.as_millis(),
+            modules: Vec::new(),
+        }
+    }
+
+    pub const fn exit_code(&self) -> u8 {
+        match self.status {
+            Status::Ok | Status::Skipped => 0,
+            Status::Failed | Status::TimedOut => EXIT_SYNC_FAILED,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_module_fails_the_run() {
+        let mut tally = Tally::default();
+        tally.add(Change::Created, 2);
+        let modules = vec![
+            ModuleReport::new(
+                "github",
+                Some("work".to_string()),
+                tally,
+                Duration::from_millis(1500),
+                &Ok(()),
+            ),
+            ModuleReport::new(
+                "daylio",
+                None,
+                Tally::default(),
+                Duration::from_millis(20),
+                &Err(anyhow::anyhow!("stale export")),
+            ),
+        ];
+
+        let report = RunReport::new(false, Duration::from_secs(2), modules);
+
+        assert_eq!(report.exit_code(), EXIT_SYNC_FAILED);
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "status": "failed",
+                "dry_run": false,
+                "duration_ms": 2000,
+                "modules": [
+                    {
+                        "module": "github",
+                        "instance": "work",
+                        "status": "ok",
+                        "created": 2,
+                        "updated": 0,
+                        "deleted": 0,
+                        "skipped": 0,
+                        "seen": 0,
+                        "duration_ms": 1500
+                    },
+                    {
+                        "module": "daylio",
+                        "status": "failed",
+                        "created": 0,
+                        "updated": 0,
+                        "deleted": 0,
+                        "skipped": 0,
+                        "seen": 0,
+                        "duration_ms": 20,
+                        "error": "stale export"
+                    }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn run_without_failures_exits_cleanly() {
+        let modules = vec![ModuleReport::new(
+            "github",
+            None,
+            Tally::default(),
+            Duration::ZERO,
+            &Ok(()),
+        )];
+
+        assert_eq!(RunReport::new(true, Duration::ZERO, modules).exit_code(), 0);
+    }
+
+    #[test]
+    fn skipped_modules_do_not_fail_the_run() {
+        let modules = vec![
+            ModuleReport::new("github", None, Tally::default(), Duration::ZERO, &Ok(())),
+            ModuleReport::skipped("daylio", None),
+        ];
+
+        let report = RunReport::new(false, Duration::ZERO, modules);
+
+        assert_eq!(report.status, Status::Ok);
+        assert_eq!(report.exit_code(), 0);
+        assert_eq!(RunReport::skipped(false, Duration::ZERO).exit_code(), 0);
+    }
+}
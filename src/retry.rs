@@ -0,0 +1,342 @@
+use crate::config::duration;
+use serde::Deserialize;
+use std::{
+    collections::hash_map::RandomState, future::Future, hash::BuildHasher, num::NonZeroU32,
+    time::Duration,
+};
+
+/// Retry settings from the global `[retry]` table or a module's `retry` key.
+/// Unset fields fall back to the global table, then to the defaults.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Attempts per call, including the first; 1 disables retries.
+    pub attempts: Option<NonZeroU32>,
+    /// Delay before the first retry, doubled for each further one.
+    #[serde(default, deserialize_with = "duration")]
+    pub backoff: Option<Duration>,
+    /// Longest delay between attempts. A server asking to wait longer
+    /// (`Retry-After`) is not retried at all.
+    #[serde(default, deserialize_with = "duration")]
+    pub max_delay: Option<Duration>,
+}
+
+impl RetryConfig {
+    /// These settings, with unset fields taken from `fallback`.
+    pub fn or(&self, fallback: &Self) -> Self {
+        Self {
+            attempts: self.attempts.or(fallback.attempts),
+            backoff: self.backoff.or(fallback.backoff),
+            max_delay: self.max_delay.or(fallback.max_delay),
+        }
+    }
+
+    pub fn policy(&self) -> RetryPolicy {
+        let default = RetryPolicy::default();
+        RetryPolicy {
+            attempts: self.attempts.map_or(default.attempts, NonZeroU32::get),
+            backoff: self.backoff.unwrap_or(default.backoff),
+            max_delay: self.max_delay.unwrap_or(default.max_delay),
+        }
+    }
+}
+
+/// Whether a failed call may succeed if made again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    Permanent,
+    /// A timeout, connection failure, rate limit or server error.
+    Transient {
+        /// How long the server asked to wait, from `Retry-After`.
+        retry_after: Option<Duration>,
+    },
+}
+
+impl Failure {
+    /// Classifies `error` by the first HTTP failure in its chain of causes.
+    pub fn of(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(failure) = classify(cause) {
+                return failure;
+            }
+        }
+        Self::Permanent
+    }
+
+    fn from_status(status: u16, retry_after: Option<&str>) -> Self {
+        match status {
+            408 | 425 | 429 | 500 | 502 | 503 | 504 => Self::Transient {
+                retry_after: retry_after.and_then(parse_retry_after),
+            },
+            _ => Self::Permanent,
+        }
+    }
+}
+
+fn classify(cause: &(dyn std::error::Error + 'static)) -> Option<Failure> {
+    if let Some(error) = cause.downcast_ref::<reqwest::Error>() {
+        if let Some(status) = error.status() {
+            return Some(Failure::from_status(status.as_u16(), None));
+        }
+        return Some(
+            if error.is_timeout() || error.is_connect() || error.is_request() {
+                Failure::Transient { retry_after: None }
+            } else {
+                Failure::Permanent
+            },
+        );
+    }
+    if let Some(beeminder::Error::HttpStatus { status, .. }) = cause.downcast_ref() {
+        return Some(Failure::from_status(*status, None));
+    }
+    if let Some(github_light::Error::Api {
+        status,
+        retry_after,
+        ..
+    }) = cause.downcast_ref()
+    {
+        return Some(Failure::from_status(*status, retry_after.as_deref()));
+    }
+    if let Some(amazing_marvin_light::Error::Api {
+        status,
+        retry_after,
+        ..
+    }) = cause.downcast_ref()
+    {
+        return Some(Failure::from_status(*status, retry_after.as_deref()));
+    }
+    if let Some(error) = cause.downcast_ref::<llm::HttpStatus>() {
+        return Some(Failure::from_status(
+            error.status,
+            error.retry_after.as_deref(),
+        ));
+    }
+    None
+}
+
+/// `Retry-After` in seconds. HTTP dates are rare for the services beesync
+/// talks to and fall back to the normal backoff.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// How often and how patiently a call is retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    attempts: u32,
+    backoff: Duration,
+    max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Calls `call` until it succeeds, fails permanently or runs out of
+    /// attempts, and returns the last result. Only use this for calls that
+    /// are safe to repeat: reads, updates and creates with a requestid.
+    pub async fn run<T, E, F, Fut>(&self, what: &str, mut call: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Into<anyhow::Error>,
+    {
+        let mut attempt = 1;
+        loop {
+            let error = match call().await {
+                Ok(value) => return Ok(value),
+                Err(e) => e.into(),
+            };
+            let Some(delay) = self.delay(attempt, Failure::of(&error)) else {
+                return Err(error);
+            };
+            tracing::warn!(
+                "{what} failed (attempt {attempt} of {}), retrying in {}: {error:#}",
+                self.attempts,
+                humantime::format_duration(delay)
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// How long to wait after failed attempt `attempt` (counting from 1), or
+    /// `None` to give up.
+    fn delay(&self, attempt: u32, failure: Failure) -> Option<Duration> {
+        let Failure::Transient { retry_after } = failure else {
+            return None;
+        };
+        if attempt >= self.attempts {
+            return None;
+        }
+        let backoff = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_delay);
+        // Jitter spreads out retries from modules that failed together.
+        let jittered = backoff.mul_f64(0.5 + 0.5 * random_fraction());
+        match retry_after {
+            Some(wait) if wait > self.max_delay => None,
+            Some(wait) => Some(wait.max(jittered)),
+            None => Some(jittered),
+        }
+    }
+}
+
+/// A number in `[0, 1)`, random enough for jitter.
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().hash_one(std::time::SystemTime::now()) >> 11;
+    bits as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const TRANSIENT: Failure = Failure::Transient { retry_after: None };
+
+    fn policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            backoff: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_limit() {
+        let policy = policy(10);
+        for (attempt, full) in [(1, 100), (2, 200), (3, 300), (6, 300)] {
+            let delay = policy.delay(attempt, TRANSIENT).unwrap();
+            let full = Duration::from_millis(full);
+            assert!(
+                delay >= full / 2 && delay <= full,
+                "{delay:?} for {attempt}"
+            );
+        }
+        assert_eq!(policy.delay(10, TRANSIENT), None);
+        assert_eq!(policy.delay(1, Failure::Permanent), None);
+    }
+
+    #[test]
+    fn retry_after_is_honored_unless_too_long() {
+        let policy = policy(3);
+        let after = |secs: f64| Failure::Transient {
+            retry_after: Some(Duration::from_secs_f64(secs)),
+        };
+        assert_eq!(
+            policy.delay(1, after(0.25)),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(policy.delay(1, after(60.0)), None);
+    }
+
+    #[test]
+    fn http_failures_are_classified_by_status() {
+        let beeminder = |status| {
+            anyhow::Error::from(beeminder::Error::HttpStatus {
+                status,
+                reason: String::new(),
+                body: String::new(),
+            })
+        };
+        assert_eq!(Failure::of(&beeminder(503)), TRANSIENT);
+        assert_eq!(Failure::of(&beeminder(422)), Failure::Permanent);
+
+        let github = anyhow::Error::from(github_light::Error::Api {
+            status: 429,
+            message: "slow down".to_string(),
+            retry_after: Some("7".to_string()),
+        })
+        .context("fetching commits");
+        assert_eq!(
+            Failure::of(&github),
+            Failure::Transient {
+                retry_after: Some(Duration::from_secs(7))
+            }
+        );
+
+        let llm = anyhow::Error::new(llm::HttpStatus {
+            status: 502,
+            retry_after: None,
+        })
+        .context("OpenRouter request failed");
+        assert_eq!(Failure::of(&llm), TRANSIENT);
+
+        assert_eq!(
+            Failure::of(&anyhow::anyhow!("bad config")),
+            Failure::Permanent
+        );
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried_until_success() {
+        let calls = AtomicU32::new(0);
+        let result = policy(3)
+            .run("test call", || async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(llm::HttpStatus {
+                        status: 503,
+                        retry_after: None,
+                    }),
+                    n => Ok(n),
+                }
+            })
+            .await;
+
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn permanent_failures_and_exhausted_attempts_give_up() {
+        let calls = AtomicU32::new(0);
+        let result: anyhow::Result<()> = policy(3)
+            .run("test call", || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(anyhow::anyhow!("bad request"))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let calls = AtomicU32::new(0);
+        let result: anyhow::Result<()> = policy(2)
+            .run("test call", || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(llm::HttpStatus {
+                    status: 500,
+                    retry_after: None,
+                })
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn module_settings_fall_back_to_global_ones() {
+        let global: RetryConfig = toml::from_str("attempts = 5\nbackoff = \"2s\"").unwrap();
+        let module: RetryConfig = toml::from_str("backoff = \"500ms\"").unwrap();
+
+        let policy = module.or(&global).policy();
+
+        assert_eq!(
+            policy,
+            RetryPolicy {
+                attempts: 5,
+                backoff: Duration::from_millis(500),
+                max_delay: Duration::from_secs(60),
+            }
+        );
+        assert!(toml::from_str::<RetryConfig>("backof = \"1s\"").is_err());
+    }
+}
@@ -0,0 +1,567 @@
+use crate::backend::Beeminder;
+use crate::key::RunKeys;
+use crate::lock::LockDir;
+use crate::logging::{Verbosity, OUTPUT};
+use crate::metrics::MetricsFile;
+use crate::module::{Module, SyncContext};
+use crate::notify::Notifier;
+use crate::report::{ModuleReport, Status, Tally, TimedOut};
+use crate::retry::RetryConfig;
+use crate::secret::redact;
+use crate::state::StateStore;
+use crate::timezone::TimeZone;
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use time::{Date, OffsetDateTime};
+use tokio::{
+    sync::{Mutex, Semaphore},
+    task::JoinSet,
+};
+use tracing::Instrument;
+
+/// How long a module may sync when neither it nor the config sets a timeout.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Runs modules concurrently on the tokio runtime.
+///
+/// At most `concurrency` modules sync at once, and modules that write to a
+/// common goal never overlap. Each module's output is buffered and printed as
+/// one block when it finishes, so concurrent modules do not interleave, and
+/// each module syncs within a `module` tracing span.
+///
+/// The limit and the goal locks hold across calls to [`Runner::run`], which
+/// the daemon makes once per scheduled module run. With
+/// [`Runner::with_locks`], each module also takes its lock file, so other
+/// beesync processes do not sync it at the same time.
+pub struct Runner {
+    beeminder: Arc<Beeminder>,
+    state: Arc<StateStore>,
+    dry_run: bool,
+    /// Print module output to stderr, keeping stdout free for a
+    /// machine-readable report.
+    output_to_stderr: bool,
+    /// Only print the output of failed modules.
+    quiet: bool,
+    permits: Arc<Semaphore>,
+    goal_locks: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    file_locks: Option<Arc<LockDir>>,
+    /// The global `[retry]` settings, which modules' own settings override.
+    retry: RetryConfig,
+    notifier: Option<Arc<Notifier>>,
+    metrics: Option<MetricsFile>,
+    /// How long a module may sync unless it sets its own timeout.
+    timeout: Duration,
+    /// The zone deciding which day datapoints are for.
+    timezone: Arc<TimeZone>,
+}
+
+impl Runner {
+    pub fn new(
+        beeminder: Arc<Beeminder>,
+        state: StateStore,
+        concurrency: NonZeroUsize,
+        dry_run: bool,
+        output_to_stderr: bool,
+        verbosity: Verbosity,
+    ) -> Self {
+        Self {
+            beeminder,
+            state: Arc::new(state),
+            dry_run,
+            output_to_stderr,
+            quiet: verbosity.is_quiet(),
+            permits: Arc::new(Semaphore::new(concurrency.get())),
+            goal_locks: std::sync::Mutex::default(),
+            file_locks: None,
+            retry: RetryConfig::default(),
+            notifier: None,
+            metrics: None,
+            timeout: DEFAULT_TIMEOUT,
+            timezone: Arc::new(TimeZone::utc()),
+        }
+    }
+
+    /// Gives modules `timezone` to decide which day datapoints are for.
+    pub fn with_timezone(mut self, timezone: TimeZone) -> Self {
+        self.timezone = Arc::new(timezone);
+        self
+    }
+
+    /// Cancels modules that sync longer than `timeout`, unless they set
+    /// their own.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Rewrites `metrics` after each call to [`Runner::run`], unless this is
+    /// a dry run.
+    pub fn with_metrics(mut self, metrics: MetricsFile) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Sends each module's outcome to `notifier` once it finishes.
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(Arc::new(notifier));
+        self
+    }
+
+    /// Retries modules' calls with `retry` unless they set their own.
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Locks each module against other beesync processes before it syncs.
+    pub fn with_locks(mut self, locks: Arc<LockDir>) -> Self {
+        self.file_locks = Some(locks);
+        self
+    }
+
+    /// Locks for `goals`, in sorted order so that modules with overlapping
+    /// goals do not deadlock on each other.
+    fn goal_locks(&self, mut goals: Vec<String>) -> Vec<Arc<Mutex<()>>> {
+        goals.sort();
+        goals.dedup();
+        let mut goal_locks = self.goal_locks.lock().unwrap_or_else(|e| e.into_inner());
+        goals
+            .into_iter()
+            .map(|goal| Arc::clone(goal_locks.entry(goal).or_default()))
+            .collect()
+    }
+
+    /// Syncs `modules` and returns one report per module, in input order.
+    /// Keys are resolved afresh for each call; see [`RunKeys`].
+    pub async fn run(&self, modules: Vec<Arc<dyn Module>>, today: Date) -> Vec<ModuleReport> {
+        let run_started = Instant::now();
+        let keys = RunKeys::default();
+        let mut tasks = JoinSet::new();
+        let mut task_modules = HashMap::new();
+
+        for (index, module) in modules.into_iter().enumerate() {
+            let goal_locks = self.goal_locks(module.goals());
+            let file_locks = self.file_locks.clone();
+            let notifier = self.notifier.clone();
+            let retry = module.options().retry.or(&self.retry).policy();
+            let timeout = module.options().timeout.unwrap_or(self.timeout);
+            let permits = Arc::clone(&self.permits);
+            let beeminder = Arc::clone(&self.beeminder);
+            let state = Arc::clone(&self.state);
+            let timezone = Arc::clone(&self.timezone);
+            let (dry_run, to_stderr, quiet) = (self.dry_run, self.output_to_stderr, self.quiet);
+            let (name, instance, label) =
+                (module.name(), module.options().name.clone(), module.label());
+            let span = tracing::info_span!("module", module = %module.label(), dry_run);
+            let task = tasks.spawn(
+                keys.clone().scope(
+                    async move {
+                        let mut started = Instant::now();
+                        let label = module.label();
+                        let file_lock = match &file_locks {
+                            Some(file_locks) => match file_locks.module(&label).await {
+                                Ok(None) => {
+                                    if !quiet {
+                                        print_skipped(module.as_ref(), to_stderr);
+                                    }
+                                    let instance = module.options().name.clone();
+                                    return (index, ModuleReport::skipped(module.name(), instance));
+                                }
+                                lock => lock,
+                            },
+                            None => Ok(None),
+                        };
+
+                        let ctx = SyncContext::new(
+                            &beeminder, &state, &label, today, &timezone, dry_run, retry,
+                        )
+                        .with_goal_deadlines(module.options().goal_deadline);
+                        let result = match file_lock {
+                            Ok(_file_lock) => {
+                                let mut guards = Vec::with_capacity(goal_locks.len());
+                                for lock in goal_locks {
+                                    guards.push(lock.lock_owned().await);
+                                }
+                                let _permit = permits
+                                    .acquire_owned()
+                                    .await
+                                    .expect("the semaphore is never closed");
+
+                                tracing::debug!(%today, "syncing");
+                                started = Instant::now();
+                                // Dropping the sync future cancels it at its
+                                // current await point.
+                                match tokio::time::timeout(timeout, module.sync(&ctx)).await {
+                                    Ok(result) => result,
+                                    Err(_) => Err(TimedOut(timeout).into()),
+                                }
+                            }
+                            Err(e) => Err(e),
+                        };
+                        if !(quiet && result.is_ok()) {
+                            print_module(module.as_ref(), &ctx.take_output(), &result, to_stderr);
+                        }
+                        let report = ModuleReport::new(
+                            module.name(),
+                            module.options().name.clone(),
+                            ctx.tally(),
+                            started.elapsed(),
+                            &result,
+                        );
+                        log_module(&report, &result);
+                        if !dry_run {
+                            record_run(&state, &label, &report);
+                        }
+                        if let Some(notifier) = notifier {
+                            notifier.module_finished(&report).await;
+                        }
+                        (index, report)
+                    }
+                    .instrument(span),
+                ),
+            );
+            task_modules.insert(task.id(), (index, name, instance, label));
+        }
+
+        let mut reports = Vec::new();
+        while let Some(joined) = tasks.join_next().await {
+            match joined {
+                Ok(report) => reports.push(report),
+                Err(e) => {
+                    let (index, name, instance, label) = task_modules
+                        .remove(&e.id())
+                        .expect("every task is registered");
+                    tracing::error!(module = name, "module task aborted: {e}");
+                    let error = Err(anyhow!("module task aborted: {e}"));
+                    let report =
+                        ModuleReport::new(name, instance, Tally::default(), Duration::ZERO, &error);
+                    if !self.dry_run {
+                        record_run(&self.state, &label, &report);
+                    }
+                    if let Some(notifier) = &self.notifier {
+                        notifier.module_finished(&report).await;
+                    }
+                    reports.push((index, report));
+                }
+            }
+        }
+        reports.sort_by_key(|(index, _)| *index);
+
+        if let Some(metrics) = self.metrics.as_ref().filter(|_| !self.dry_run) {
+            if let Err(e) = metrics.write(&self.state, run_started.elapsed()) {
+                tracing::warn!("{e:#}");
+            }
+        }
+        reports.into_iter().map(|(_, report)| report).collect()
+    }
+}
+
+/// Records a module's run for the metrics file. Skipped modules did not run.
+fn record_run(state: &StateStore, label: &str, report: &ModuleReport) {
+    if report.status == Status::Skipped {
+        return;
+    }
+    let finished_at = OffsetDateTime::now_utc().unix_timestamp();
+    if let Err(e) = state.record_run(label, report, finished_at) {
+        tracing::warn!("recording the run in the state database failed: {e:#}");
+    }
+}
+
+fn log_module(report: &ModuleReport, result: &Result<()>) {
+    let tally = report.tally;
+    match result {
+        Ok(()) => tracing::info!(
+            target: OUTPUT,
+            created = tally.created,
+            updated = tally.updated,
+            deleted = tally.deleted,
+            skipped = tally.skipped,
+            seen = tally.seen,
+            duration_ms = report.duration_ms,
+            "completed successfully"
+        ),
+        Err(e) => tracing::error!(
+            target: OUTPUT,
+            duration_ms = report.duration_ms,
+            "failed: {e:#}"
+        ),
+    }
+}
+
+fn print_skipped(module: &dyn Module, to_stderr: bool) {
+    let line = format!(
+        "{} {}: ⏭️  skipped, another beesync run is syncing it",
+        module.emoji(),
+        module.title()
+    );
+    if to_stderr {
+        eprintln!("{line}");
+    } else {
+        println!("{line}");
+    }
+}
+
+fn print_module(module: &dyn Module, output: &str, result: &Result<()>, to_stderr: bool) {
+    let header = format!("{} {}", module.emoji(), module.title());
+    if to_stderr {
+        // Hold stderr for the whole block; `eprintln!` re-enters the same lock.
+        let _stderr = std::io::stderr().lock();
+        eprintln!("{header}");
+        eprint!("{output}");
+        match result {
+            Ok(()) => eprintln!("  ✅ completed successfully"),
+            Err(e) => eprintln!("  ❌ failed: {}", redact(&e.to_string())),
+        }
+        return;
+    }
+
+    // Hold stdout for the whole block; `println!` re-enters the same lock.
+    let _stdout = std::io::stdout().lock();
+    println!("{header}");
+    print!("{output}");
+    match result {
+        Ok(()) => println!("  ✅ completed successfully"),
+        Err(e) => eprintln!("  ❌ failed: {}", redact(&e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check::Check;
+    use crate::lock::OnConflict;
+    use crate::module::{report, BoxFuture, ModuleOptions};
+    use crate::report::Status;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static OPTIONS: ModuleOptions = ModuleOptions {
+        name: None,
+        enabled: true,
+        schedule: None,
+        retry: RetryConfig {
+            attempts: None,
+            backoff: None,
+            max_delay: None,
+        },
+        timeout: None,
+        goal_deadline: true,
+    };
+
+    /// Tracks how many probes are syncing at once.
+    #[derive(Default)]
+    struct Overlap {
+        active: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    struct Probe {
+        goal: &'static str,
+        overlap: Arc<Overlap>,
+    }
+
+    impl Module for Probe {
+        fn name(&self) -> &'static str {
+            "probe"
+        }
+
+        fn emoji(&self) -> &'static str {
+            "🧪"
+        }
+
+        fn options(&self) -> &ModuleOptions {
+            &OPTIONS
+        }
+
+        fn goals(&self) -> Vec<String> {
+            vec![self.goal.to_string()]
+        }
+
+        fn sync<'a>(&'a self, _ctx: &'a SyncContext<'a>) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                let active = self.overlap.active.fetch_add(1, Ordering::SeqCst) + 1;
+                self.overlap.peak.fetch_max(active, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                self.overlap.active.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            })
+        }
+
+        fn check(&self) -> BoxFuture<'_, Vec<Check>> {
+            Box::pin(async { Vec::new() })
+        }
+    }
+
+    struct Panics;
+
+    impl Module for Panics {
+        fn name(&self) -> &'static str {
+            "panics"
+        }
+
+        fn emoji(&self) -> &'static str {
+            "💥"
+        }
+
+        fn options(&self) -> &ModuleOptions {
+            &OPTIONS
+        }
+
+        fn goals(&self) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn sync<'a>(&'a self, _ctx: &'a SyncContext<'a>) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async { panic!("boom") })
+        }
+
+        fn check(&self) -> BoxFuture<'_, Vec<Check>> {
+            Box::pin(async { Vec::new() })
+        }
+    }
+
+    struct Hangs;
+
+    impl Module for Hangs {
+        fn name(&self) -> &'static str {
+            "hangs"
+        }
+
+        fn emoji(&self) -> &'static str {
+            "🐌"
+        }
+
+        fn options(&self) -> &ModuleOptions {
+            &OPTIONS
+        }
+
+        fn goals(&self) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn sync<'a>(&'a self, ctx: &'a SyncContext<'a>) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                report!(ctx, "  waiting for a server that never answers");
+                std::future::pending().await
+            })
+        }
+
+        fn check(&self) -> BoxFuture<'_, Vec<Check>> {
+            Box::pin(async { Vec::new() })
+        }
+    }
+
+    const TODAY: Date = time::macros::date!(2026 - 08 - 04);
+
+    fn runner(concurrency: usize) -> Runner {
+        Runner::new(
+            Arc::new(Beeminder::Api(beeminder::BeeminderClient::new(
+                String::new(),
+            ))),
+            StateStore::in_memory(),
+            NonZeroUsize::new(concurrency).unwrap(),
+            true,
+            true,
+            Verbosity::Normal,
+        )
+    }
+
+    async fn peak_overlap(goals: &[&'static str], concurrency: usize) -> usize {
+        let overlap = Arc::new(Overlap::default());
+        let modules = goals
+            .iter()
+            .map(|goal| {
+                Arc::new(Probe {
+                    goal,
+                    overlap: Arc::clone(&overlap),
+                }) as Arc<dyn Module>
+            })
+            .collect();
+        runner(concurrency).run(modules, TODAY).await;
+        overlap.peak.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn modules_with_distinct_goals_run_concurrently() {
+        assert_eq!(peak_overlap(&["a", "b", "c"], 4).await, 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrency_limit_is_respected() {
+        assert_eq!(peak_overlap(&["a", "b", "c"], 2).await, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn modules_sharing_a_goal_run_one_at_a_time() {
+        assert_eq!(peak_overlap(&["a", "a", "a"], 4).await, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn panicking_module_is_reported_as_failed() {
+        let overlap = Arc::new(Overlap::default());
+        let probe = |goal| {
+            Arc::new(Probe {
+                goal,
+                overlap: Arc::clone(&overlap),
+            }) as Arc<dyn Module>
+        };
+        let modules = vec![probe("a"), Arc::new(Panics), probe("b")];
+
+        let reports = runner(4).run(modules, TODAY).await;
+
+        let outcomes: Vec<_> = reports
+            .iter()
+            .map(|report| (report.module, report.status))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("probe", Status::Ok),
+                ("panics", Status::Failed),
+                ("probe", Status::Ok)
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn hung_module_times_out_and_the_run_continues() {
+        let probe = Arc::new(Probe {
+            goal: "a",
+            overlap: Arc::default(),
+        });
+        let modules = vec![Arc::new(Hangs) as Arc<dyn Module>, probe];
+
+        let reports = runner(4)
+            .with_timeout(Duration::from_millis(100))
+            .run(modules, TODAY)
+            .await;
+
+        assert_eq!(reports[0].status, Status::TimedOut);
+        assert_eq!(reports[0].error.as_deref(), Some("timed out after 100ms"));
+        assert_eq!(reports[1].status, Status::Ok);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn module_locked_by_another_run_is_skipped() {
+        let dir = std::env::temp_dir().join(format!("beesync-runner-lock-{}", std::process::id()));
+        let locks = Arc::new(LockDir::new(dir.clone(), OnConflict::Skip));
+        let held = locks.module("probe").await.unwrap().unwrap();
+        let probe = Arc::new(Probe {
+            goal: "a",
+            overlap: Arc::default(),
+        });
+
+        let runner = runner(4).with_locks(locks);
+        let skipped = runner.run(vec![probe.clone()], TODAY).await;
+        drop(held);
+        let synced = runner.run(vec![probe], TODAY).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(skipped[0].status, Status::Skipped);
+        assert_eq!(synced[0].status, Status::Ok);
+    }
+}
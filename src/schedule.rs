@@ -0,0 +1,309 @@
+use crate::timezone::TimeZone;
+use anyhow::{bail, Context, Result};
+use chrono::{Datelike, Timelike};
+use serde::Deserialize;
+use std::{fmt, time::Duration};
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
+
+/// When the daemon runs a module, set with the section's `schedule` key:
+/// `{ every = "15m" }` or `{ cron = "0 3 * * *" }`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "ScheduleSpec")]
+pub enum Schedule {
+    /// Runs at daemon start and then each time the interval has passed.
+    Every(Duration),
+    /// Runs at every minute the expression matches, on the clocks of the
+    /// configured time zone.
+    Cron(Cron),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScheduleSpec {
+    Every { every: String },
+    Cron { cron: String },
+}
+
+impl TryFrom<ScheduleSpec> for Schedule {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: ScheduleSpec) -> Result<Self> {
+        match spec {
+            ScheduleSpec::Every { every } => {
+                let interval = humantime::parse_duration(&every)
+                    .with_context(|| format!("invalid interval '{every}'"))?;
+                if interval < Duration::from_secs(1) {
+                    bail!("interval '{every}' is shorter than one second");
+                }
+                Ok(Self::Every(interval))
+            }
+            ScheduleSpec::Cron { cron } => Ok(Self::Cron(cron.parse()?)),
+        }
+    }
+}
+
+impl Schedule {
+    /// When a daemon started at `now` first runs the module: intervals run
+    /// straight away, cron schedules wait for their next match.
+    pub fn first_run(&self, now: OffsetDateTime, timezone: &TimeZone) -> Option<OffsetDateTime> {
+        match self {
+            Self::Every(_) => Some(now),
+            Self::Cron(cron) => cron.next_after(now, timezone),
+        }
+    }
+
+    /// The first time after `now` at which the module is due, or `None` if
+    /// the schedule never fires again.
+    pub fn next_after(&self, now: OffsetDateTime, timezone: &TimeZone) -> Option<OffsetDateTime> {
+        match self {
+            Self::Every(interval) => Some(now + *interval),
+            Self::Cron(cron) => cron.next_after(now, timezone),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Every(interval) => write!(f, "every {}", humantime::format_duration(*interval)),
+            Self::Cron(cron) => write!(f, "cron '{}'", cron.expression),
+        }
+    }
+}
+
+/// A five-field cron expression: minute, hour, day of month, month and day
+/// of week (0 or 7 is Sunday), as in crontab(5). When both day fields are
+/// restricted, a day matching either one is due.
+#[derive(Clone, Debug)]
+pub struct Cron {
+    expression: String,
+    /// Boxed, as it is large next to the other schedules.
+    pattern: Box<croner::Cron>,
+}
+
+impl PartialEq for Cron {
+    fn eq(&self, other: &Self) -> bool {
+        self.expression == other.expression
+    }
+}
+
+impl Eq for Cron {}
+
+impl std::str::FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        let expression = expression.split_whitespace().collect::<Vec<_>>().join(" ");
+        let pattern = croner::Cron::new(&expression)
+            .parse()
+            .with_context(|| format!("invalid cron expression '{expression}'"))?;
+        Ok(Self {
+            expression,
+            pattern: Box::new(pattern),
+        })
+    }
+}
+
+impl Cron {
+    /// The first moment strictly after `now` at which the clocks of
+    /// `timezone` show a matching minute.
+    ///
+    /// A minute skipped when the clocks go forward fires as they jump, and a
+    /// minute repeated when they go back fires only the first time.
+    pub fn next_after(&self, now: OffsetDateTime, timezone: &TimeZone) -> Option<OffsetDateTime> {
+        let local = timezone.local(now);
+        let mut after = PrimitiveDateTime::new(local.date(), local.time());
+        loop {
+            let next = self.next_local(after)?;
+            let at = timezone.resolve(next);
+            // In the second pass through a repeated hour, matches resolve to
+            // the first pass, which is over.
+            if at > now {
+                return Some(at);
+            }
+            after = next;
+        }
+    }
+
+    /// The first matching minute strictly after the wall-clock time `after`.
+    /// Wall-clock times are handed to croner as UTC, which has no gaps or
+    /// repeats of its own.
+    fn next_local(&self, after: PrimitiveDateTime) -> Option<PrimitiveDateTime> {
+        let after = chrono::NaiveDate::from_ymd_opt(
+            after.year(),
+            u32::from(u8::from(after.month())),
+            u32::from(after.day()),
+        )?
+        .and_hms_opt(
+            u32::from(after.hour()),
+            u32::from(after.minute()),
+            u32::from(after.second()),
+        )?
+        .and_utc();
+        let next = self.pattern.find_next_occurrence(&after, false).ok()?;
+        let date = Date::from_calendar_date(
+            next.year(),
+            Month::try_from(u8::try_from(next.month()).ok()?).ok()?,
+            u8::try_from(next.day()).ok()?,
+        )
+        .ok()?;
+        let time = Time::from_hms(
+            u8::try_from(next.hour()).ok()?,
+            u8::try_from(next.minute()).ok()?,
+            0,
+        )
+        .ok()?;
+        Some(PrimitiveDateTime::new(date, time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn schedule(section: toml::Table) -> Result<Schedule> {
+        Ok(toml::Value::Table(section).try_into()?)
+    }
+
+    fn cron(expression: &str) -> Cron {
+        expression.parse().unwrap()
+    }
+
+    #[test]
+    fn intervals_use_human_durations() {
+        let schedule = schedule(toml::toml! { every = "1h 30m" }).unwrap();
+
+        assert_eq!(schedule, Schedule::Every(Duration::from_secs(5400)));
+        assert_eq!(schedule.to_string(), "every 1h 30m");
+        assert_eq!(
+            schedule.next_after(datetime!(2026-08-04 10:00 UTC), &TimeZone::utc()),
+            Some(datetime!(2026-08-04 11:30 UTC))
+        );
+        assert_eq!(
+            schedule.first_run(datetime!(2026-08-04 10:00 UTC), &TimeZone::utc()),
+            Some(datetime!(2026-08-04 10:00 UTC))
+        );
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        assert!(schedule(toml::toml! { every = "often" }).is_err());
+        assert!(schedule(toml::toml! { every = "0s" }).is_err());
+        assert!(schedule(toml::toml! { cron = "0 3 * *" }).is_err());
+        assert!(schedule(toml::toml! { cron = "0 24 * * *" }).is_err());
+        assert!(schedule(toml::toml! { at = "03:00" }).is_err());
+    }
+
+    #[test]
+    fn daily_cron_fires_next_day_once_passed() {
+        let daily = cron("0 3 * * *");
+        let berlin = TimeZone::named("Europe/Berlin").unwrap();
+
+        assert_eq!(
+            daily.next_after(datetime!(2026-08-04 02:59:30 +02:00), &berlin),
+            Some(datetime!(2026-08-04 03:00 +02:00))
+        );
+        assert_eq!(
+            daily.next_after(datetime!(2026-08-04 03:00 +02:00), &berlin),
+            Some(datetime!(2026-08-05 03:00 +02:00))
+        );
+    }
+
+    #[test]
+    fn steps_ranges_and_lists() {
+        let working_hours = cron("*/15 9-17/4 * * 1-5");
+
+        // 2026-08-07 is a Friday.
+        assert_eq!(
+            working_hours.next_after(datetime!(2026-08-07 17:50 UTC), &TimeZone::utc()),
+            Some(datetime!(2026-08-10 09:00 UTC))
+        );
+        assert_eq!(
+            working_hours.next_after(datetime!(2026-08-10 13:20 UTC), &TimeZone::utc()),
+            Some(datetime!(2026-08-10 13:30 UTC))
+        );
+        assert_eq!(
+            cron("5,50 * * * *").next_after(datetime!(2026-08-10 13:20 UTC), &TimeZone::utc()),
+            Some(datetime!(2026-08-10 13:50 UTC))
+        );
+    }
+
+    #[test]
+    fn day_of_month_or_weekday_matches_when_both_are_set() {
+        // The 1st of the month, or any Sunday (7 is an alias for 0).
+        let schedule = cron("0 0 1 * 7");
+
+        assert_eq!(
+            schedule.next_after(datetime!(2026-08-04 12:00 UTC), &TimeZone::utc()),
+            Some(datetime!(2026-08-09 00:00 UTC))
+        );
+        assert_eq!(
+            schedule.next_after(datetime!(2026-08-30 12:00 UTC), &TimeZone::utc()),
+            Some(datetime!(2026-09-01 00:00 UTC))
+        );
+    }
+
+    #[test]
+    fn leap_day_and_impossible_dates() {
+        assert_eq!(
+            cron("0 0 29 2 *").next_after(datetime!(2026-03-01 00:00 UTC), &TimeZone::utc()),
+            Some(datetime!(2028-02-29 00:00 UTC))
+        );
+        assert_eq!(
+            cron("0 0 30 2 *").next_after(datetime!(2026-03-01 00:00 UTC), &TimeZone::utc()),
+            None
+        );
+    }
+
+    #[test]
+    fn cron_follows_the_clocks_across_daylight_saving_time() {
+        let la = TimeZone::named("America/Los_Angeles").unwrap();
+        let daily = cron("0 3 * * *");
+
+        // 3am PST, then 3am PDT only 23 hours later.
+        assert_eq!(
+            daily.next_after(datetime!(2026-03-07 10:00 UTC), &la),
+            Some(datetime!(2026-03-07 11:00 UTC))
+        );
+        assert_eq!(
+            daily.next_after(datetime!(2026-03-07 11:00 UTC), &la),
+            Some(datetime!(2026-03-08 10:00 UTC))
+        );
+        assert_eq!(
+            daily.next_after(datetime!(2026-10-31 10:00 UTC), &la),
+            Some(datetime!(2026-11-01 11:00 UTC))
+        );
+    }
+
+    #[test]
+    fn skipped_minutes_fire_at_the_jump_and_repeated_ones_once() {
+        let la = TimeZone::named("America/Los_Angeles").unwrap();
+        let skipped = cron("30 2 * * *");
+        let repeated = cron("30 1 * * *");
+
+        // 2:30am does not exist on March 8th; the clocks jump from 2am PST
+        // to 3am PDT at 10:00 UTC.
+        assert_eq!(
+            skipped.next_after(datetime!(2026-03-08 08:00 UTC), &la),
+            Some(datetime!(2026-03-08 10:00 UTC))
+        );
+        assert_eq!(
+            skipped.next_after(datetime!(2026-03-08 10:00 UTC), &la),
+            Some(datetime!(2026-03-09 09:30 UTC))
+        );
+        // 1:30am happens twice on November 1st, first in PDT.
+        assert_eq!(
+            repeated.next_after(datetime!(2026-11-01 07:00 UTC), &la),
+            Some(datetime!(2026-11-01 08:30 UTC))
+        );
+        assert_eq!(
+            repeated.next_after(datetime!(2026-11-01 08:30 UTC), &la),
+            Some(datetime!(2026-11-02 09:30 UTC))
+        );
+        assert_eq!(
+            repeated.next_after(datetime!(2026-11-01 09:10 UTC), &la),
+            Some(datetime!(2026-11-02 09:30 UTC))
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use secrecy::SecretString;
+use std::{
+    borrow::Cow,
+    fmt, io,
+    sync::{Arc, RwLock},
+};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Shorter values are not scrubbed from output, since they would match
+/// ordinary words.
+const MIN_REDACTED_LEN: usize = 6;
+
+const REDACTED: &str = "[redacted]";
+
+/// Every secret resolved so far, scrubbed from output by [`redact`].
+static KNOWN: RwLock<Vec<Arc<str>>> = RwLock::new(Vec::new());
+
+/// A credential. Its value is only reachable through [`Secret::expose`],
+/// never through `Debug` or `Display`, and is scrubbed from errors and log
+/// lines once resolved as a key (see [`Secret::scrub`]). Settings that are
+/// not credentials stay plain strings (see [`crate::key::Key::value`]).
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Secret(Arc<str>);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Self(value.into())
+    }
+
+    /// Scrubs the secret from all output by [`redact`] from now on.
+    pub fn scrub(&self) {
+        if self.0.len() >= MIN_REDACTED_LEN {
+            let mut known = KNOWN.write().unwrap_or_else(|e| e.into_inner());
+            if !known.contains(&self.0) {
+                known.push(Arc::clone(&self.0));
+            }
+        }
+    }
+
+    /// The secret itself. Clients take a [`SecretString`] instead, which they
+    /// only expose to build the request; this is for the few that take a
+    /// plain `String`. Never format it.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<Secret> for SecretString {
+    fn from(secret: Secret) -> Self {
+        SecretString::from(&*secret.0)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({REDACTED})")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// `text` with every resolved secret replaced by `[redacted]`.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let known = KNOWN.read().unwrap_or_else(|e| e.into_inner());
+    let mut text = Cow::Borrowed(text);
+    for secret in known.iter() {
+        if text.contains(&**secret) {
+            text = Cow::Owned(text.replace(&**secret, REDACTED));
+        }
+    }
+    text
+}
+
+/// Wraps a log writer so that every log line is passed through [`redact`].
+pub struct Redacting<M>(pub M);
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for Redacting<M> {
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter(self.0.make_writer())
+    }
+}
+
+pub struct RedactingWriter<W>(W);
+
+impl<W: io::Write> io::Write for RedactingWriter<W> {
+    // The log formatter writes each event in one call, so secrets are never
+    // split across writes.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        match redact(&text) {
+            Cow::Borrowed(_) => self.0.write(buf),
+            Cow::Owned(redacted) => {
+                self.0.write_all(redacted.as_bytes())?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn secrets_never_format() {
+        let secret = Secret::new("ghp_formatting".to_string());
+        assert_eq!(
+            format!("{secret:?} {secret}"),
+            "Secret([redacted]) [redacted]"
+        );
+        assert_eq!(secret.expose(), "ghp_formatting");
+    }
+
+    #[test]
+    fn scrubbed_secrets_are_redacted_from_text() {
+        Secret::new("hunter2-scrubbed".to_string()).scrub();
+        Secret::new("short".to_string()).scrub();
+        Secret::new("never-scrubbed".to_string());
+
+        assert_eq!(
+            redact("401 for token hunter2-scrubbed (short), never-scrubbed"),
+            "401 for token [redacted] (short), never-scrubbed"
+        );
+        assert!(matches!(redact("nothing to hide"), Cow::Borrowed(_)));
+
+        let mut log = RedactingWriter(Vec::new());
+        log.write_all(b"Authorization: hunter2-scrubbed\n").unwrap();
+        assert_eq!(log.0, b"Authorization: [redacted]\n");
+    }
+}
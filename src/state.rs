@@ -0,0 +1,397 @@
+use crate::report::{ModuleReport, Status, Tally};
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS goals (
+    module TEXT NOT NULL,
+    goal TEXT NOT NULL,
+    cursor TEXT,
+    rebuilt_at TEXT NOT NULL,
+    PRIMARY KEY (module, goal)
+);
+CREATE TABLE IF NOT EXISTS items (
+    module TEXT NOT NULL,
+    goal TEXT NOT NULL,
+    item TEXT NOT NULL,
+    datapoint_id TEXT,
+    synced_at TEXT NOT NULL,
+    PRIMARY KEY (module, goal, item)
+);
+CREATE TABLE IF NOT EXISTS runs (
+    module TEXT NOT NULL PRIMARY KEY,
+    finished_at INTEGER NOT NULL,
+    succeeded INTEGER NOT NULL,
+    succeeded_at INTEGER,
+    duration_ms INTEGER NOT NULL,
+    created INTEGER NOT NULL,
+    updated INTEGER NOT NULL,
+    deleted INTEGER NOT NULL,
+    skipped INTEGER NOT NULL,
+    seen INTEGER NOT NULL,
+    errors INTEGER NOT NULL
+);
+";
+
+/// Local record of what each module has synced, so modules can deduplicate
+/// without re-deriving it from Beeminder on every run.
+///
+/// State is kept per module and goal: the source items already synced (with
+/// the datapoint each became) and an optional cursor, such as the time of the
+/// newest synced item. A goal without state is rebuilt from Beeminder by its
+/// module on the next run, so deleting the database is always safe.
+pub struct StateStore {
+    db: Mutex<Connection>,
+}
+
+impl StateStore {
+    /// `state.sqlite3` in the XDG state directory.
+    pub fn default_path() -> Result<PathBuf> {
+        Ok(crate::paths::state_dir()?.join("state.sqlite3"))
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let open = || -> Result<Self> {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            Self::with_connection(Connection::open(path)?)
+        };
+        open().with_context(|| format!("opening state database {}", path.display()))
+    }
+
+    /// A store that lives only as long as the process; used by tests.
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Self::with_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn with_connection(db: Connection) -> Result<Self> {
+        // Concurrent beesync processes (say, a daemon and a manual run) wait
+        // for each other instead of failing with "database is locked".
+        db.busy_timeout(std::time::Duration::from_secs(10))?;
+        db.execute_batch(SCHEMA)?;
+        Ok(Self { db: Mutex::new(db) })
+    }
+
+    fn db(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.db.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A module instance's most recent run, kept for the metrics file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ModuleRun {
+    /// The module instance's [`crate::module::Module::label`].
+    pub module: String,
+    /// Unix time the last run finished.
+    pub finished_at: i64,
+    /// Unix time of the last successful run, if any.
+    pub succeeded_at: Option<i64>,
+    pub succeeded: bool,
+    pub duration_ms: u64,
+    /// Changes and source items of the last run.
+    pub tally: Tally,
+    /// Failed runs, ever.
+    pub errors: u64,
+}
+
+impl StateStore {
+    /// Records how the run of module instance `label` that finished at
+    /// `finished_at` (Unix time) went.
+    pub fn record_run(&self, label: &str, report: &ModuleReport, finished_at: i64) -> Result<()> {
+        let succeeded = report.status == Status::Ok;
+        let tally = report.tally;
+        self.db().execute(
+            "INSERT INTO runs (module, finished_at, succeeded, succeeded_at, duration_ms,
+                               created, updated, deleted, skipped, seen, errors)
+             VALUES (?1, ?2, ?3, CASE WHEN ?3 THEN ?2 END, ?4, ?5, ?6, ?7, ?8, ?9, NOT ?3)
+             ON CONFLICT (module) DO UPDATE SET
+                 finished_at = excluded.finished_at,
+                 succeeded = excluded.succeeded,
+                 succeeded_at = coalesce(excluded.succeeded_at, succeeded_at),
+                 duration_ms = excluded.duration_ms,
+                 created = excluded.created,
+                 updated = excluded.updated,
+                 deleted = excluded.deleted,
+                 skipped = excluded.skipped,
+                 seen = excluded.seen,
+                 errors = errors + excluded.errors",
+            params![
+                label,
+                finished_at,
+                succeeded,
+                i64::try_from(report.duration_ms).unwrap_or(i64::MAX),
+                tally.created,
+                tally.updated,
+                tally.deleted,
+                tally.skipped,
+                tally.seen,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The last run of every module instance that ever ran, by label.
+    pub fn runs(&self) -> Result<Vec<ModuleRun>> {
+        let db = self.db();
+        let mut query = db.prepare(
+            "SELECT module, finished_at, succeeded, succeeded_at, duration_ms,
+                    created, updated, deleted, skipped, seen, errors
+             FROM runs ORDER BY module",
+        )?;
+        let runs = query
+            .query_map([], |row| {
+                Ok(ModuleRun {
+                    module: row.get(0)?,
+                    finished_at: row.get(1)?,
+                    succeeded: row.get(2)?,
+                    succeeded_at: row.get(3)?,
+                    duration_ms: row.get(4)?,
+                    tally: Tally {
+                        created: row.get(5)?,
+                        updated: row.get(6)?,
+                        deleted: row.get(7)?,
+                        skipped: row.get(8)?,
+                        seen: row.get(9)?,
+                    },
+                    errors: row.get(10)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(runs)
+    }
+}
+
+/// One module's state for one goal; see [`crate::module::SyncContext::state`].
+pub struct GoalState<'a> {
+    store: &'a StateStore,
+    /// The module instance's [`crate::module::Module::label`].
+    module: &'a str,
+    goal: String,
+    /// Dry runs read state but never record items or move the cursor.
+    dry_run: bool,
+}
+
+impl<'a> GoalState<'a> {
+    pub(crate) fn new(store: &'a StateStore, module: &'a str, goal: &str, dry_run: bool) -> Self {
+        Self {
+            store,
+            module,
+            goal: goal.to_string(),
+            dry_run,
+        }
+    }
+
+    /// Source items recorded as synced, or `None` if this goal's state has
+    /// never been built and must be rebuilt from Beeminder.
+    pub fn items(&self) -> Result<Option<HashSet<String>>> {
+        let db = self.store.db();
+        let built: Option<i64> = db
+            .query_row(
+                "SELECT 1 FROM goals WHERE module = ?1 AND goal = ?2",
+                params![self.module, self.goal],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if built.is_none() {
+            return Ok(None);
+        }
+        let mut query = db.prepare("SELECT item FROM items WHERE module = ?1 AND goal = ?2")?;
+        let items = query
+            .query_map(params![self.module, self.goal], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Some(items))
+    }
+
+    /// Replaces this goal's state with `items` (source item, datapoint id)
+    /// found on Beeminder, and `cursor`.
+    ///
+    /// This runs in dry runs too: it only mirrors what Beeminder already has.
+    pub fn rebuild(
+        &self,
+        items: impl IntoIterator<Item = (String, Option<String>)>,
+        cursor: Option<&str>,
+    ) -> Result<()> {
+        let mut db = self.store.db();
+        let tx = db.transaction()?;
+        tx.execute(
+            "DELETE FROM items WHERE module = ?1 AND goal = ?2",
+            params![self.module, self.goal],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO goals (module, goal, cursor, rebuilt_at)
+             VALUES (?1, ?2, ?3, datetime('now'))",
+            params![self.module, self.goal, cursor],
+        )?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT OR REPLACE INTO items (module, goal, item, datapoint_id, synced_at)
+                 VALUES (?1, ?2, ?3, ?4, datetime('now'))",
+            )?;
+            for (item, datapoint_id) in items {
+                insert.execute(params![self.module, self.goal, item, datapoint_id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Records that `item` was synced as datapoint `datapoint_id`.
+    pub fn record(&self, item: &str, datapoint_id: Option<&str>) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        self.store.db().execute(
+            "INSERT OR REPLACE INTO items (module, goal, item, datapoint_id, synced_at)
+             VALUES (?1, ?2, ?3, ?4, datetime('now'))",
+            params![self.module, self.goal, item, datapoint_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn cursor(&self) -> Result<Option<String>> {
+        Ok(self
+            .store
+            .db()
+            .query_row(
+                "SELECT cursor FROM goals WHERE module = ?1 AND goal = ?2",
+                params![self.module, self.goal],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    /// Moves the cursor. Only call this after [`GoalState::items`] returned
+    /// `Some` or after [`GoalState::rebuild`].
+    pub fn set_cursor(&self, cursor: &str) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        self.store.db().execute(
+            "UPDATE goals SET cursor = ?3 WHERE module = ?1 AND goal = ?2",
+            params![self.module, self.goal, cursor],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn unbuilt_goal_has_no_items_until_rebuilt() {
+        let store = StateStore::in_memory();
+        let state = GoalState::new(&store, "github", "commits", false);
+
+        assert_eq!(state.items().unwrap(), None);
+
+        state
+            .rebuild([("abc".to_string(), Some("dp1".to_string()))], Some("t1"))
+            .unwrap();
+        state.record("def", Some("dp2")).unwrap();
+        state.set_cursor("t2").unwrap();
+
+        assert_eq!(
+            state.items().unwrap(),
+            Some(HashSet::from(["abc".to_string(), "def".to_string()]))
+        );
+        assert_eq!(state.cursor().unwrap().as_deref(), Some("t2"));
+    }
+
+    #[test]
+    fn state_is_scoped_per_module_and_goal() {
+        let store = StateStore::in_memory();
+        GoalState::new(&store, "github", "commits", false)
+            .rebuild([("abc".to_string(), None)], None)
+            .unwrap();
+
+        assert_eq!(
+            GoalState::new(&store, "github", "other", false)
+                .items()
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            GoalState::new(&store, "fatebook", "commits", false)
+                .items()
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn dry_runs_do_not_record() {
+        let store = StateStore::in_memory();
+        let state = GoalState::new(&store, "github", "commits", true);
+        state.rebuild([], Some("t1")).unwrap();
+
+        state.record("abc", None).unwrap();
+        state.set_cursor("t2").unwrap();
+
+        assert_eq!(state.items().unwrap(), Some(HashSet::new()));
+        assert_eq!(state.cursor().unwrap().as_deref(), Some("t1"));
+    }
+
+    #[test]
+    fn runs_keep_the_last_success_and_count_errors() {
+        let store = StateStore::in_memory();
+        let tally = Tally {
+            seen: 12,
+            ..Tally::default()
+        };
+        let ok = ModuleReport::new("github", None, tally, Duration::from_millis(1500), &Ok(()));
+        let failed = ModuleReport::new(
+            "github",
+            None,
+            Tally::default(),
+            Duration::ZERO,
+            &Err(anyhow::anyhow!("GitHub is down")),
+        );
+
+        store.record_run("github", &ok, 100).unwrap();
+        store.record_run("github", &failed, 200).unwrap();
+        store.record_run("github", &failed, 300).unwrap();
+
+        assert_eq!(
+            store.runs().unwrap(),
+            vec![ModuleRun {
+                module: "github".to_string(),
+                finished_at: 300,
+                succeeded_at: Some(100),
+                succeeded: false,
+                duration_ms: 0,
+                tally: Tally::default(),
+                errors: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn state_survives_reopening() {
+        let dir = std::env::temp_dir().join(format!("beesync-state-{}", std::process::id()));
+        let path = dir.join("state.sqlite3");
+        GoalState::new(
+            &StateStore::open(&path).unwrap(),
+            "fatebook",
+            "fatebook",
+            false,
+        )
+        .rebuild([("q1".to_string(), None)], None)
+        .unwrap();
+
+        let reopened = StateStore::open(&path).unwrap();
+        let items = GoalState::new(&reopened, "fatebook", "fatebook", false).items();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(items.unwrap(), Some(HashSet::from(["q1".to_string()])));
+    }
+}
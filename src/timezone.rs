@@ -0,0 +1,296 @@
+use anyhow::{Context, Result};
+use jiff::tz::AmbiguousOffset;
+use serde::Deserialize;
+use std::fmt;
+use time::{Date, Duration, OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+/// `YYYYMMDD`, the day a datapoint counts for on Beeminder.
+pub fn daystamp(date: Date) -> String {
+    format!(
+        "{:04}{:02}{:02}",
+        date.year(),
+        date.month() as u8,
+        date.day()
+    )
+}
+
+/// A time zone from the IANA database, which decides the day every
+/// datapoint is for. Unlike a fixed offset it follows daylight saving time,
+/// also in a daemon that runs across a change.
+///
+/// Zones come from the system's database (`/usr/share/zoneinfo`, or
+/// `$TZDIR`), else from the copy built into beesync.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeZone {
+    name: String,
+    zone: jiff::tz::TimeZone,
+}
+
+impl TryFrom<String> for TimeZone {
+    type Error = anyhow::Error;
+
+    fn try_from(name: String) -> Result<Self> {
+        Self::named(&name)
+    }
+}
+
+impl fmt::Display for TimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl TimeZone {
+    pub fn utc() -> Self {
+        Self {
+            name: "UTC".to_string(),
+            zone: jiff::tz::TimeZone::UTC,
+        }
+    }
+
+    /// The zone called `name` in the IANA database, e.g.
+    /// `America/Los_Angeles`.
+    pub fn named(name: &str) -> Result<Self> {
+        let zone = jiff::tz::TimeZone::get(name).with_context(|| {
+            format!("unknown time zone '{name}'; use an IANA name such as America/Los_Angeles")
+        })?;
+        Ok(Self {
+            name: name.to_string(),
+            zone,
+        })
+    }
+
+    /// The machine's time zone: `$TZ`, else `/etc/localtime`, else UTC.
+    pub fn system() -> Self {
+        match jiff::tz::TimeZone::try_system() {
+            Ok(zone) => Self {
+                name: zone.iana_name().unwrap_or("localtime").to_string(),
+                zone,
+            },
+            Err(e) => {
+                tracing::warn!("cannot determine the local time zone, using UTC: {e}");
+                Self::utc()
+            }
+        }
+    }
+
+    pub fn offset_at(&self, at: OffsetDateTime) -> UtcOffset {
+        utc_offset(self.zone.to_offset(timestamp(at)))
+    }
+
+    /// `at` in this zone.
+    pub fn local(&self, at: OffsetDateTime) -> OffsetDateTime {
+        at.to_offset(self.offset_at(at))
+    }
+
+    pub fn now(&self) -> OffsetDateTime {
+        self.local(OffsetDateTime::now_utc())
+    }
+
+    pub fn today(&self) -> Date {
+        self.now().date()
+    }
+
+    /// The moment the clocks in this zone show `local`. A time skipped when
+    /// the clocks go forward resolves to the moment they jump, and a time
+    /// repeated when they go back to its first occurrence.
+    pub fn resolve(&self, local: PrimitiveDateTime) -> OffsetDateTime {
+        let offset = match self.zone.to_ambiguous_timestamp(civil(local)).offset() {
+            AmbiguousOffset::Unambiguous { offset }
+            | AmbiguousOffset::Fold { before: offset, .. } => offset,
+            AmbiguousOffset::Gap { after, .. } => {
+                // Read with the offset after the jump, `local` lies before
+                // it, so the next transition is the jump itself.
+                let before_jump = local.assume_offset(utc_offset(after));
+                let jump = self
+                    .zone
+                    .following(timestamp(before_jump))
+                    .next()
+                    .map_or(before_jump, |transition| {
+                        from_timestamp(transition.timestamp())
+                    });
+                return self.local(jump);
+            }
+        };
+        local.assume_offset(utc_offset(offset))
+    }
+
+    /// Days in this zone that end at `deadline` after midnight (before it,
+    /// if negative), like a Beeminder goal's. A zero deadline gives calendar
+    /// days, which are not always 24 hours long.
+    pub const fn goal_days(&self, deadline: Duration) -> GoalDays<'_> {
+        GoalDays {
+            timezone: self,
+            deadline,
+        }
+    }
+}
+
+/// How a Beeminder goal divides time into days: in the configured time zone,
+/// each ending at the goal's deadline. With a 3am deadline a session at 1am
+/// still counts for the day before; with an 11pm one, a commit at 11:30pm
+/// counts for the next day.
+#[derive(Clone, Copy, Debug)]
+pub struct GoalDays<'a> {
+    timezone: &'a TimeZone,
+    deadline: Duration,
+}
+
+impl GoalDays<'_> {
+    /// The goal's day `at` counts for.
+    pub fn date(&self, at: OffsetDateTime) -> Date {
+        (self.timezone.local(at) - self.deadline).date()
+    }
+
+    pub fn daystamp(&self, at: OffsetDateTime) -> String {
+        daystamp(self.date(at))
+    }
+
+    /// When the goal's `date` starts, at the previous day's deadline.
+    pub fn start(&self, date: Date) -> OffsetDateTime {
+        self.timezone.resolve(date.midnight() + self.deadline)
+    }
+}
+
+fn timestamp(at: OffsetDateTime) -> jiff::Timestamp {
+    let nanos = i32::try_from(at.nanosecond()).unwrap_or(0);
+    jiff::Timestamp::new(at.unix_timestamp(), nanos).unwrap_or(jiff::Timestamp::MAX)
+}
+
+fn from_timestamp(at: jiff::Timestamp) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp_nanos(at.as_nanosecond())
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH)
+}
+
+fn utc_offset(offset: jiff::tz::Offset) -> UtcOffset {
+    UtcOffset::from_whole_seconds(offset.seconds()).unwrap_or(UtcOffset::UTC)
+}
+
+fn civil(local: PrimitiveDateTime) -> jiff::civil::DateTime {
+    // Both crates cover the years -9999 to 9999, so every part fits.
+    jiff::civil::DateTime::new(
+        local.year() as i16,
+        u8::from(local.month()) as i8,
+        local.day() as i8,
+        local.hour() as i8,
+        local.minute() as i8,
+        local.second() as i8,
+        local.nanosecond() as i32,
+    )
+    .expect("a valid date and time")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::{date, datetime};
+
+    fn los_angeles() -> TimeZone {
+        TimeZone::named("America/Los_Angeles").unwrap()
+    }
+
+    fn hours(zone: &TimeZone, at: OffsetDateTime) -> f64 {
+        f64::from(zone.offset_at(at).whole_seconds()) / 3600.0
+    }
+
+    #[test]
+    fn daylight_saving_time_follows_the_zone() {
+        let la = los_angeles();
+
+        assert_eq!(la.to_string(), "America/Los_Angeles");
+        assert_eq!(hours(&la, datetime!(2026-01-15 12:00 UTC)), -8.0);
+        assert_eq!(hours(&la, datetime!(2026-07-01 12:00 UTC)), -7.0);
+        // 2am PST on the second Sunday of March, and 2am PDT on the first
+        // Sunday of November.
+        assert_eq!(hours(&la, datetime!(2026-03-08 09:59 UTC)), -8.0);
+        assert_eq!(hours(&la, datetime!(2026-03-08 10:00 UTC)), -7.0);
+        assert_eq!(hours(&la, datetime!(2026-11-01 08:59 UTC)), -7.0);
+        assert_eq!(hours(&la, datetime!(2026-11-01 09:00 UTC)), -8.0);
+        // Before 2007 the US switched in April.
+        assert_eq!(hours(&la, datetime!(2000-03-20 12:00 UTC)), -8.0);
+        assert_eq!(hours(&la, datetime!(2000-04-03 12:00 UTC)), -7.0);
+
+        let sydney = TimeZone::named("Australia/Sydney").unwrap();
+        assert_eq!(hours(&sydney, datetime!(2026-01-15 12:00 UTC)), 11.0);
+        assert_eq!(hours(&sydney, datetime!(2026-07-01 12:00 UTC)), 10.0);
+
+        let kolkata = TimeZone::named("Asia/Kolkata").unwrap();
+        assert_eq!(hours(&kolkata, datetime!(2026-07-01 12:00 UTC)), 5.5);
+    }
+
+    #[test]
+    fn skipped_and_repeated_times_resolve_to_one_moment() {
+        let la = los_angeles();
+
+        assert_eq!(
+            la.resolve(datetime!(2026-03-08 02:30)),
+            datetime!(2026-03-08 10:00 UTC)
+        );
+        assert_eq!(
+            la.resolve(datetime!(2026-11-01 01:30)),
+            datetime!(2026-11-01 08:30 UTC)
+        );
+        assert_eq!(
+            la.resolve(datetime!(2026-07-01 12:00)),
+            datetime!(2026-07-01 12:00 -7)
+        );
+    }
+
+    #[test]
+    fn evening_commits_count_for_the_local_day() {
+        let zone = los_angeles();
+        let la = zone.goal_days(time::Duration::ZERO);
+        let utc = TimeZone::utc();
+
+        assert_eq!(la.daystamp(datetime!(2026-06-01 03:00 UTC)), "20260531");
+        assert_eq!(la.daystamp(datetime!(2026-06-01 07:00 UTC)), "20260601");
+        assert_eq!(
+            utc.goal_days(time::Duration::ZERO)
+                .daystamp(datetime!(2026-06-01 03:00 UTC)),
+            "20260601"
+        );
+    }
+
+    #[test]
+    fn days_start_at_local_midnight_across_changes() {
+        let zone = los_angeles();
+        let la = zone.goal_days(time::Duration::ZERO);
+
+        let start = la.start(date!(2026 - 03 - 08));
+        let next = la.start(date!(2026 - 03 - 09));
+
+        assert_eq!(start, datetime!(2026-03-08 08:00 UTC));
+        assert_eq!(next - start, time::Duration::hours(23));
+    }
+
+    #[test]
+    fn goal_days_end_at_the_deadline() {
+        let la = los_angeles();
+        let night_owl = la.goal_days(time::Duration::hours(3));
+        let early_bird = la.goal_days(time::Duration::hours(-1));
+
+        // 1am and 11:30pm on June 1st in Los Angeles.
+        let after_midnight = datetime!(2026-06-01 08:00 UTC);
+        let before_midnight = datetime!(2026-06-02 06:30 UTC);
+        assert_eq!(night_owl.daystamp(after_midnight), "20260531");
+        assert_eq!(night_owl.daystamp(before_midnight), "20260601");
+        assert_eq!(early_bird.daystamp(after_midnight), "20260601");
+        assert_eq!(early_bird.daystamp(before_midnight), "20260602");
+
+        assert_eq!(
+            night_owl.start(date!(2026 - 06 - 01)),
+            datetime!(2026-06-01 10:00 UTC)
+        );
+        assert_eq!(
+            early_bird.start(date!(2026 - 06 - 01)),
+            datetime!(2026-06-01 06:00 UTC)
+        );
+    }
+
+    #[test]
+    fn unknown_zones_are_rejected() {
+        assert!(TimeZone::named("Mars/Olympus_Mons").is_err());
+        assert!(TimeZone::named("../../etc/passwd").is_err());
+    }
+}
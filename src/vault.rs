@@ -0,0 +1,372 @@
+use crate::key::{self, Key};
+use crate::secret::Secret;
+use age::{
+    armor::{ArmoredReader, ArmoredWriter, Format},
+    DecryptError, Decryptor, Encryptor, IdentityFile, NoCallbacks,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// How vaults are unlocked, from the `[vault]` table. Set exactly one.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VaultConfig {
+    /// An age identity file, which only its owner may read; `~` is
+    /// expanded. The first `beesync secrets set` creates it.
+    pub identity: Option<String>,
+    /// The vault's passphrase, from any key source but a vault.
+    pub passphrase: Option<Key>,
+}
+
+/// The `[vault]` settings of the loaded config; see [`configure`].
+static CONFIG: Mutex<Option<VaultConfig>> = Mutex::new(None);
+
+/// Vaults decrypted by this process, with when each was last modified, so
+/// each is decrypted once and again only after it changes.
+type Opened = BTreeMap<PathBuf, (Option<SystemTime>, Arc<Vault>)>;
+static OPENED: Mutex<Opened> = Mutex::new(BTreeMap::new());
+
+/// Uses `config` to unlock the vaults that keys refer to.
+pub fn configure(config: &VaultConfig) {
+    *CONFIG.lock().unwrap_or_else(|e| e.into_inner()) = Some(config.clone());
+}
+
+/// Resolves a `{ vault = "...", entry = "..." }` key.
+pub async fn entry(vault: &str, entry: &str) -> Result<Secret> {
+    let path = crate::paths::expand_tilde(vault)?;
+    let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+    let opened = OPENED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&path)
+        .filter(|(opened_at, _)| *opened_at == modified)
+        .map(|(_, vault)| Arc::clone(vault));
+    let vault = match opened {
+        Some(vault) => vault,
+        None => {
+            let config = CONFIG
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone()
+                .unwrap_or_default();
+            let vault = Arc::new(Vault::open(&path, &config).await?);
+            OPENED
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(path, (modified, Arc::clone(&vault)));
+            vault
+        }
+    };
+    vault.get(entry).map(|value| Secret::new(value.to_string()))
+}
+
+/// How a vault is unlocked.
+enum Unlock {
+    Identity(PathBuf),
+    Passphrase(SecretString),
+}
+
+impl Unlock {
+    async fn new(config: &VaultConfig) -> Result<Self> {
+        match (&config.identity, &config.passphrase) {
+            (Some(identity), None) => Ok(Self::Identity(crate::paths::expand_tilde(identity)?)),
+            (None, Some(Key::Vault { .. })) => {
+                bail!("the vault passphrase cannot itself come from a vault")
+            }
+            (None, Some(passphrase)) => Ok(Self::Passphrase(
+                passphrase
+                    .resolve()
+                    .await
+                    .context("resolving the vault passphrase")?
+                    .into(),
+            )),
+            (Some(_), Some(_)) => bail!("[vault] sets both identity and passphrase; choose one"),
+            (None, None) => {
+                bail!("no way to unlock vaults: set identity or passphrase in a [vault] section")
+            }
+        }
+    }
+
+    fn recipients(&self) -> Result<Vec<Box<dyn age::Recipient + Send>>> {
+        match self {
+            Self::Identity(path) => Ok(identity_file(path)?.to_recipients()?),
+            Self::Passphrase(passphrase) => Ok(vec![Box::new(age::scrypt::Recipient::new(
+                passphrase.clone(),
+            ))]),
+        }
+    }
+
+    fn identities(&self) -> Result<Vec<Box<dyn age::Identity>>> {
+        match self {
+            Self::Identity(path) => Ok(identity_file(path)?.into_identities()?),
+            Self::Passphrase(passphrase) => Ok(vec![Box::new(age::scrypt::Identity::new(
+                passphrase.clone(),
+            ))]),
+        }
+    }
+}
+
+fn identity_file(path: &Path) -> Result<IdentityFile<NoCallbacks>> {
+    IdentityFile::from_buffer(key::read_file(path)?.as_bytes())
+        .map_err(|_| anyhow!("'{}' is not an age identity file", path.display()))
+}
+
+/// A file of named secrets, encrypted with [age](https://age-encryption.org)
+/// to an identity file or a passphrase.
+///
+/// The file is ASCII-armored, so it can live in a dotfiles repository, and
+/// the `age` tool decrypts it too (`age -d -i vault.key secrets.vault`).
+pub struct Vault {
+    path: PathBuf,
+    unlock: Unlock,
+    entries: BTreeMap<String, String>,
+}
+
+impl Vault {
+    /// Decrypts the vault at `path`, off the async runtime, as a passphrase
+    /// takes a while to check.
+    pub async fn open(path: &Path, config: &VaultConfig) -> Result<Self> {
+        let unlock = Unlock::new(config).await?;
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let text =
+                fs::read(&path).with_context(|| format!("reading vault {}", path.display()))?;
+            let entries = decrypt(&text, &unlock)
+                .with_context(|| format!("opening vault {}", path.display()))?;
+            Ok(Self {
+                path,
+                unlock,
+                entries,
+            })
+        })
+        .await?
+    }
+
+    /// Decrypts the vault at `path`, or starts an empty one if there is no
+    /// such file. A missing identity file is created for a new vault.
+    pub async fn open_or_create(path: &Path, config: &VaultConfig) -> Result<Self> {
+        if path.exists() {
+            return Self::open(path, config).await;
+        }
+        let unlock = Unlock::new(config).await?;
+        if let Unlock::Identity(identity) = &unlock {
+            if !identity.exists() {
+                create_identity(identity)?;
+                eprintln!("🔑 created vault identity {}", identity.display());
+            }
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            unlock,
+            entries: BTreeMap::new(),
+        })
+    }
+
+    pub fn get(&self, entry: &str) -> Result<&str> {
+        self.entries.get(entry).map(String::as_str).ok_or_else(|| {
+            let names: Vec<_> = self.names().collect();
+            anyhow!(
+                "vault {} has no entry '{entry}'; it has: {}",
+                self.path.display(),
+                if names.is_empty() {
+                    "nothing".to_string()
+                } else {
+                    names.join(", ")
+                }
+            )
+        })
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    pub fn set(&mut self, entry: &str, value: &str) {
+        self.entries.insert(entry.to_string(), value.to_string());
+    }
+
+    /// Encrypts the vault to its file, replacing it atomically.
+    pub fn save(&self) -> Result<()> {
+        let text = encrypt(&self.entries, &self.unlock)?;
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let write = || -> std::io::Result<()> {
+            // A temporary file left behind by an earlier run may be readable
+            // by others, so it is replaced rather than reused.
+            match fs::remove_file(&temporary) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&temporary)?;
+            file.write_all(&text)?;
+            file.sync_all()?;
+            fs::rename(&temporary, &self.path)
+        };
+        write().with_context(|| format!("writing vault {}", self.path.display()))
+    }
+}
+
+fn create_identity(path: &Path) -> Result<()> {
+    let identity = age::x25519::Identity::generate();
+    let create = || -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        // The layout of `age-keygen`, so either tool can use the file.
+        writeln!(file, "# public key: {}", identity.to_public())?;
+        writeln!(file, "{}", identity.to_string().expose_secret())
+    };
+    create().with_context(|| format!("creating vault identity {}", path.display()))
+}
+
+fn encrypt(entries: &BTreeMap<String, String>, unlock: &Unlock) -> Result<Vec<u8>> {
+    let recipients = unlock.recipients()?;
+    let encryptor = Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref() as _))?;
+    let mut text = Vec::new();
+    let mut writer =
+        encryptor.wrap_output(ArmoredWriter::wrap_output(&mut text, Format::AsciiArmor)?)?;
+    writer.write_all(toml::to_string(entries)?.as_bytes())?;
+    writer.finish()?.finish()?;
+    Ok(text)
+}
+
+fn decrypt(text: &[u8], unlock: &Unlock) -> Result<BTreeMap<String, String>> {
+    let decryptor =
+        Decryptor::new_buffered(ArmoredReader::new(text)).context("not an age-encrypted vault")?;
+    let identities = unlock.identities()?;
+    let mut plaintext = String::new();
+    decryptor
+        .decrypt(identities.iter().map(|i| i.as_ref()))
+        .map_err(|e| match e {
+            DecryptError::NoMatchingKeys
+            | DecryptError::DecryptionFailed
+            | DecryptError::KeyDecryptionFailed => {
+                anyhow!("wrong identity or passphrase, or the vault was modified")
+            }
+            e => e.into(),
+        })?
+        .read_to_string(&mut plaintext)
+        .map_err(|_| anyhow!("the vault was modified"))?;
+    Ok(toml::from_str(&plaintext)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("beesync-vault-{}-{test}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn passphrase(passphrase: &str) -> VaultConfig {
+        VaultConfig {
+            identity: None,
+            passphrase: Some(Key::Cmd {
+                cmd: format!("echo {passphrase}"),
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn identity_vault_round_trips() {
+        let dir = temp_dir("identity");
+        let config = VaultConfig {
+            identity: Some(dir.join("identity").display().to_string()),
+            passphrase: None,
+        };
+        let path = dir.join("secrets.vault");
+
+        let mut vault = Vault::open_or_create(&path, &config).await.unwrap();
+        vault.set("github", "ghp_round_trip");
+        vault.save().unwrap();
+        let reopened = Vault::open(&path, &config)
+            .await
+            .map(|vault| vault.get("github").map(str::to_string));
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(reopened.unwrap().unwrap(), "ghp_round_trip");
+        assert!(text.starts_with("-----BEGIN AGE ENCRYPTED FILE-----\n"));
+        assert!(!text.contains("ghp_round_trip"));
+    }
+
+    #[tokio::test]
+    async fn passphrase_vault_needs_the_right_passphrase() {
+        let dir = temp_dir("passphrase");
+        let path = dir.join("secrets.vault");
+        let mut vault = Vault::open_or_create(&path, &passphrase("correct-horse"))
+            .await
+            .unwrap();
+        vault.set("fatebook", "fb_token");
+        vault.save().unwrap();
+
+        let right = Vault::open(&path, &passphrase("correct-horse")).await;
+        let wrong = Vault::open(&path, &passphrase("battery-staple")).await;
+        fs::remove_dir_all(&dir).unwrap();
+
+        let right = right.unwrap();
+        assert_eq!(right.names().collect::<Vec<_>>(), ["fatebook"]);
+        assert!(format!("{:#}", wrong.err().unwrap()).contains("wrong identity or passphrase"));
+        assert!(right
+            .get("github")
+            .unwrap_err()
+            .to_string()
+            .ends_with("has no entry 'github'; it has: fatebook"));
+    }
+
+    #[tokio::test]
+    async fn stale_temporary_file_is_not_reused() {
+        let dir = temp_dir("stale");
+        let path = dir.join("secrets.vault");
+        let temporary = dir.join("secrets.vault.tmp");
+        fs::write(&temporary, "left behind").unwrap();
+        fs::set_permissions(&temporary, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let vault = Vault::open_or_create(&path, &passphrase("stale-temporary"))
+            .await
+            .unwrap();
+        vault.save().unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        let leftover = temporary.exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(mode, 0o600);
+        assert!(!leftover);
+    }
+
+    #[test]
+    fn tampered_vault_is_rejected() {
+        let unlock = Unlock::Passphrase(SecretString::from("tamper-proof"));
+        let text = String::from_utf8(encrypt(&BTreeMap::new(), &unlock).unwrap()).unwrap();
+        // Flip one character of the armored payload.
+        let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+        let flipped = if lines[1].starts_with('A') { "B" } else { "A" };
+        lines[1].replace_range(..1, flipped);
+        let tampered = lines.join("\n") + "\n";
+
+        assert!(decrypt(text.as_bytes(), &unlock).is_ok());
+        assert!(decrypt(tampered.as_bytes(), &unlock).is_err());
+    }
+}